@@ -0,0 +1,36 @@
+use anchor_lang::prelude::Result;
+
+use crate::SwapError;
+
+/// Narrows an amount computed in `u128` to a token amount, failing with
+/// `ConversionFailure` when it doesn't fit in a `u64`.
+pub fn to_u64(amount: u128) -> Result<u64> {
+    Ok(try_to_u64(amount)?)
+}
+
+/// `to_u64` for pure math returning a `SwapError`, as in `quote`.
+pub fn try_to_u64(amount: u128) -> std::result::Result<u64, SwapError> {
+    u64::try_from(amount).map_err(|_| SwapError::ConversionFailure)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_u64_at_u64_max() {
+        assert_eq!(to_u64(u128::from(u64::MAX)), Ok(u64::MAX));
+        assert_eq!(to_u64(0), Ok(0));
+    }
+
+    #[test]
+    fn to_u64_above_u64_max() {
+        for amount in [u128::from(u64::MAX) + 1, u128::MAX] {
+            assert_eq!(to_u64(amount), Err(SwapError::ConversionFailure.into()));
+            assert!(matches!(
+                try_to_u64(amount),
+                Err(SwapError::ConversionFailure)
+            ));
+        }
+    }
+}
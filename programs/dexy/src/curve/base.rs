@@ -1,10 +1,13 @@
 use super::{
     calculator::{CurveCalculator, RoundDirection, SwapWithoutFeesResult, TradeDirection},
+    constant_price::ConstantPriceCurve,
     constant_product::ConstantProductCurve,
     fees::CurveFees,
+    offset::OffsetCurve,
 };
+use crate::SwapError;
 use anchor_lang::{
-    prelude::ProgramError,
+    prelude::{borsh, AnchorDeserialize, AnchorSerialize, ProgramError},
     solana_program::program_pack::{Pack, Sealed},
 };
 use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
@@ -66,18 +69,16 @@ use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
 /// - ConstantProductWithOffset: k = (x + a)(y + b) where a,b are offsets
 ///
 #[repr(C)]
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
 pub enum CurveType {
     /// Standard constant product curve (Uniswap V2 style)
     /// Formula: x * y = k
     ConstantProduct,
 
-    // TODO: Implement the following curve types
     /// Constant price curve (stable swap)
     /// Formula: y = mx where m is the price
     ConstantPrice,
 
-    // TODO: Implement the following curve types
     /// Constant product curve with offset for concentrated liquidity
     /// Formula: (x + offset_x)(y + offset_y) = k
     ConstantProductWithOffset,
@@ -360,14 +361,18 @@ impl Pack for SwapCurve {
     fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
         let input = array_ref![src, 0, 33];
         let (curve_type, calculator) = array_refs![input, 1, 32];
-        let curve_type = CurveType::try_from(curve_type[0])?;
-        let calculator = match curve_type {
+        let curve_type = CurveType::try_from(curve_type[0])
+            .map_err(|_| anchor_lang::error::Error::from(SwapError::UnsupportedCurveType))?;
+        let calculator: Box<dyn CurveCalculator> = match curve_type {
             CurveType::ConstantProduct => {
-                let calculator = ConstantProductCurve::unpack_from_slice(calculator)?;
-                Box::new(calculator)
+                Box::new(ConstantProductCurve::unpack_from_slice(calculator)?)
+            }
+            CurveType::ConstantPrice => {
+                Box::new(ConstantPriceCurve::unpack_from_slice(calculator)?)
+            }
+            CurveType::ConstantProductWithOffset => {
+                Box::new(OffsetCurve::unpack_from_slice(calculator)?)
             }
-            CurveType::ConstantPrice => todo!(),
-            CurveType::ConstantProductWithOffset => todo!(),
         };
         Ok(Self {
             curve_type,
@@ -383,3 +388,95 @@ impl Clone for SwapCurve {
         Self::unpack_from_slice(&packed_self).unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::{calculator::DynPack, test_util::Rng};
+
+    fn round_trip<T: Pack + PartialEq + std::fmt::Debug>(curve: &T) {
+        let mut packed = vec![0u8; T::LEN];
+        curve.pack_into_slice(&mut packed);
+        assert_eq!(&T::unpack_from_slice(&packed).unwrap(), curve);
+    }
+
+    /// Packs the calculator alone into the 32 bytes `SwapCurve` keeps for it.
+    fn calculator_round_trip<T: Pack + DynPack + PartialEq + std::fmt::Debug>(calculator: &T) {
+        let mut packed = [0u8; 32];
+        DynPack::pack_into_slice(calculator, &mut packed);
+        assert_eq!(&T::unpack_from_slice(&packed).unwrap(), calculator);
+    }
+
+    #[test]
+    fn pack_constant_product_curve() {
+        calculator_round_trip(&ConstantProductCurve);
+
+        let curve = SwapCurve::default();
+        round_trip(&curve);
+        assert_eq!(curve.clone(), curve);
+    }
+
+    #[test]
+    fn pack_constant_price_curve() {
+        for token_b_price in [1, 2, 1_000_000, u64::MAX] {
+            let calculator = ConstantPriceCurve { token_b_price };
+            calculator_round_trip(&calculator);
+
+            let curve = SwapCurve {
+                curve_type: CurveType::ConstantPrice,
+                calculator: Box::new(calculator),
+            };
+            round_trip(&curve);
+            assert_eq!(curve.clone(), curve);
+        }
+    }
+
+    #[test]
+    fn pack_offset_curve() {
+        for token_b_offset in [1, 2, 1_000_000, u64::MAX] {
+            let calculator = OffsetCurve { token_b_offset };
+            calculator_round_trip(&calculator);
+
+            let curve = SwapCurve {
+                curve_type: CurveType::ConstantProductWithOffset,
+                calculator: Box::new(calculator),
+            };
+            round_trip(&curve);
+            assert_eq!(curve.clone(), curve);
+        }
+    }
+
+    #[test]
+    fn curves_with_different_parameters_differ() {
+        let curve = |token_b_price| SwapCurve {
+            curve_type: CurveType::ConstantPrice,
+            calculator: Box::new(ConstantPriceCurve { token_b_price }),
+        };
+        assert_ne!(curve(1), curve(2));
+        let offset = SwapCurve {
+            curve_type: CurveType::ConstantProductWithOffset,
+            calculator: Box::new(OffsetCurve { token_b_offset: 1 }),
+        };
+        assert_ne!(curve(1), offset);
+    }
+
+    #[test]
+    fn pack_built_curves() {
+        // Reproducible parameters across the whole u64 range
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..256 {
+            let seed = rng.next();
+            let mut curve_params = [0u8; 32];
+            curve_params[..8].copy_from_slice(&seed.max(1).to_le_bytes());
+            for curve_type in 0..3 {
+                let curve = crate::build_curve(&crate::CurveInput {
+                    curve_type,
+                    curve_params,
+                })
+                .unwrap();
+                round_trip(&curve);
+                assert_eq!(curve.clone(), curve);
+            }
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use anchor_lang::prelude::{borsh, AnchorDeserialize, AnchorSerialize};
 use spl_math::precise_number::PreciseNumber;
 use std::fmt::Debug;
 
@@ -21,7 +22,7 @@ pub fn map_zero_to_none(x: u128) -> Option<u128> {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, PartialEq)]
 pub enum TradeDirection {
     AtoB,
     BtoA,
@@ -70,7 +71,9 @@ pub trait CurveCalculator: Debug + DynPack {
         trade_direction: TradeDirection,
     ) -> Option<SwapWithoutFeesResult>;
 
-    fn new_supply_pool(&self) -> u128 {
+    /// Pool tokens minted for the seeded reserves when a pool is created.
+    /// Curves without a natural measure of the reserves mint a fixed amount.
+    fn new_supply_pool(&self, _token_a_amount: u128, _token_b_amount: u128) -> u128 {
         INITIAL_SWAP_POOL_AMOUNT
     }
 
@@ -124,4 +127,98 @@ pub trait CurveCalculator: Debug + DynPack {
         swap_token_a_amount: u128,
         swap_token_b_amount: u128,
     ) -> Option<PreciseNumber>;
+
+    /// Spot price the pool would quote after swapping `source_amount`, in
+    /// destination tokens per source token, ignoring fees.
+    fn marginal_price_after(
+        &self,
+        source_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<PreciseNumber>;
+
+    /// Spot price of the pool before any swap, in destination tokens per
+    /// source token, ignoring fees. Defaults to the constant product price,
+    /// the ratio of the reserves.
+    fn spot_price(
+        &self,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<PreciseNumber> {
+        let (swap_source_amount, swap_destination_amount) = match trade_direction {
+            TradeDirection::AtoB => (swap_token_a_amount, swap_token_b_amount),
+            TradeDirection::BtoA => (swap_token_b_amount, swap_token_a_amount),
+        };
+        PreciseNumber::new(swap_destination_amount)?
+            .checked_div(&PreciseNumber::new(swap_source_amount)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::{
+        constant_price::ConstantPriceCurve, constant_product::ConstantProductCurve,
+        offset::OffsetCurve,
+    };
+
+    #[test]
+    fn marginal_price_after_matches_spot_price_after_swap() {
+        let calculators: [Box<dyn CurveCalculator>; 3] = [
+            Box::new(ConstantProductCurve),
+            Box::new(ConstantPriceCurve { token_b_price: 3 }),
+            Box::new(OffsetCurve {
+                token_b_offset: 500_000,
+            }),
+        ];
+        let (swap_token_a_amount, swap_token_b_amount) = (1_000_000u128, 2_000_000u128);
+        for calculator in &calculators {
+            for trade_direction in [TradeDirection::AtoB, TradeDirection::BtoA] {
+                let (swap_source_amount, swap_destination_amount) = match trade_direction {
+                    TradeDirection::AtoB => (swap_token_a_amount, swap_token_b_amount),
+                    TradeDirection::BtoA => (swap_token_b_amount, swap_token_a_amount),
+                };
+                for source_amount in [3u128, 1_000, 77_777, 200_000] {
+                    let result = calculator
+                        .swap_without_token_fees(
+                            source_amount,
+                            swap_source_amount,
+                            swap_destination_amount,
+                            trade_direction,
+                        )
+                        .unwrap();
+                    let new_swap_source_amount = swap_source_amount + result.source_amount_swapped;
+                    let new_swap_destination_amount =
+                        swap_destination_amount - result.destination_amount_swapped;
+                    let (new_swap_token_a_amount, new_swap_token_b_amount) = match trade_direction {
+                        TradeDirection::AtoB => {
+                            (new_swap_source_amount, new_swap_destination_amount)
+                        }
+                        TradeDirection::BtoA => {
+                            (new_swap_destination_amount, new_swap_source_amount)
+                        }
+                    };
+
+                    let marginal_price = calculator
+                        .marginal_price_after(
+                            source_amount,
+                            swap_source_amount,
+                            swap_destination_amount,
+                            trade_direction,
+                        )
+                        .unwrap();
+                    let spot_price = calculator
+                        .spot_price(
+                            new_swap_token_a_amount,
+                            new_swap_token_b_amount,
+                            trade_direction,
+                        )
+                        .unwrap();
+                    assert_eq!(marginal_price, spot_price);
+                }
+            }
+        }
+    }
 }
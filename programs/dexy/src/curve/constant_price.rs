@@ -0,0 +1,302 @@
+use anchor_lang::solana_program::program_pack::{IsInitialized, Pack, Sealed};
+use arrayref::{array_mut_ref, array_ref};
+use spl_math::{checked_ceil_div::CheckedCeilDiv, precise_number::PreciseNumber, uint::U256};
+
+use super::calculator::{
+    map_zero_to_none, CurveCalculator, DynPack, RoundDirection, SwapWithoutFeesResult,
+    TradeDirection, TradingTokenResult,
+};
+use crate::SwapError;
+
+/// Constant price curve, `y = m * x`, where one token B always trades for
+/// `token_b_price` tokens A.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConstantPriceCurve {
+    /// Amount of token A required to get 1 token B
+    pub token_b_price: u64,
+}
+
+/// Converts a single-sided amount into pool tokens by its share of the total
+/// pool value, measured in token A.
+pub fn trading_tokens_to_pool_tokens(
+    token_b_price: u64,
+    source_amount: u128,
+    swap_token_a_amount: u128,
+    swap_token_b_amount: u128,
+    pool_supply: u128,
+    trade_direction: TradeDirection,
+    round_direction: RoundDirection,
+) -> Option<u128> {
+    let token_b_price = U256::from(token_b_price);
+    let given_value = match trade_direction {
+        TradeDirection::AtoB => U256::from(source_amount),
+        TradeDirection::BtoA => U256::from(source_amount).checked_mul(token_b_price)?,
+    };
+    let total_value = U256::from(swap_token_b_amount)
+        .checked_mul(token_b_price)?
+        .checked_add(U256::from(swap_token_a_amount))?;
+    let pool_supply = U256::from(pool_supply);
+    let pool_tokens = match round_direction {
+        RoundDirection::Floor => pool_supply
+            .checked_mul(given_value)?
+            .checked_div(total_value)?,
+        RoundDirection::Ceil => {
+            pool_supply
+                .checked_mul(given_value)?
+                .checked_ceil_div(total_value)?
+                .0
+        }
+    };
+    u128::try_from(pool_tokens).ok()
+}
+
+impl CurveCalculator for ConstantPriceCurve {
+    fn swap_without_token_fees(
+        &self,
+        source_amount: u128,
+        _swap_source_amount: u128,
+        _swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<SwapWithoutFeesResult> {
+        let token_b_price = u128::from(self.token_b_price);
+
+        let (source_amount_swapped, destination_amount_swapped) = match trade_direction {
+            TradeDirection::BtoA => (source_amount, source_amount.checked_mul(token_b_price)?),
+            TradeDirection::AtoB => {
+                let destination_amount_swapped = source_amount.checked_div(token_b_price)?;
+                // Only take the token A that buys whole tokens B
+                let remainder = source_amount.checked_rem(token_b_price)?;
+                let source_amount_swapped = source_amount.checked_sub(remainder)?;
+                (source_amount_swapped, destination_amount_swapped)
+            }
+        };
+
+        Some(SwapWithoutFeesResult {
+            source_amount_swapped: map_zero_to_none(source_amount_swapped)?,
+            destination_amount_swapped: map_zero_to_none(destination_amount_swapped)?,
+        })
+    }
+
+    fn pool_tokens_to_trading_tokens(
+        &self,
+        pool_tokens: u128,
+        pool_token_supply: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        round_direction: RoundDirection,
+    ) -> Option<TradingTokenResult> {
+        let token_b_price = u128::from(self.token_b_price);
+        let total_value = self
+            .normalized_value(swap_token_a_amount, swap_token_b_amount)?
+            .floor()?
+            .to_imprecise()?;
+
+        let (token_a_amount, token_b_amount) = match round_direction {
+            RoundDirection::Floor => {
+                let token_a_amount = pool_tokens
+                    .checked_mul(total_value)?
+                    .checked_div(pool_token_supply)?;
+                let token_b_amount = pool_tokens
+                    .checked_mul(total_value)?
+                    .checked_div(token_b_price)?
+                    .checked_div(pool_token_supply)?;
+                (token_a_amount, token_b_amount)
+            }
+            RoundDirection::Ceil => {
+                let (token_a_amount, _) = pool_tokens
+                    .checked_mul(total_value)?
+                    .checked_ceil_div(pool_token_supply)?;
+                let (pool_value_as_token_b, _) = pool_tokens
+                    .checked_mul(total_value)?
+                    .checked_ceil_div(token_b_price)?;
+                let (token_b_amount, _) =
+                    pool_value_as_token_b.checked_ceil_div(pool_token_supply)?;
+                (token_a_amount, token_b_amount)
+            }
+        };
+
+        Some(TradingTokenResult {
+            token_a_amount,
+            token_b_amount,
+        })
+    }
+
+    fn deposit_single_token_type(
+        &self,
+        source_amount: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        pool_supply: u128,
+        trade_direction: TradeDirection,
+        round_direction: RoundDirection,
+    ) -> Option<u128> {
+        trading_tokens_to_pool_tokens(
+            self.token_b_price,
+            source_amount,
+            swap_token_a_amount,
+            swap_token_b_amount,
+            pool_supply,
+            trade_direction,
+            round_direction,
+        )
+    }
+
+    fn withdraw_single_token_type_exact_out(
+        &self,
+        source_amount: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        pool_supply: u128,
+        trade_direction: TradeDirection,
+        round_direction: RoundDirection,
+    ) -> Option<u128> {
+        trading_tokens_to_pool_tokens(
+            self.token_b_price,
+            source_amount,
+            swap_token_a_amount,
+            swap_token_b_amount,
+            pool_supply,
+            trade_direction,
+            round_direction,
+        )
+    }
+
+    fn validate(&self) -> Result<(), SwapError> {
+        if self.token_b_price == 0 {
+            Err(SwapError::InvalidCurve)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Token A is valued through the fixed price of token B, so a pool may
+    /// start with only token B
+    fn validate_supply(&self, _token_a_amount: u64, token_b_amount: u64) -> Result<(), SwapError> {
+        if token_b_amount == 0 {
+            return Err(SwapError::EmptySupply);
+        }
+        Ok(())
+    }
+
+    fn normalized_value(
+        &self,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+    ) -> Option<PreciseNumber> {
+        let swap_token_b_value = swap_token_b_amount.checked_mul(u128::from(self.token_b_price))?;
+        // Value in token A, `a + m * b`, halved to match the scale of an
+        // equal-value pool. The halving is kept precise so that the value grows
+        // with every token added to either reserve.
+        let value = PreciseNumber::new(swap_token_a_amount.checked_add(swap_token_b_value)?)?;
+        value.checked_div(&PreciseNumber::new(2)?)
+    }
+    fn marginal_price_after(
+        &self,
+        _source_amount: u128,
+        _swap_source_amount: u128,
+        _swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<PreciseNumber> {
+        // The price never moves, whatever the size of the swap
+        let token_b_price = PreciseNumber::new(u128::from(self.token_b_price))?;
+        match trade_direction {
+            TradeDirection::AtoB => PreciseNumber::new(1)?.checked_div(&token_b_price),
+            TradeDirection::BtoA => Some(token_b_price),
+        }
+    }
+
+    fn spot_price(
+        &self,
+        _swap_token_a_amount: u128,
+        _swap_token_b_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<PreciseNumber> {
+        self.marginal_price_after(0, 0, 0, trade_direction)
+    }
+}
+
+impl IsInitialized for ConstantPriceCurve {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+}
+impl Sealed for ConstantPriceCurve {}
+impl Pack for ConstantPriceCurve {
+    const LEN: usize = 8;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        (self as &dyn DynPack).pack_into_slice(dst);
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, anchor_lang::prelude::ProgramError> {
+        let token_b_price = array_ref![src, 0, 8];
+        Ok(Self {
+            token_b_price: u64::from_le_bytes(*token_b_price),
+        })
+    }
+}
+
+impl DynPack for ConstantPriceCurve {
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let token_b_price = array_mut_ref![dst, 0, 8];
+        *token_b_price = self.token_b_price.to_le_bytes();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::test_util::Rng;
+
+    #[test]
+    fn normalized_value_grows_with_either_reserve() {
+        let curve = ConstantPriceCurve { token_b_price: 7 };
+        let mut rng = Rng(0x5eed);
+        for _ in 0..1_000 {
+            let token_a = rng.amount(12);
+            let token_b = rng.amount(12);
+            let value = curve.normalized_value(token_a, token_b).unwrap();
+            let more_a = curve.normalized_value(token_a + 1, token_b).unwrap();
+            let more_b = curve.normalized_value(token_a, token_b + 1).unwrap();
+            assert!(more_a.greater_than(&value));
+            assert!(more_b.greater_than(&more_a));
+        }
+    }
+
+    #[test]
+    fn normalized_value_unchanged_by_swap() {
+        let curve = ConstantPriceCurve { token_b_price: 7 };
+        let mut rng = Rng(0x5eed);
+        for _ in 0..1_000 {
+            let token_a = rng.amount(12);
+            let token_b = rng.amount(12);
+            let source_amount = rng.amount(6);
+            let value = curve.normalized_value(token_a, token_b).unwrap();
+            for trade_direction in [TradeDirection::AtoB, TradeDirection::BtoA] {
+                let (swap_source, swap_destination) = match trade_direction {
+                    TradeDirection::AtoB => (token_a, token_b),
+                    TradeDirection::BtoA => (token_b, token_a),
+                };
+                let Some(result) = curve.swap_without_token_fees(
+                    source_amount,
+                    swap_source,
+                    swap_destination,
+                    trade_direction,
+                ) else {
+                    continue;
+                };
+                if result.destination_amount_swapped > swap_destination {
+                    continue;
+                }
+                let swap_source = swap_source + result.source_amount_swapped;
+                let swap_destination = swap_destination - result.destination_amount_swapped;
+                let (token_a, token_b) = match trade_direction {
+                    TradeDirection::AtoB => (swap_source, swap_destination),
+                    TradeDirection::BtoA => (swap_destination, swap_source),
+                };
+                // A swap trades at the fixed price, so the pool keeps its value
+                assert_eq!(curve.normalized_value(token_a, token_b).unwrap(), value);
+            }
+        }
+    }
+}
@@ -1,11 +1,18 @@
 use anchor_lang::solana_program::program_pack::{IsInitialized, Pack, Sealed};
-use spl_math::{checked_ceil_div::CheckedCeilDiv, precise_number::PreciseNumber};
+use spl_math::{
+    approximations::sqrt, checked_ceil_div::CheckedCeilDiv, precise_number::PreciseNumber,
+    uint::U256,
+};
 
 use super::calculator::{
     map_zero_to_none, CurveCalculator, DynPack, RoundDirection, SwapWithoutFeesResult,
-    TradeDirection, TradingTokenResult,
+    TradeDirection, TradingTokenResult, INITIAL_SWAP_POOL_AMOUNT,
 };
 
+/// Constant product curve, `x * y = k`.
+///
+/// The invariant is scale-invariant, so reserves are used in raw token units
+/// and the decimals of token A and B never need to be normalized.
 #[derive(Debug, PartialEq, Eq, Default)]
 pub struct ConstantProductCurve;
 
@@ -14,10 +21,15 @@ pub fn swap(
     swap_source_amount: u128,
     swap_destination_amount: u128,
 ) -> Option<SwapWithoutFeesResult> {
-    let invariant = swap_source_amount.checked_mul(swap_destination_amount)?;
-    let new_swap_source_amount = swap_source_amount.checked_add(source_amount)?;
+    // The invariant is kept in 256 bits, since the product of deep reserves
+    // overflows a u128
+    let invariant =
+        U256::from(swap_source_amount).checked_mul(U256::from(swap_destination_amount))?;
+    let new_swap_source_amount = U256::from(swap_source_amount.checked_add(source_amount)?);
     let (new_swap_destination_amount, new_swap_source_amount) =
         invariant.checked_ceil_div(new_swap_source_amount)?;
+    let new_swap_source_amount = u128::try_from(new_swap_source_amount).ok()?;
+    let new_swap_destination_amount = u128::try_from(new_swap_destination_amount).ok()?;
     let source_amount_swapped = new_swap_source_amount.checked_sub(swap_source_amount)?;
     let destination_amount_swapped =
         map_zero_to_none(swap_destination_amount.checked_sub(new_swap_destination_amount)?)?;
@@ -28,6 +40,20 @@ pub fn swap(
     })
 }
 
+/// Spot price after a constant product swap, `(dest - out) / (source + in)`.
+pub fn marginal_price_after(
+    source_amount: u128,
+    swap_source_amount: u128,
+    swap_destination_amount: u128,
+) -> Option<PreciseNumber> {
+    let result = swap(source_amount, swap_source_amount, swap_destination_amount)?;
+    let new_swap_source_amount = swap_source_amount.checked_add(result.source_amount_swapped)?;
+    let new_swap_destination_amount =
+        swap_destination_amount.checked_sub(result.destination_amount_swapped)?;
+    PreciseNumber::new(new_swap_destination_amount)?
+        .checked_div(&PreciseNumber::new(new_swap_source_amount)?)
+}
+
 pub fn pool_tokens_to_trading_tokens(
     pool_tokens: u128,
     pool_token_supply: u128,
@@ -92,29 +118,20 @@ pub fn pool_tokens_to_trading_tokens(
 ///    - If depositing token **A**, it takes the total **A** balance.
 ///    - If depositing token **B**, it takes the total **B** balance.
 ///
-/// 2. **Convert to PreciseNumber Format:**
-///    - Converts `swap_source_amount` and `source_amount` into `PreciseNumber` for high-precision calculations.
-///
-/// 3. **Compute Deposit Ratio:**
-///    - Calculates the **deposit ratio**:
-///      ```math
-///      ratio = source_amount\swap_source_amount
-///      ```
-///    - This determines how much the deposited token changes the pool balance.
-///
-/// 4. **Apply Square Root-Based Adjustment:**
+/// 2. **Compute the New Supply:**
 ///    - Uses the formula:
 ///      ```math
-///      root = 1 - sqrt(1 + ratio)
+///      new_supply = sqrt(supply^2 * (swap_source_amount + source_amount) / swap_source_amount)
 ///      ```
-///    - This accounts for the **non-linear impact** of a single-token deposit on the pool reserves.
+///    - This is `supply * sqrt(1 + ratio)`, kept in 256-bit integers rather than `PreciseNumber`, whose
+///      12 decimals would round the ratio of a small deposit into a deep pool to zero.
 ///
-/// 5. **Scale by LP Token Supply:**
-///    - Multiplies `root` by the total LP token supply to determine how many LP tokens should be minted.
+/// 3. **Apply Rounding:**
+///    - If `RoundDirection::Floor`, the division and square root round down.
+///    - If `RoundDirection::Ceil`, they round up.
 ///
-/// 6. **Apply Rounding for Precision:**
-///    - If `RoundDirection::Floor`, rounds down.
-///    - If `RoundDirection::Ceil`, rounds up.
+/// 4. **Subtract the Current Supply:**
+///    - The LP tokens minted are `new_supply - supply`.
 ///
 /// # Why is the Square Root Used?
 /// - The function follows **constant product AMM** principles (like Uniswap and Balancer).
@@ -145,18 +162,36 @@ pub fn deposit_single_token_type(
         TradeDirection::AtoB => swap_token_a_amount,
         TradeDirection::BtoA => swap_token_b_amount,
     };
-    let swap_source_amount = PreciseNumber::new(swap_source_amount)?;
-    let source_amount = PreciseNumber::new(source_amount)?;
-    let ratio = source_amount.checked_div(&swap_source_amount)?;
-    let one = PreciseNumber::new(1)?;
-    let base = one.checked_add(&ratio)?;
-    let root = one.checked_sub(&base.sqrt()?)?;
-    let pool_supply = PreciseNumber::new(pool_supply)?;
-    let pool_tokens = pool_supply.checked_mul(&root)?;
-    match round_direction {
-        RoundDirection::Floor => pool_tokens.floor()?.to_imprecise(),
-        RoundDirection::Ceil => pool_tokens.ceiling()?.to_imprecise(),
+    if swap_source_amount == 0 {
+        return None;
     }
+
+    // The ratio only has the 12 decimals of a PreciseNumber, which rounds
+    // small deposits into deep pools to nothing. The new supply is instead
+    // computed in integers, `sqrt(supply^2 * (reserve + source) / reserve)`,
+    // rounded as requested.
+    let pool_supply = U256::from(pool_supply);
+    let swap_source_amount = U256::from(swap_source_amount);
+    let squared_supply = pool_supply
+        .checked_mul(pool_supply)?
+        .checked_mul(swap_source_amount.checked_add(U256::from(source_amount))?)?;
+    let new_pool_supply = match round_direction {
+        RoundDirection::Floor => squared_supply
+            .checked_div(swap_source_amount)?
+            .integer_sqrt(),
+        RoundDirection::Ceil => {
+            let squared_supply = squared_supply
+                .checked_add(swap_source_amount.checked_sub(U256::one())?)?
+                .checked_div(swap_source_amount)?;
+            let root = squared_supply.integer_sqrt();
+            if root.checked_mul(root)? < squared_supply {
+                root.checked_add(U256::one())?
+            } else {
+                root
+            }
+        }
+    };
+    u128::try_from(new_pool_supply.checked_sub(pool_supply)?).ok()
 }
 
 /// Computes the number of liquidity pool (LP) tokens a user must burn to withdraw an exact amount of a single token (A or B).
@@ -254,6 +289,16 @@ impl CurveCalculator for ConstantProductCurve {
         swap(source_amount, swap_source_amount, swap_destination_amount)
     }
 
+    fn new_supply_pool(&self, token_a_amount: u128, token_b_amount: u128) -> u128 {
+        // Geometric mean of the seeded reserves, so the initial supply tracks
+        // the invariant instead of an arbitrary constant
+        token_a_amount
+            .checked_mul(token_b_amount)
+            .and_then(sqrt)
+            .and_then(map_zero_to_none)
+            .unwrap_or(INITIAL_SWAP_POOL_AMOUNT)
+    }
+
     fn pool_tokens_to_trading_tokens(
         &self,
         pool_tokens: u128,
@@ -320,6 +365,15 @@ impl CurveCalculator for ConstantProductCurve {
     ) -> Option<spl_math::precise_number::PreciseNumber> {
         normalize_value(swap_token_a_amount, swap_token_b_amount)
     }
+    fn marginal_price_after(
+        &self,
+        source_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        _trade_direction: TradeDirection,
+    ) -> Option<PreciseNumber> {
+        marginal_price_after(source_amount, swap_source_amount, swap_destination_amount)
+    }
 }
 
 impl IsInitialized for ConstantProductCurve {
@@ -343,3 +397,284 @@ impl Pack for ConstantProductCurve {
 impl DynPack for ConstantProductCurve {
     fn pack_into_slice(&self, _: &mut [u8]) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::test_util::Rng;
+
+    #[test]
+    fn swap_never_decreases_invariant() {
+        let mut rng = Rng(0x5eed);
+        for _ in 0..10_000 {
+            let source_amount = rng.amount(18);
+            let swap_source_amount = rng.amount(18);
+            let swap_destination_amount = rng.amount(18);
+            let Some(result) = swap(source_amount, swap_source_amount, swap_destination_amount)
+            else {
+                continue;
+            };
+            let invariant = U256::from(swap_source_amount) * U256::from(swap_destination_amount);
+            let new_invariant = U256::from(swap_source_amount + result.source_amount_swapped)
+                * U256::from(swap_destination_amount - result.destination_amount_swapped);
+            assert!(new_invariant >= invariant);
+            assert!(result.source_amount_swapped <= source_amount);
+        }
+    }
+
+    #[test]
+    fn swap_output_below_destination_reserve() {
+        let mut rng = Rng(0xcafe);
+        for _ in 0..10_000 {
+            let swap_destination_amount = rng.amount(18);
+            if let Some(result) = swap(rng.amount(18), rng.amount(18), swap_destination_amount) {
+                assert!(result.destination_amount_swapped > 0);
+                assert!(result.destination_amount_swapped < swap_destination_amount);
+            }
+        }
+    }
+
+    #[test]
+    fn swap_with_reserves_overflowing_u128_product() {
+        // u128::MAX.isqrt() is just under 2^64, so these products no longer
+        // fit in a u128
+        let reserves = [
+            (1u128 << 64, 1u128 << 64),
+            ((1 << 64) - 1, (1 << 64) + 2),
+            (1 << 70, 1 << 80),
+            (u128::MAX >> 1, u128::MAX >> 1),
+        ];
+        for (swap_source_amount, swap_destination_amount) in reserves {
+            assert!(swap_source_amount
+                .checked_mul(swap_destination_amount)
+                .is_none());
+            let source_amount = swap_source_amount / 1_000;
+            let result = swap(source_amount, swap_source_amount, swap_destination_amount).unwrap();
+            assert_eq!(result.source_amount_swapped, source_amount);
+            // Adding a thousandth of the source takes about 1/1001 of the
+            // destination
+            assert!(result.destination_amount_swapped <= swap_destination_amount / 1_001);
+            assert!(result.destination_amount_swapped > swap_destination_amount / 1_002);
+        }
+    }
+
+    #[test]
+    fn swap_zero_input() {
+        let mut rng = Rng(0xbeef);
+        for _ in 0..1_000 {
+            assert!(swap(0, rng.amount(18), rng.amount(18)).is_none());
+        }
+    }
+
+    #[test]
+    fn pool_tokens_to_trading_tokens_divisible() {
+        // 100 * 1_000 and 100 * 5_000 are both divisible by 1_000
+        for round_direction in [RoundDirection::Floor, RoundDirection::Ceil] {
+            let tokens =
+                pool_tokens_to_trading_tokens(100, 1_000, 1_000, 5_000, round_direction).unwrap();
+            assert_eq!(tokens.token_a_amount, 100);
+            assert_eq!(tokens.token_b_amount, 500);
+        }
+    }
+
+    #[test]
+    fn pool_tokens_to_trading_tokens_not_divisible() {
+        // 7 * 1_000 / 3 = 2_333.33 and 7 * 2_000 / 3 = 4_666.67
+        let floor =
+            pool_tokens_to_trading_tokens(7, 3, 1_000, 2_000, RoundDirection::Floor).unwrap();
+        assert_eq!(floor.token_a_amount, 2_333);
+        assert_eq!(floor.token_b_amount, 4_666);
+
+        let ceil = pool_tokens_to_trading_tokens(7, 3, 1_000, 2_000, RoundDirection::Ceil).unwrap();
+        assert_eq!(ceil.token_a_amount, 2_334);
+        assert_eq!(ceil.token_b_amount, 4_667);
+    }
+
+    #[test]
+    fn pool_tokens_to_trading_tokens_ceil_leaves_zero_at_zero() {
+        // 1 * 10 / 1_000 floors to zero and is not rounded up
+        let tokens =
+            pool_tokens_to_trading_tokens(1, 1_000, 10, 5_000, RoundDirection::Ceil).unwrap();
+        assert_eq!(tokens.token_a_amount, 0);
+        assert_eq!(tokens.token_b_amount, 5);
+    }
+
+    #[test]
+    fn pool_tokens_to_trading_tokens_rounds_in_pool_favor() {
+        let supply = 1_000_003u128;
+        let reserves = [(1u128, 1u128), (997, 1_000_001), (123_456_789, 987_654_321)];
+        for (swap_token_a_amount, swap_token_b_amount) in reserves {
+            for pool_tokens in [1u128, 2, 3, 999, 1_000_003 / 7, 1_000_003] {
+                let ceil = pool_tokens_to_trading_tokens(
+                    pool_tokens,
+                    supply,
+                    swap_token_a_amount,
+                    swap_token_b_amount,
+                    RoundDirection::Ceil,
+                )
+                .unwrap();
+                let floor = pool_tokens_to_trading_tokens(
+                    pool_tokens,
+                    supply,
+                    swap_token_a_amount,
+                    swap_token_b_amount,
+                    RoundDirection::Floor,
+                )
+                .unwrap();
+
+                // Withdrawals never pay out more than the exact share
+                assert!(floor.token_a_amount * supply <= pool_tokens * swap_token_a_amount);
+                assert!(floor.token_b_amount * supply <= pool_tokens * swap_token_b_amount);
+                // Deposits never pay in less than the exact share, unless
+                // the share floors to zero
+                if floor.token_a_amount > 0 {
+                    assert!(ceil.token_a_amount * supply >= pool_tokens * swap_token_a_amount);
+                }
+                if floor.token_b_amount > 0 {
+                    assert!(ceil.token_b_amount * supply >= pool_tokens * swap_token_b_amount);
+                }
+                assert!(ceil.token_a_amount - floor.token_a_amount <= 1);
+                assert!(ceil.token_b_amount - floor.token_b_amount <= 1);
+            }
+        }
+    }
+
+    #[test]
+    fn pool_tokens_to_trading_tokens_deposit_then_withdraw() {
+        let (swap_token_a_amount, swap_token_b_amount, supply) = (1_000_001u128, 333_337, 99_991);
+        for pool_tokens in [1u128, 2, 17, 1_000, 99_991] {
+            let deposit = pool_tokens_to_trading_tokens(
+                pool_tokens,
+                supply,
+                swap_token_a_amount,
+                swap_token_b_amount,
+                RoundDirection::Ceil,
+            )
+            .unwrap();
+            let withdraw = pool_tokens_to_trading_tokens(
+                pool_tokens,
+                supply + pool_tokens,
+                swap_token_a_amount + deposit.token_a_amount,
+                swap_token_b_amount + deposit.token_b_amount,
+                RoundDirection::Floor,
+            )
+            .unwrap();
+            assert!(withdraw.token_a_amount <= deposit.token_a_amount);
+            assert!(withdraw.token_b_amount <= deposit.token_b_amount);
+        }
+    }
+
+    // Reserves and supply of a pool seeded with 1_000_000 and 250_000
+    // tokens of 6 decimals, whose supply is the geometric mean of the seed
+    const SWAP_TOKEN_A_AMOUNT: u128 = 1_000_000_000_000;
+    const SWAP_TOKEN_B_AMOUNT: u128 = 250_000_000_000;
+    const POOL_SUPPLY: u128 = 500_000_000_000;
+
+    #[test]
+    fn deposit_single_token_type_mints_for_nonzero_deposit() {
+        for source_amount in [10u128, 1_000, 1_000_000, 1_000_000_000_000] {
+            for trade_direction in [TradeDirection::AtoB, TradeDirection::BtoA] {
+                let pool_tokens = deposit_single_token_type(
+                    source_amount,
+                    SWAP_TOKEN_A_AMOUNT,
+                    SWAP_TOKEN_B_AMOUNT,
+                    POOL_SUPPLY,
+                    trade_direction,
+                    RoundDirection::Floor,
+                )
+                .unwrap();
+                assert!(pool_tokens > 0);
+            }
+        }
+    }
+
+    #[test]
+    fn deposit_single_token_type_floor_not_above_ceil() {
+        for source_amount in [0u128, 1, 3, 999, 123_456_789, 1_000_000_000_000] {
+            for trade_direction in [TradeDirection::AtoB, TradeDirection::BtoA] {
+                let deposit = |round_direction| {
+                    deposit_single_token_type(
+                        source_amount,
+                        SWAP_TOKEN_A_AMOUNT,
+                        SWAP_TOKEN_B_AMOUNT,
+                        POOL_SUPPLY,
+                        trade_direction,
+                        round_direction,
+                    )
+                    .unwrap()
+                };
+                let floor = deposit(RoundDirection::Floor);
+                let ceil = deposit(RoundDirection::Ceil);
+                assert!(floor <= ceil);
+                assert!(ceil - floor <= 1);
+            }
+        }
+    }
+
+    #[test]
+    fn deposit_single_token_type_then_withdraw() {
+        for source_amount in [1u128, 1_000, 1_000_000, 50_000_000_000, 1_000_000_000_000] {
+            let pool_tokens = deposit_single_token_type(
+                source_amount,
+                SWAP_TOKEN_A_AMOUNT,
+                SWAP_TOKEN_B_AMOUNT,
+                POOL_SUPPLY,
+                TradeDirection::AtoB,
+                RoundDirection::Floor,
+            )
+            .unwrap();
+            let swap_token_a_amount = SWAP_TOKEN_A_AMOUNT + source_amount;
+            let pool_supply = POOL_SUPPLY + pool_tokens;
+
+            let withdrawn = pool_tokens_to_trading_tokens(
+                pool_tokens,
+                pool_supply,
+                swap_token_a_amount,
+                SWAP_TOKEN_B_AMOUNT,
+                RoundDirection::Floor,
+            )
+            .unwrap();
+            // Token B withdrawn is swapped back into token A against what is
+            // left in the pool
+            let swapped = swap(
+                withdrawn.token_b_amount,
+                SWAP_TOKEN_B_AMOUNT - withdrawn.token_b_amount,
+                swap_token_a_amount - withdrawn.token_a_amount,
+            )
+            .map_or(0, |result| result.destination_amount_swapped);
+            assert!(withdrawn.token_a_amount + swapped <= source_amount);
+        }
+    }
+
+    #[test]
+    fn new_supply_pool_is_geometric_mean_of_seeds() {
+        let curve = ConstantProductCurve;
+        assert_eq!(curve.new_supply_pool(1_000, 1_000), 1_000);
+        assert_eq!(curve.new_supply_pool(100, 10_000), 1_000);
+        assert_eq!(curve.new_supply_pool(10_000, 40_000), 20_000);
+        // Rounds down
+        assert_eq!(curve.new_supply_pool(2, 1), 1);
+    }
+
+    #[test]
+    fn new_supply_pool_scales_with_seed_size() {
+        let curve = ConstantProductCurve;
+        let small = curve.new_supply_pool(1_000, 4_000);
+        // Scaling both seeds scales the supply by the same factor
+        assert_eq!(curve.new_supply_pool(10_000, 40_000), small * 10);
+        assert_eq!(curve.new_supply_pool(1_000_000, 4_000_000), small * 1_000);
+        // Lopsided seeds of the same product mint the same supply
+        assert_eq!(curve.new_supply_pool(4_000, 1_000), small);
+        assert_eq!(curve.new_supply_pool(400, 10_000), small);
+    }
+
+    #[test]
+    fn new_supply_pool_falls_back_without_mean() {
+        let curve = ConstantProductCurve;
+        assert_eq!(curve.new_supply_pool(0, 1_000), INITIAL_SWAP_POOL_AMOUNT);
+        assert_eq!(
+            curve.new_supply_pool(u128::MAX, 2),
+            INITIAL_SWAP_POOL_AMOUNT
+        );
+    }
+}
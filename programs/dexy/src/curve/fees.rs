@@ -3,8 +3,15 @@ use anchor_lang::{
     solana_program::program_pack::{IsInitialized, Pack, Sealed},
 };
 use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
+use spl_math::{precise_number::PreciseNumber, uint::U256};
 
-use crate::SwapError;
+use crate::{SwapError, BASIS_POINTS};
+
+/// Ceiling on the trade fee plus the owner trade fee, in basis points
+pub const MAX_TOTAL_TRADE_FEE_BPS: u16 = 1_000;
+
+/// Most the dynamic fee can multiply the trade fee by
+pub const MAX_DYNAMIC_FEE_MULTIPLIER: u128 = 10;
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, Default, PartialEq)]
 pub struct CurveFees {
@@ -36,6 +43,23 @@ pub struct CurveFees {
     pub host_fee_numerator: u64,
     /// Host trading fee denominator
     pub host_fee_denominator: u64,
+
+    /// Flash loan fees are extra token amounts that must be returned to the
+    /// borrowed reserve on top of the loan, making the value of liquidity
+    /// tokens rise.
+    /// Flash loan fee numerator
+    pub flash_loan_fee_numerator: u64,
+    /// Flash loan fee denominator
+    pub flash_loan_fee_denominator: u64,
+
+    /// Dynamic fees scale the trade fee up when a swap leaves the source
+    /// reserve worth more than the destination reserve at the spot price, by
+    /// `1 + dynamic_fee * (source value / destination - 1)`, up to
+    /// `MAX_DYNAMIC_FEE_MULTIPLIER` times. Both zero for static fees.
+    /// Dynamic fee numerator
+    pub dynamic_fee_numerator: u64,
+    /// Dynamic fee denominator
+    pub dynamic_fee_denominator: u64,
 }
 
 fn validate_fraction(numerator: u64, denominator: u64) -> std::result::Result<(), SwapError> {
@@ -48,6 +72,15 @@ fn validate_fraction(numerator: u64, denominator: u64) -> std::result::Result<()
     }
 }
 
+/// Fee fraction with an unset `0 / 0` fee read as zero.
+fn fee_fraction(numerator: u64, denominator: u64) -> (U256, U256) {
+    if denominator == 0 {
+        (U256::zero(), U256::one())
+    } else {
+        (U256::from(numerator), U256::from(denominator))
+    }
+}
+
 pub fn calculate_fee(
     token_amount: u128,
     fee_numerator: u128,
@@ -100,6 +133,74 @@ impl CurveFees {
         )
     }
 
+    pub fn flash_loan_fee(&self, loan_amount: u128) -> Option<u128> {
+        calculate_fee(
+            loan_amount,
+            u128::from(self.flash_loan_fee_numerator),
+            u128::from(self.flash_loan_fee_denominator),
+        )
+    }
+
+    /// Trade fee of a swap scaled by the imbalance it leaves, given the
+    /// reserves after the swap and the spot price before it, in destination
+    /// tokens per source token. The reserves are compared by value at that
+    /// price, so swaps that don't leave the source side worth more than the
+    /// destination side pay `trade_fee` as is.
+    pub fn dynamic_trading_fee(
+        &self,
+        trade_fee: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        spot_price: &PreciseNumber,
+    ) -> Option<u128> {
+        if self.dynamic_fee_numerator == 0 {
+            return Some(trade_fee);
+        }
+        let swap_source_value = PreciseNumber::new(swap_source_amount)?
+            .checked_mul(spot_price)?
+            .floor()?
+            .to_imprecise()?;
+        if swap_source_value <= swap_destination_amount {
+            return Some(trade_fee);
+        }
+        // trade_fee * (d * destination + n * (source value - destination)) / (d * destination)
+        let numerator = U256::from(self.dynamic_fee_numerator);
+        let denominator = U256::from(self.dynamic_fee_denominator)
+            .checked_mul(U256::from(swap_destination_amount))?;
+        let excess = U256::from(swap_source_value.checked_sub(swap_destination_amount)?);
+        let scaled_fee = U256::from(trade_fee)
+            .checked_mul(denominator.checked_add(numerator.checked_mul(excess)?)?)?
+            .checked_div(denominator)?;
+        let max_fee = trade_fee.checked_mul(MAX_DYNAMIC_FEE_MULTIPLIER)?;
+        if scaled_fee > U256::from(max_fee) {
+            Some(max_fee)
+        } else {
+            u128::try_from(scaled_fee).ok()
+        }
+    }
+
+    /// Rejects a trade fee plus owner trade fee above `MAX_TOTAL_TRADE_FEE_BPS`.
+    /// Checked when fees are set rather than in `validate`, so that pools
+    /// created with higher fees keep working.
+    pub fn validate_total_trade_fee(&self) -> std::result::Result<(), SwapError> {
+        let (trade_numerator, trade_denominator) =
+            fee_fraction(self.trade_fee_numerator, self.trade_fee_denominator);
+        let (owner_numerator, owner_denominator) = fee_fraction(
+            self.owner_trade_fee_numerator,
+            self.owner_trade_fee_denominator,
+        );
+        // trade + owner <= max / BASIS_POINTS, over a common denominator
+        let total_numerator =
+            trade_numerator * owner_denominator + owner_numerator * trade_denominator;
+        let denominator = trade_denominator * owner_denominator;
+        if total_numerator * U256::from(BASIS_POINTS)
+            > denominator * U256::from(MAX_TOTAL_TRADE_FEE_BPS)
+        {
+            return Err(SwapError::InvalidFees);
+        }
+        Ok(())
+    }
+
     pub fn validate(&self) -> std::result::Result<(), SwapError> {
         validate_fraction(self.trade_fee_numerator, self.trade_fee_denominator)?;
         validate_fraction(
@@ -110,7 +211,16 @@ impl CurveFees {
             self.owner_withdraw_fee_numerator,
             self.owner_withdraw_fee_denominator,
         )?;
-        validate_fraction(self.host_fee_numerator, self.host_fee_denominator)
+        validate_fraction(self.host_fee_numerator, self.host_fee_denominator)?;
+        validate_fraction(
+            self.flash_loan_fee_numerator,
+            self.flash_loan_fee_denominator,
+        )?;
+        // The dynamic fee is a scale rather than a share, so it may exceed one
+        if self.dynamic_fee_numerator != 0 && self.dynamic_fee_denominator == 0 {
+            return Err(SwapError::InvalidFees);
+        }
+        Ok(())
     }
 }
 
@@ -121,10 +231,10 @@ impl IsInitialized for CurveFees {
 }
 impl Sealed for CurveFees {}
 impl Pack for CurveFees {
-    const LEN: usize = 64;
+    const LEN: usize = 96;
 
     fn pack_into_slice(&self, dst: &mut [u8]) {
-        let output = array_mut_ref![dst, 0, 64];
+        let output = array_mut_ref![dst, 0, 96];
         let (
             trade_fee_numerator,
             trade_fee_denominator,
@@ -134,7 +244,11 @@ impl Pack for CurveFees {
             owner_withdraw_fee_denominator,
             host_fee_numerator,
             host_fee_denominator,
-        ) = mut_array_refs![output, 8, 8, 8, 8, 8, 8, 8, 8];
+            flash_loan_fee_numerator,
+            flash_loan_fee_denominator,
+            dynamic_fee_numerator,
+            dynamic_fee_denominator,
+        ) = mut_array_refs![output, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8];
         *trade_fee_numerator = self.trade_fee_numerator.to_le_bytes();
         *trade_fee_denominator = self.trade_fee_denominator.to_le_bytes();
         *owner_trade_fee_numerator = self.owner_trade_fee_numerator.to_le_bytes();
@@ -143,10 +257,14 @@ impl Pack for CurveFees {
         *owner_withdraw_fee_denominator = self.owner_withdraw_fee_denominator.to_le_bytes();
         *host_fee_numerator = self.host_fee_numerator.to_le_bytes();
         *host_fee_denominator = self.host_fee_denominator.to_le_bytes();
+        *flash_loan_fee_numerator = self.flash_loan_fee_numerator.to_le_bytes();
+        *flash_loan_fee_denominator = self.flash_loan_fee_denominator.to_le_bytes();
+        *dynamic_fee_numerator = self.dynamic_fee_numerator.to_le_bytes();
+        *dynamic_fee_denominator = self.dynamic_fee_denominator.to_le_bytes();
     }
 
     fn unpack_from_slice(src: &[u8]) -> std::result::Result<Self, ProgramError> {
-        let input = array_ref![src, 0, 64];
+        let input = array_ref![src, 0, 96];
         let (
             trade_fee_numerator,
             trade_fee_denominator,
@@ -156,7 +274,11 @@ impl Pack for CurveFees {
             owner_withdraw_fee_denominator,
             host_fee_numerator,
             host_fee_denominator,
-        ) = array_refs![input, 8, 8, 8, 8, 8, 8, 8, 8];
+            flash_loan_fee_numerator,
+            flash_loan_fee_denominator,
+            dynamic_fee_numerator,
+            dynamic_fee_denominator,
+        ) = array_refs![input, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8];
         Ok(Self {
             trade_fee_numerator: u64::from_le_bytes(*trade_fee_numerator),
             trade_fee_denominator: u64::from_le_bytes(*trade_fee_denominator),
@@ -166,6 +288,95 @@ impl Pack for CurveFees {
             owner_withdraw_fee_denominator: u64::from_le_bytes(*owner_withdraw_fee_denominator),
             host_fee_numerator: u64::from_le_bytes(*host_fee_numerator),
             host_fee_denominator: u64::from_le_bytes(*host_fee_denominator),
+            flash_loan_fee_numerator: u64::from_le_bytes(*flash_loan_fee_numerator),
+            flash_loan_fee_denominator: u64::from_le_bytes(*flash_loan_fee_denominator),
+            dynamic_fee_numerator: u64::from_le_bytes(*dynamic_fee_numerator),
+            dynamic_fee_denominator: u64::from_le_bytes(*dynamic_fee_denominator),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trade_fees(
+        trade_fee_numerator: u64,
+        trade_fee_denominator: u64,
+        owner_trade_fee_numerator: u64,
+        owner_trade_fee_denominator: u64,
+    ) -> CurveFees {
+        CurveFees {
+            trade_fee_numerator,
+            trade_fee_denominator,
+            owner_trade_fee_numerator,
+            owner_trade_fee_denominator,
+            ..CurveFees::default()
+        }
+    }
+
+    #[test]
+    fn total_trade_fee_at_ceiling() {
+        let max = u64::from(MAX_TOTAL_TRADE_FEE_BPS);
+        let basis_points = u64::from(BASIS_POINTS);
+        assert!(trade_fees(max, basis_points, 0, 0)
+            .validate_total_trade_fee()
+            .is_ok());
+        assert!(trade_fees(0, 0, max, basis_points)
+            .validate_total_trade_fee()
+            .is_ok());
+        assert!(trade_fees(max - 1, basis_points, 1, basis_points)
+            .validate_total_trade_fee()
+            .is_ok());
+        // Same total over different denominators: 6% + 4%
+        assert!(trade_fees(6, 100, 40, 1_000)
+            .validate_total_trade_fee()
+            .is_ok());
+    }
+
+    #[test]
+    fn total_trade_fee_above_ceiling() {
+        let max = u64::from(MAX_TOTAL_TRADE_FEE_BPS);
+        let basis_points = u64::from(BASIS_POINTS);
+        assert!(matches!(
+            trade_fees(max + 1, basis_points, 0, 0).validate_total_trade_fee(),
+            Err(SwapError::InvalidFees)
+        ));
+        assert!(matches!(
+            trade_fees(max, basis_points, 1, basis_points).validate_total_trade_fee(),
+            Err(SwapError::InvalidFees)
+        ));
+        // Just above over a finer denominator: 10% + 1 / 1_000_000
+        assert!(matches!(
+            trade_fees(1, 10, 1, 1_000_000).validate_total_trade_fee(),
+            Err(SwapError::InvalidFees)
+        ));
+    }
+
+    #[test]
+    fn dynamic_trading_fee_compares_reserves_by_value() {
+        let fees = CurveFees {
+            dynamic_fee_numerator: 1,
+            dynamic_fee_denominator: 1,
+            ..CurveFees::default()
+        };
+        let fee = |swap_source_amount, swap_destination_amount, spot_price| {
+            fees.dynamic_trading_fee(
+                1_000,
+                swap_source_amount,
+                swap_destination_amount,
+                &PreciseNumber::new(spot_price).unwrap(),
+            )
+            .unwrap()
+        };
+        // Fewer source tokens can still be worth more than the destination
+        // side
+        assert_eq!(fee(250_000, 1_000_000, 4), 1_000);
+        assert_eq!(fee(250_000, 1_000_000, 5), 1_250);
+
+        // The fee rises as the destination reserve is depleted
+        assert!(fee(300_000, 800_000, 4) > fee(250_000, 1_000_000, 5));
+        assert!(fee(350_000, 600_000, 4) > fee(300_000, 800_000, 4));
+        assert_eq!(fee(400_000, 100_000, 4), 10_000);
+    }
+}
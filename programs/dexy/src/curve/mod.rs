@@ -1,4 +1,9 @@
 pub mod base;
 pub mod calculator;
+pub mod constant_price;
 pub mod constant_product;
 pub mod fees;
+pub mod offset;
+#[cfg(test)]
+pub mod test_util;
+pub mod weighted;
@@ -0,0 +1,274 @@
+use anchor_lang::solana_program::program_pack::{IsInitialized, Pack, Sealed};
+use arrayref::{array_mut_ref, array_ref};
+use spl_math::precise_number::PreciseNumber;
+
+use super::{
+    calculator::{
+        CurveCalculator, DynPack, RoundDirection, SwapWithoutFeesResult, TradeDirection,
+        TradingTokenResult,
+    },
+    constant_product::{
+        deposit_single_token_type, marginal_price_after, normalize_value,
+        pool_tokens_to_trading_tokens, swap, withdraw_single_token_type_exact_out,
+    },
+};
+use crate::SwapError;
+
+/// Constant product curve with a virtual offset on token B,
+/// `(x) * (y + token_b_offset) = k`.
+///
+/// The offset lets a pool sell token B starting from a zero token B reserve,
+/// which suits token launches. Deposits are not allowed, since the virtual
+/// token B has no owner.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OffsetCurve {
+    /// Amount to offset the token B reserve by
+    pub token_b_offset: u64,
+}
+
+impl CurveCalculator for OffsetCurve {
+    fn swap_without_token_fees(
+        &self,
+        source_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<SwapWithoutFeesResult> {
+        let token_b_offset = u128::from(self.token_b_offset);
+        let (swap_source_amount, swap_destination_amount) = match trade_direction {
+            TradeDirection::AtoB => (
+                swap_source_amount,
+                swap_destination_amount.checked_add(token_b_offset)?,
+            ),
+            TradeDirection::BtoA => (
+                swap_source_amount.checked_add(token_b_offset)?,
+                swap_destination_amount,
+            ),
+        };
+        swap(source_amount, swap_source_amount, swap_destination_amount)
+    }
+
+    fn pool_tokens_to_trading_tokens(
+        &self,
+        pool_tokens: u128,
+        pool_token_supply: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        round_direction: RoundDirection,
+    ) -> Option<TradingTokenResult> {
+        pool_tokens_to_trading_tokens(
+            pool_tokens,
+            pool_token_supply,
+            swap_token_a_amount,
+            swap_token_b_amount,
+            round_direction,
+        )
+    }
+
+    fn deposit_single_token_type(
+        &self,
+        source_amount: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        pool_supply: u128,
+        trade_direction: TradeDirection,
+        round_direction: RoundDirection,
+    ) -> Option<u128> {
+        let token_b_offset = u128::from(self.token_b_offset);
+        deposit_single_token_type(
+            source_amount,
+            swap_token_a_amount,
+            swap_token_b_amount.checked_add(token_b_offset)?,
+            pool_supply,
+            trade_direction,
+            round_direction,
+        )
+    }
+
+    fn withdraw_single_token_type_exact_out(
+        &self,
+        source_amount: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        pool_supply: u128,
+        trade_direction: TradeDirection,
+        round_direction: RoundDirection,
+    ) -> Option<u128> {
+        let token_b_offset = u128::from(self.token_b_offset);
+        withdraw_single_token_type_exact_out(
+            source_amount,
+            swap_token_a_amount,
+            swap_token_b_amount.checked_add(token_b_offset)?,
+            pool_supply,
+            trade_direction,
+            round_direction,
+        )
+    }
+
+    fn validate(&self) -> Result<(), SwapError> {
+        if self.token_b_offset == 0 {
+            Err(SwapError::InvalidCurve)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn validate_supply(&self, token_a_amount: u64, _token_b_amount: u64) -> Result<(), SwapError> {
+        if token_a_amount == 0 {
+            return Err(SwapError::EmptySupply);
+        }
+        Ok(())
+    }
+
+    fn allow_deposits(&self) -> bool {
+        false
+    }
+
+    fn normalized_value(
+        &self,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+    ) -> Option<PreciseNumber> {
+        let token_b_offset = u128::from(self.token_b_offset);
+        normalize_value(
+            swap_token_a_amount,
+            swap_token_b_amount.checked_add(token_b_offset)?,
+        )
+    }
+    fn marginal_price_after(
+        &self,
+        source_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<PreciseNumber> {
+        let token_b_offset = u128::from(self.token_b_offset);
+        let (swap_source_amount, swap_destination_amount) = match trade_direction {
+            TradeDirection::AtoB => (
+                swap_source_amount,
+                swap_destination_amount.checked_add(token_b_offset)?,
+            ),
+            TradeDirection::BtoA => (
+                swap_source_amount.checked_add(token_b_offset)?,
+                swap_destination_amount,
+            ),
+        };
+        marginal_price_after(source_amount, swap_source_amount, swap_destination_amount)
+    }
+
+    fn spot_price(
+        &self,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<PreciseNumber> {
+        let (swap_source_amount, swap_destination_amount) = match trade_direction {
+            TradeDirection::AtoB => (
+                swap_token_a_amount,
+                swap_token_b_amount.checked_add(u128::from(self.token_b_offset))?,
+            ),
+            TradeDirection::BtoA => (
+                swap_token_b_amount.checked_add(u128::from(self.token_b_offset))?,
+                swap_token_a_amount,
+            ),
+        };
+        PreciseNumber::new(swap_destination_amount)?
+            .checked_div(&PreciseNumber::new(swap_source_amount)?)
+    }
+}
+
+impl IsInitialized for OffsetCurve {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+}
+impl Sealed for OffsetCurve {}
+impl Pack for OffsetCurve {
+    const LEN: usize = 8;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        (self as &dyn DynPack).pack_into_slice(dst);
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, anchor_lang::prelude::ProgramError> {
+        let token_b_offset = array_ref![src, 0, 8];
+        Ok(Self {
+            token_b_offset: u64::from_le_bytes(*token_b_offset),
+        })
+    }
+}
+
+impl DynPack for OffsetCurve {
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let token_b_offset = array_mut_ref![dst, 0, 8];
+        *token_b_offset = self.token_b_offset.to_le_bytes();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::test_util::Rng;
+
+    #[test]
+    fn normalized_value_grows_with_either_reserve() {
+        let curve = OffsetCurve {
+            token_b_offset: 500_000,
+        };
+        let mut rng = Rng(0x5eed);
+        for _ in 0..1_000 {
+            let token_a = rng.amount(12);
+            let token_b = rng.amount(12);
+            let value = curve.normalized_value(token_a, token_b).unwrap();
+            let more_a = curve.normalized_value(token_a * 2, token_b).unwrap();
+            let more_b = curve.normalized_value(token_a, token_b * 2 + 1).unwrap();
+            assert!(!value.greater_than(&more_a));
+            assert!(!value.greater_than(&more_b));
+        }
+        // The offset counts as token B, so an empty token B reserve has value
+        assert!(curve
+            .normalized_value(1_000, 0)
+            .unwrap()
+            .greater_than(&PreciseNumber::new(0).unwrap()));
+    }
+
+    #[test]
+    fn normalized_value_never_decreases_on_swap() {
+        let curve = OffsetCurve {
+            token_b_offset: 500_000,
+        };
+        let mut rng = Rng(0x5eed);
+        for _ in 0..1_000 {
+            let token_a = rng.amount(12);
+            let token_b = rng.amount(12);
+            let source_amount = rng.amount(9);
+            let value = curve.normalized_value(token_a, token_b).unwrap();
+            for trade_direction in [TradeDirection::AtoB, TradeDirection::BtoA] {
+                let (swap_source, swap_destination) = match trade_direction {
+                    TradeDirection::AtoB => (token_a, token_b),
+                    TradeDirection::BtoA => (token_b, token_a),
+                };
+                let Some(result) = curve.swap_without_token_fees(
+                    source_amount,
+                    swap_source,
+                    swap_destination,
+                    trade_direction,
+                ) else {
+                    continue;
+                };
+                // Token B bought out of the offset is not in the reserve
+                if result.destination_amount_swapped > swap_destination {
+                    continue;
+                }
+                let swap_source = swap_source + result.source_amount_swapped;
+                let swap_destination = swap_destination - result.destination_amount_swapped;
+                let (token_a, token_b) = match trade_direction {
+                    TradeDirection::AtoB => (swap_source, swap_destination),
+                    TradeDirection::BtoA => (swap_destination, swap_source),
+                };
+                let after = curve.normalized_value(token_a, token_b).unwrap();
+                assert!(!value.greater_than(&after));
+            }
+        }
+    }
+}
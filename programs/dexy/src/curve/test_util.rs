@@ -0,0 +1,19 @@
+//! Helpers shared by the unit tests of the curves and quotes.
+
+/// Xorshift generator with a fixed seed, so failures are reproducible.
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Amount spread over magnitudes up to `10^max_digits`.
+    pub fn amount(&mut self, max_digits: u32) -> u128 {
+        let digits = 1 + (self.next() % u64::from(max_digits)) as u32;
+        1 + u128::from(self.next()) % 10u128.pow(digits)
+    }
+}
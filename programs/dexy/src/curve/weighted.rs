@@ -0,0 +1,180 @@
+use spl_math::{precise_number::PreciseNumber, uint::U256};
+
+use super::calculator::{map_zero_to_none, RoundDirection, SwapWithoutFeesResult};
+
+/// Maximum number of reserves in a weighted pool. Deposits and withdrawals
+/// pass two accounts per reserve, which must fit in the remaining accounts.
+pub const MAX_WEIGHTED_TOKENS: usize = 4;
+
+/// Maximum weight of a single reserve, keeping weight ratios small enough
+/// for the power approximation.
+pub const MAX_WEIGHT: u64 = 100;
+
+/// Swaps can bring in at most half of the source reserve, which keeps the
+/// base of the power approximation between 2/3 and 1, where it converges.
+pub const MAX_IN_RATIO_DENOMINATOR: u128 = 2;
+
+/// Iterations of the binomial series before giving up on convergence.
+const MAX_APPROXIMATION_ITERATIONS: u128 = 100;
+
+/// Terms of the binomial series below this precise value are dropped.
+const APPROXIMATION_PRECISION: u128 = 100;
+
+/// Error bound of `pow_fraction`, by which results are moved in the pool's
+/// favor.
+fn approximation_error() -> PreciseNumber {
+    PreciseNumber {
+        value: U256::from(APPROXIMATION_PRECISION),
+    }
+}
+
+/// Weighted constant product curve for pools of more than two tokens,
+/// `prod(reserve_i ^ weight_i) = k`, as in Balancer.
+#[derive(Debug, PartialEq, Eq, Default)]
+pub struct WeightedCurve;
+
+/// `base ^ (numerator / denominator)`, for a base between 0 and 2.
+///
+/// The whole part of the exponent is applied with `checked_pow` and the
+/// fractional part with the binomial series around 1,
+/// `x^n = 1 + n (x - 1) + n (n - 1) / 2! (x - 1)^2 + ...`.
+pub fn pow_fraction(
+    base: &PreciseNumber,
+    numerator: u128,
+    denominator: u128,
+) -> Option<PreciseNumber> {
+    let one = PreciseNumber::new(1)?;
+    let two = PreciseNumber::new(2)?;
+    if base.value.is_zero() || base.greater_than_or_equal(&two) {
+        return None;
+    }
+
+    let whole = base.checked_pow(numerator.checked_div(denominator)?)?;
+    let remainder = numerator.checked_rem(denominator)?;
+    if remainder == 0 {
+        return Some(whole);
+    }
+    let exponent = PreciseNumber::new(remainder)?.checked_div(&PreciseNumber::new(denominator)?)?;
+
+    // Terms are kept unsigned, with their sign tracked separately
+    let (x_minus_one, x_minus_one_negative) = base.unsigned_sub(&one);
+    let mut guess = one.clone();
+    let mut term = one.clone();
+    let mut negative = false;
+    for k in 1..MAX_APPROXIMATION_ITERATIONS {
+        let k = PreciseNumber::new(k)?;
+        // n + 1 - k, negative once k passes n + 1
+        let (factor, factor_negative) = exponent.checked_add(&one)?.unsigned_sub(&k);
+        term = term
+            .checked_mul(&factor)?
+            .checked_mul(&x_minus_one)?
+            .checked_div(&k)?;
+        if term.value < U256::from(APPROXIMATION_PRECISION) {
+            break;
+        }
+        if x_minus_one_negative {
+            negative = !negative;
+        }
+        if factor_negative {
+            negative = !negative;
+        }
+        guess = if negative {
+            guess.checked_sub(&term)?
+        } else {
+            guess.checked_add(&term)?
+        };
+    }
+
+    whole.checked_mul(&guess)
+}
+
+impl WeightedCurve {
+    /// Output of a weighted swap,
+    /// `out = dest * (1 - (source / (source + in)) ^ (source_weight / destination_weight))`,
+    /// rounded down.
+    pub fn swap(
+        &self,
+        source_amount: u128,
+        swap_source_amount: u128,
+        source_weight: u64,
+        swap_destination_amount: u128,
+        destination_weight: u64,
+    ) -> Option<SwapWithoutFeesResult> {
+        if source_amount > swap_source_amount.checked_div(MAX_IN_RATIO_DENOMINATOR)? {
+            return None;
+        }
+
+        let new_swap_source_amount = swap_source_amount.checked_add(source_amount)?;
+        let ratio = PreciseNumber::new(swap_source_amount)?
+            .checked_div(&PreciseNumber::new(new_swap_source_amount)?)?;
+        let power = pow_fraction(
+            &ratio,
+            u128::from(source_weight),
+            u128::from(destination_weight),
+        )?;
+        let power = power.checked_add(&approximation_error())?;
+        let destination_amount_swapped = PreciseNumber::new(swap_destination_amount)?
+            .checked_mul(&PreciseNumber::new(1)?.checked_sub(&power)?)?
+            .floor()?
+            .to_imprecise()?;
+
+        Some(SwapWithoutFeesResult {
+            source_amount_swapped: source_amount,
+            destination_amount_swapped: map_zero_to_none(destination_amount_swapped)?,
+        })
+    }
+
+    /// Pool tokens for a deposit of one reserve token,
+    /// `supply * ((1 + in / reserve) ^ (weight / total_weight) - 1)`, rounded
+    /// down. The deposit is limited to the size of the reserve.
+    pub fn deposit_single_token_type(
+        &self,
+        source_amount: u128,
+        swap_source_amount: u128,
+        source_weight: u64,
+        total_weight: u64,
+        pool_supply: u128,
+    ) -> Option<u128> {
+        if source_amount >= swap_source_amount {
+            return None;
+        }
+
+        let base = PreciseNumber::new(swap_source_amount.checked_add(source_amount)?)?
+            .checked_div(&PreciseNumber::new(swap_source_amount)?)?;
+        let power = pow_fraction(&base, u128::from(source_weight), u128::from(total_weight))?
+            .checked_sub(&approximation_error())?;
+        PreciseNumber::new(pool_supply)?
+            .checked_mul(&power.checked_sub(&PreciseNumber::new(1)?)?)?
+            .floor()?
+            .to_imprecise()
+    }
+
+    /// Reserve tokens matching `pool_tokens` out of `pool_token_supply`, for
+    /// proportional deposits and withdrawals.
+    pub fn pool_tokens_to_trading_tokens(
+        &self,
+        pool_tokens: u128,
+        pool_token_supply: u128,
+        swap_token_amount: u128,
+        round_direction: RoundDirection,
+    ) -> Option<u128> {
+        let token_amount = pool_tokens
+            .checked_mul(swap_token_amount)?
+            .checked_div(pool_token_supply)?;
+        match round_direction {
+            RoundDirection::Floor => Some(token_amount),
+            RoundDirection::Ceil => {
+                // As for two-token pools, dust that floors to zero is left at
+                // zero so the caller rejects it
+                let remainder = pool_tokens
+                    .checked_mul(swap_token_amount)?
+                    .checked_rem(pool_token_supply)?;
+                if remainder > 0 && token_amount > 0 {
+                    token_amount.checked_add(1)
+                } else {
+                    Some(token_amount)
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Instruction discriminators, the first 8 bytes of instruction data, for
+//! programs invoking this one through CPI.
+//!
+//! Programs depending on this crate with the `cpi` feature can call
+//! `dexy::cpi::swap` directly. Others can build the instruction by hand: the
+//! discriminator is followed by the Borsh encoded arguments, as in the
+//! matching `dexy::instruction` struct, and the accounts follow the order of
+//! the instruction's accounts struct. Optional accounts left out are passed
+//! as this program's id.
+//!
+//! ```ignore
+//! use anchor_lang::solana_program::{
+//!     instruction::{AccountMeta, Instruction},
+//!     program::invoke,
+//! };
+//!
+//! let mut data = dexy::discriminator::SWAP.to_vec();
+//! data.extend_from_slice(&amount_in.to_le_bytes());
+//! data.extend_from_slice(&minimum_amount_out.to_le_bytes());
+//!
+//! let instruction = Instruction {
+//!     program_id: dexy::ID,
+//!     accounts: vec![
+//!         AccountMeta::new_readonly(authority, false),
+//!         AccountMeta::new(amm, false),
+//!         AccountMeta::new_readonly(user_transfer_authority, true),
+//!         AccountMeta::new(source, false),
+//!         AccountMeta::new(destination, false),
+//!         AccountMeta::new(swap_source, false),
+//!         AccountMeta::new(swap_destination, false),
+//!         AccountMeta::new(pool_mint, false),
+//!         AccountMeta::new(pool_fee_account, false),
+//!         AccountMeta::new_readonly(spl_token::ID, false),
+//!         // No host fee account, default host fee account, host position,
+//!         // wrapped SOL account, native mint or system program
+//!         AccountMeta::new_readonly(dexy::ID, false),
+//!         AccountMeta::new_readonly(dexy::ID, false),
+//!         AccountMeta::new_readonly(dexy::ID, false),
+//!         AccountMeta::new_readonly(dexy::ID, false),
+//!         AccountMeta::new_readonly(dexy::ID, false),
+//!         AccountMeta::new_readonly(dexy::ID, false),
+//!     ],
+//!     data,
+//! };
+//! invoke(&instruction, &account_infos)?;
+//! ```
+
+use anchor_lang::Discriminator;
+
+use crate::instruction;
+
+pub const SWAP: [u8; 8] = instruction::Swap::DISCRIMINATOR;
+pub const SWAP_WITH_DEADLINE: [u8; 8] = instruction::SwapWithDeadline::DISCRIMINATOR;
+pub const SWAP_WITH_EXPECTED_SLOT: [u8; 8] = instruction::SwapWithExpectedSlot::DISCRIMINATOR;
+pub const SWAP_BATCH: [u8; 8] = instruction::SwapBatch::DISCRIMINATOR;
+pub const SWAP_WITH_PARTIAL_FILL: [u8; 8] = instruction::SwapWithPartialFill::DISCRIMINATOR;
+pub const SIMULATE_SWAP: [u8; 8] = instruction::SimulateSwap::DISCRIMINATOR;
+pub const QUOTE_SWAP: [u8; 8] = instruction::QuoteSwap::DISCRIMINATOR;
+pub const DEPOSIT_LIQUIDITY: [u8; 8] = instruction::DepositLiquidity::DISCRIMINATOR;
+pub const WITHDRAW_LIQUIDITY: [u8; 8] = instruction::WithdrawLiquidity::DISCRIMINATOR;
@@ -1,13 +1,39 @@
+mod convert;
 mod curve;
+pub mod discriminator;
+mod quote;
 
-use anchor_lang::{prelude::*, solana_program::program_option::COption};
-use anchor_spl::token::{self, Burn, Mint, MintTo, TokenAccount, Transfer};
+use anchor_lang::{
+    prelude::*,
+    solana_program::{
+        hash::hashv,
+        instruction::{AccountMeta, Instruction},
+        program::invoke,
+        program_option::COption,
+        program_pack::Pack,
+    },
+    system_program,
+};
+use anchor_spl::token::{
+    self,
+    spl_token::{self, native_mint},
+    Burn, Mint, MintTo, TokenAccount, Transfer,
+};
+use convert::to_u64;
 use curve::{
     base::{CurveType, SwapCurve},
-    calculator::{CurveCalculator, RoundDirection},
+    calculator::{CurveCalculator, RoundDirection, TradeDirection, INITIAL_SWAP_POOL_AMOUNT},
+    constant_price::ConstantPriceCurve,
     constant_product::ConstantProductCurve,
     fees::CurveFees,
+    offset::OffsetCurve,
+    weighted::{WeightedCurve, MAX_IN_RATIO_DENOMINATOR, MAX_WEIGHT, MAX_WEIGHTED_TOKENS},
 };
+use quote::{
+    compute_deposit_single_token_type, compute_minimum_deposit, compute_partial_fill,
+    compute_reserve_ratio, compute_swap, DepositAmounts, MinimumDeposit, ReserveRatio, SwapAmounts,
+};
+use spl_math::{precise_number::PreciseNumber, uint::U256};
 
 declare_id!("HRPryQD82JQcHALokdMpAYL83hUvSaSZGLKoHoFADvV");
 
@@ -21,218 +47,362 @@ pub mod dexy {
         ctx: Context<Initialize>,
         fees_input: FeeInput,
         curve_input: CurveInput,
+        min_pool_value: u64,
+        max_price_impact_bps: u16,
     ) -> Result<()> {
-        let (swap_authority, bump_seed) = Pubkey::find_program_address(
-            &[&ctx.accounts.amm.to_account_info().key.to_bytes()],
+        ctx.accounts.process_initialize(
             ctx.program_id,
-        );
-        let curve = ctx
-            .accounts
-            .validate_amm_fees_and_curve(&fees_input, &curve_input)?;
-        let _ = &ctx.accounts.validate_input_accounts(swap_authority)?;
-        let _ = &mut ctx.accounts.mint_create_state_account(
-            bump_seed,
+            fees_input,
             curve_input,
+            min_pool_value,
+            max_price_impact_bps,
+        )
+    }
+
+    /// Initializes the pool with an admin `owner` distinct from the
+    /// `fee_owner` that owns the pool fee account, e.g. a DAO multisig and
+    /// its treasury.
+    pub fn initialize_with_owner(
+        ctx: Context<Initialize>,
+        fees_input: FeeInput,
+        curve_input: CurveInput,
+        min_pool_value: u64,
+        max_price_impact_bps: u16,
+        owner: Pubkey,
+        fee_owner: Pubkey,
+    ) -> Result<()> {
+        if owner == Pubkey::default() || fee_owner == Pubkey::default() {
+            return Err(SwapError::InvalidInput.into());
+        }
+
+        if ctx.accounts.fee_account.owner != fee_owner {
+            return Err(SwapError::IncorrectFeeAccount.into());
+        }
+
+        ctx.accounts.process_initialize(
+            ctx.program_id,
             fees_input,
-            &curve,
+            curve_input,
+            min_pool_value,
+            max_price_impact_bps,
         )?;
 
+        let amm = &mut ctx.accounts.amm;
+        amm.owner = owner;
+        amm.fee_owner = fee_owner;
+
         Ok(())
     }
 
-    pub fn swap(ctx: Context<Swap>, amount_in: u64, minimum_amount_out: u64) -> Result<()> {
+    /// Initializes the pool with its owner trade fee split between the
+    /// `fee_split` recipients, which swaps pass as remaining accounts in the
+    /// same order, instead of minted to the pool fee account.
+    pub fn initialize_with_fee_split(
+        ctx: Context<Initialize>,
+        fees_input: FeeInput,
+        curve_input: CurveInput,
+        min_pool_value: u64,
+        max_price_impact_bps: u16,
+        fee_split: Vec<FeeRecipient>,
+    ) -> Result<()> {
+        validate_fee_split(&fee_split)?;
+
+        ctx.accounts.process_initialize(
+            ctx.program_id,
+            fees_input,
+            curve_input,
+            min_pool_value,
+            max_price_impact_bps,
+        )?;
+
         let amm = &mut ctx.accounts.amm;
-        if amm.to_account_info().owner != ctx.program_id {
-            return Err(SwapError::InvalidOwner.into());
-        }
+        amm.fee_split[..fee_split.len()].copy_from_slice(&fee_split);
 
-        if *ctx.accounts.authority.key
-            != authority_key(ctx.program_id, amm.to_account_info().key(), amm.bump_seed)?
-        {
-            return Err(SwapError::InvalidProgramAddress.into());
-        }
+        Ok(())
+    }
 
-        if !(*ctx.accounts.swap_source.to_account_info().key == amm.token_a_account
-            || *ctx.accounts.swap_source.to_account_info().key == amm.token_b_account)
-        {
-            return Err(SwapError::IncorrectSwapAccount.into());
-        }
+    /// Initializes the pool after moving `token_a_amount` and `token_b_amount`
+    /// from the initializer's token accounts into the empty reserves.
+    pub fn initialize_with_funding(
+        ctx: Context<Initialize>,
+        fees_input: FeeInput,
+        curve_input: CurveInput,
+        min_pool_value: u64,
+        max_price_impact_bps: u16,
+        token_a_amount: u64,
+        token_b_amount: u64,
+    ) -> Result<()> {
+        ctx.accounts.fund_reserves(token_a_amount, token_b_amount)?;
 
-        if !(*ctx.accounts.swap_destination.to_account_info().key == amm.token_a_account
-            || *ctx.accounts.swap_destination.to_account_info().key == amm.token_b_account)
-        {
-            return Err(SwapError::IncorrectSwapAccount.into());
+        initialize(
+            ctx,
+            fees_input,
+            curve_input,
+            min_pool_value,
+            max_price_impact_bps,
+        )
+    }
+
+    /// Creates the global config gating pool initialization, with the
+    /// program's upgrade authority as its admin.
+    pub fn initialize_config(ctx: Context<InitializeConfig>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        config.admin = *ctx.accounts.admin.key;
+        config.bump_seed = ctx.bumps.config;
+        config.allowlist = Vec::new();
+
+        Ok(())
+    }
+
+    /// Lets `initializer` create pools when the global config is passed to
+    /// `initialize`.
+    pub fn add_to_allowlist(ctx: Context<UpdateConfig>, initializer: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+
+        if *ctx.accounts.admin.key != config.admin {
+            return Err(SwapError::Unauthorized.into());
         }
 
-        if *ctx.accounts.swap_source.to_account_info().key
-            == *ctx.accounts.swap_destination.to_account_info().key
-        {
+        if config.allowlist.contains(&initializer) {
             return Err(SwapError::InvalidInput.into());
         }
+        if config.allowlist.len() >= MAX_ALLOWLIST_LEN {
+            return Err(SwapError::AllowlistFull.into());
+        }
 
-        if ctx.accounts.swap_source.to_account_info().key != ctx.accounts.source_info.key {
-            return Err(SwapError::InvalidInput.into());
+        config.allowlist.push(initializer);
+
+        Ok(())
+    }
+
+    pub fn remove_from_allowlist(ctx: Context<UpdateConfig>, initializer: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+
+        if *ctx.accounts.admin.key != config.admin {
+            return Err(SwapError::Unauthorized.into());
         }
 
-        if ctx.accounts.swap_destination.to_account_info().key != ctx.accounts.destination_info.key
-        {
-            return Err(SwapError::InvalidInput.into());
+        let index = config
+            .allowlist
+            .iter()
+            .position(|key| *key == initializer)
+            .ok_or(SwapError::InvalidInput)?;
+        config.allowlist.swap_remove(index);
+
+        Ok(())
+    }
+
+    pub fn swap<'info>(
+        ctx: Context<'_, '_, '_, 'info, Swap<'info>>,
+        amount_in: u64,
+        minimum_amount_out: u64,
+    ) -> Result<()> {
+        validate_remaining_accounts(ctx.remaining_accounts)?;
+
+        ctx.accounts.process_swap(
+            ctx.program_id,
+            ctx.remaining_accounts,
+            amount_in,
+            minimum_amount_out,
+            false,
+            false,
+        )?;
+
+        Ok(())
+    }
+
+    /// Swap without trading, owner or host fees, reserved to the pool owner
+    /// so it can rebalance the pool or arbitrage it back to the market price.
+    pub fn swap_no_fee<'info>(
+        ctx: Context<'_, '_, '_, 'info, Swap<'info>>,
+        amount_in: u64,
+        minimum_amount_out: u64,
+    ) -> Result<()> {
+        if *ctx.accounts.user_transfer_authority.key != ctx.accounts.amm.owner {
+            return Err(SwapError::Unauthorized.into());
         }
 
-        if *ctx.accounts.pool_mint.to_account_info().key != amm.pool_mint {
-            return Err(SwapError::IncorrectPoolMint.into());
+        validate_remaining_accounts(ctx.remaining_accounts)?;
+
+        ctx.accounts.process_swap(
+            ctx.program_id,
+            ctx.remaining_accounts,
+            amount_in,
+            minimum_amount_out,
+            true,
+            false,
+        )?;
+
+        Ok(())
+    }
+
+    /// Runs every check and computation of `swap` without moving tokens or
+    /// updating the pool, and returns the amount the swap would send.
+    pub fn simulate_swap<'info>(
+        ctx: Context<'_, '_, '_, 'info, Swap<'info>>,
+        amount_in: u64,
+        minimum_amount_out: u64,
+    ) -> Result<u64> {
+        validate_remaining_accounts(ctx.remaining_accounts)?;
+
+        ctx.accounts.process_swap(
+            ctx.program_id,
+            ctx.remaining_accounts,
+            amount_in,
+            minimum_amount_out,
+            false,
+            true,
+        )
+    }
+
+    /// Swaps only if the pool has not been modified after
+    /// `expected_last_update_slot`, the slot of the state a quote was made on.
+    pub fn swap_with_expected_slot<'info>(
+        ctx: Context<'_, '_, '_, 'info, Swap<'info>>,
+        amount_in: u64,
+        minimum_amount_out: u64,
+        expected_last_update_slot: u64,
+    ) -> Result<()> {
+        if ctx.accounts.amm.last_update_slot > expected_last_update_slot {
+            return Err(SwapError::StaleQuote.into());
         }
 
-        if *ctx.accounts.host_fee_account.to_account_info().key != amm.pool_fee_account {
-            return Err(SwapError::IncorrectFeeAccount.into());
+        swap(ctx, amount_in, minimum_amount_out)
+    }
+
+    /// Swaps only if executed no later than the unix timestamp `deadline`,
+    /// 0 for no deadline.
+    pub fn swap_with_deadline<'info>(
+        ctx: Context<'_, '_, '_, 'info, Swap<'info>>,
+        amount_in: u64,
+        minimum_amount_out: u64,
+        deadline: i64,
+    ) -> Result<()> {
+        if deadline != 0 && Clock::get()?.unix_timestamp > deadline {
+            return Err(SwapError::DeadlineExceeded.into());
         }
 
-        if *ctx.accounts.token_program.key != amm.token_program_id {
-            return Err(SwapError::IncorrectTokenProgramId.into());
+        swap(ctx, amount_in, minimum_amount_out)
+    }
+
+    /// Swaps as much of `amount_in` as the pool fills at no worse than the
+    /// limit price `minimum_amount_out / amount_in`, instead of failing when
+    /// the whole amount doesn't. Returns the input left unfilled.
+    pub fn swap_with_partial_fill<'info>(
+        ctx: Context<'_, '_, '_, 'info, Swap<'info>>,
+        amount_in: u64,
+        minimum_amount_out: u64,
+    ) -> Result<u64> {
+        validate_remaining_accounts(ctx.remaining_accounts)?;
+
+        if amount_in == 0 {
+            return Err(SwapError::InvalidInput.into());
         }
 
+        let amm = &ctx.accounts.amm;
         let trade_direction =
             if *ctx.accounts.swap_source.to_account_info().key == amm.token_a_account {
                 TradeDirection::AtoB
             } else {
                 TradeDirection::BtoA
             };
-
-        let curve = build_curve(&amm.curve).unwrap();
-        let fees = build_fees(&amm.fees).unwrap();
-
-        let result = curve
-            .calculator
-            .swap_without_token_fees(
-                u128::from(amount_in),
-                u128::from(ctx.accounts.swap_source.amount),
-                u128::from(ctx.accounts.swap_destination.amount),
-                trade_direction,
-            )
-            .ok_or(SwapError::ZeroTradingTokens)?;
-
-        let mut trade_fee = fees
-            .trading_fee(result.destination_amount_swapped)
-            .ok_or(SwapError::FeeCalculationFailure)?;
-
-        let mut owner_fee = fees
-            .owner_trading_fee(result.destination_amount_swapped)
-            .ok_or(SwapError::FeeCalculationFailure)?;
-
-        let host_fee = if owner_fee > 0 {
-            fees.host_fee(owner_fee)
-                .ok_or(SwapError::FeeCalculationFailure)?
-        } else {
-            0
-        };
-
-        if host_fee > 0 {
-            owner_fee = owner_fee
-                .checked_sub(host_fee)
-                .ok_or(SwapError::FeeCalculationFailure)?;
+        let fill_amount = compute_partial_fill(
+            amount_in,
+            minimum_amount_out,
+            ctx.accounts.swap_source.amount,
+            ctx.accounts.swap_destination.amount,
+            &amm.swap_fees(Clock::get()?.unix_timestamp)?,
+            &amm.swap_curve()?,
+            trade_direction,
+        );
+        if fill_amount == 0 {
+            return Err(SwapError::ExceededSlippage.into());
         }
 
-        let total_fees = trade_fee
-            .checked_add(owner_fee)
-            .ok_or(SwapError::FeeCalculationFailure)?;
+        // The minimum shrinks with the input, rounded up to hold the limit price
+        let minimum_fill_amount_out = u128::from(minimum_amount_out)
+            .checked_mul(u128::from(fill_amount))
+            .map(|amount| amount.div_ceil(u128::from(amount_in)))
+            .ok_or(SwapError::ConversionFailure)?;
 
-        let destination_amount_swapped = result
-            .destination_amount_swapped
-            .checked_sub(total_fees)
-            .ok_or(SwapError::FeeCalculationFailure)?;
+        ctx.accounts.process_swap(
+            ctx.program_id,
+            ctx.remaining_accounts,
+            fill_amount,
+            to_u64(minimum_fill_amount_out)?,
+            false,
+            false,
+        )?;
 
-        let output_amount = u64::try_from(destination_amount_swapped)
-            .map_err(|_| SwapError::ConversionFailure)?;
+        Ok(amount_in - fill_amount)
+    }
 
-        if output_amount < minimum_amount_out {
-            return Err(SwapError::ExceededSlippage.into());
+    /// Swaps with a minimum output `slippage_bps` below the output quoted
+    /// with the pool's fees and curve when the swap executes, so clients don't
+    /// need a separate quote that can be stale by the time the transaction
+    /// lands.
+    pub fn swap_with_slippage_bps<'info>(
+        ctx: Context<'_, '_, '_, 'info, Swap<'info>>,
+        amount_in: u64,
+        slippage_bps: u16,
+    ) -> Result<()> {
+        if slippage_bps > BASIS_POINTS {
+            return Err(SwapError::InvalidInput.into());
         }
 
-        let seeds = &[
-            &amm.to_account_info().key().to_bytes(),
-            &[amm.bump_seed][..],
-        ];
-
-        token::transfer(
-            CpiContext::new(
-                ctx.accounts.token_program.clone(),
-                token::Transfer {
-                    from: ctx.accounts.source_info.clone(),
-                    to: ctx.accounts.swap_source.to_account_info().clone(),
-                    authority: ctx.accounts.user_transfer_authority.clone(),
-                },
-            ),
+        let amm = &ctx.accounts.amm;
+        let trade_direction =
+            if *ctx.accounts.swap_source.to_account_info().key == amm.token_a_account {
+                TradeDirection::AtoB
+            } else {
+                TradeDirection::BtoA
+            };
+        let amounts = compute_swap(
             amount_in,
+            ctx.accounts.swap_source.amount,
+            ctx.accounts.swap_destination.amount,
+            &amm.swap_fees(Clock::get()?.unix_timestamp)?,
+            &amm.swap_curve()?,
+            trade_direction,
         )?;
+        let minimum_amount_out = slippage_minimum(amounts.output_amount, slippage_bps);
 
-        token::transfer(
-            CpiContext::new_with_signer(
-                ctx.accounts.token_program.clone(),
-                token::Transfer {
-                    from: ctx.accounts.swap_destination.to_account_info().clone(),
-                    to: ctx.accounts.destination_info.clone(),
-                    authority: ctx.accounts.authority.clone(),
-                },
-                &[&seeds[..]],
-            ),
-            output_amount,
-        )?;
+        swap(ctx, amount_in, minimum_amount_out)
+    }
 
-        if owner_fee > 0 {
-            let pool_mint_amount = curve
-                .calculator
-                .deposit_single_token_type(
-                    owner_fee,
-                    u128::from(ctx.accounts.swap_source.amount),
-                    u128::from(ctx.accounts.swap_destination.amount),
-                    u128::from(ctx.accounts.pool_mint.supply),
-                    trade_direction,
-                    RoundDirection::Floor,
-                )
-                .ok_or(SwapError::ZeroOwnerTradeFee)?;
+    /// Executes each leg as a swap in the same direction, so later legs see
+    /// the price moved by earlier ones. Returns the total amount received.
+    pub fn swap_batch<'info>(
+        ctx: Context<'_, '_, '_, 'info, Swap<'info>>,
+        legs: Vec<SwapLeg>,
+    ) -> Result<u64> {
+        validate_remaining_accounts(ctx.remaining_accounts)?;
 
-            token::mint_to(
-                CpiContext::new_with_signer(
-                    ctx.accounts.token_program.clone(),
-                    MintTo {
-                        mint: ctx.accounts.pool_mint.to_account_info().clone(),
-                        to: ctx.accounts.pool_account.to_account_info().clone(),
-                        authority: ctx.accounts.authority.clone(),
-                    },
-                    &[&seeds[..]],
-                ),
-                u64::try_from(pool_mint_amount).map_err(|_| SwapError::ConversionFailure)?,
-            )?;
+        if legs.is_empty() || legs.len() > MAX_BATCH_LEGS {
+            return Err(SwapError::InvalidInput.into());
         }
 
-        if host_fee > 0 {
-            let host_fee_mint_amount = curve
-                .calculator
-                .deposit_single_token_type(
-                    host_fee,
-                    u128::from(ctx.accounts.swap_source.amount),
-                    u128::from(ctx.accounts.swap_destination.amount),
-                    u128::from(ctx.accounts.pool_mint.supply),
-                    trade_direction,
-                    RoundDirection::Floor,
-                )
-                .ok_or(SwapError::ZeroHostFee)?;
+        // The temporary wrapped SOL account can't be recreated once closed
+        if ctx.accounts.wsol_account.is_some() {
+            return Err(SwapError::InvalidInput.into());
+        }
 
-            token::mint_to(
-                CpiContext::new_with_signer(
-                    ctx.accounts.token_program.clone(),
-                    MintTo {
-                        mint: ctx.accounts.pool_mint.to_account_info().clone(),
-                        to: ctx.accounts.host_fee_account.clone(),
-                        authority: ctx.accounts.authority.clone(),
-                    },
-                    &[&seeds[..]],
-                ),
-                u64::try_from(host_fee_mint_amount).map_err(|_| SwapError::ConversionFailure)?,
+        let mut total_output_amount: u64 = 0;
+        for leg in legs {
+            let output_amount = ctx.accounts.process_swap(
+                ctx.program_id,
+                ctx.remaining_accounts,
+                leg.amount_in,
+                leg.minimum_amount_out,
+                false,
+                false,
             )?;
+            total_output_amount = total_output_amount
+                .checked_add(output_amount)
+                .ok_or(SwapError::ConversionFailure)?;
         }
 
-        Ok(())
+        Ok(total_output_amount)
     }
 
     pub fn deposit_liquidity(
@@ -241,13 +411,8 @@ pub mod dexy {
         maximum_token_a_amount: u64,
         maximum_token_b_amount: u64,
     ) -> Result<()> {
-        let amm = &mut ctx.accounts.amm;
-
-        if !amm.is_initialized {
-            return Err(SwapError::NotInitialized.into());
-        }
-
-        let curve = build_curve(&amm.curve)?;
+        let curve = ctx.accounts.validate_deposit(ctx.program_id)?;
+        let amm = &ctx.accounts.amm;
 
         let current_pool_mint_supply = u128::from(ctx.accounts.pool_mint.supply);
         let (token_a_amount, token_b_amount) = if current_pool_mint_supply > 0 {
@@ -262,10 +427,8 @@ pub mod dexy {
                 )
                 .ok_or(SwapError::ZeroTradingTokens)?;
 
-            let token_a_amount = u64::try_from(tokens.token_a_amount)
-                .map_err(|_| SwapError::ConversionFailure)?;
-            let token_b_amount = u64::try_from(tokens.token_b_amount)
-                .map_err(|_| SwapError::ConversionFailure)?;
+            let token_a_amount = to_u64(tokens.token_a_amount)?;
+            let token_b_amount = to_u64(tokens.token_b_amount)?;
 
             if token_a_amount > maximum_token_a_amount {
                 return Err(SwapError::ExceededSlippage.into());
@@ -279,16 +442,54 @@ pub mod dexy {
             (maximum_token_a_amount, maximum_token_b_amount)
         };
 
+        amm.validate_reserve_cap(
+            ctx.accounts.token_a.amount.saturating_add(token_a_amount),
+            ctx.accounts.token_b.amount.saturating_add(token_b_amount),
+        )?;
+
         let seeds = &[
             &amm.to_account_info().key().to_bytes(),
             &[amm.bump_seed][..],
         ];
 
+        // With a wrapped SOL account, the native side is funded from lamports
+        // instead of the user's token account
+        let mut source_a = ctx.accounts.user_token_a.to_account_info();
+        let mut source_b = ctx.accounts.user_token_b.to_account_info();
+        if let Some(wsol_account) = &ctx.accounts.wsol_account {
+            let amount = if ctx.accounts.token_a.mint == native_mint::ID {
+                source_a = wsol_account.clone();
+                token_a_amount
+            } else if ctx.accounts.token_b.mint == native_mint::ID {
+                source_b = wsol_account.clone();
+                token_b_amount
+            } else {
+                return Err(SwapError::InvalidInput.into());
+            };
+            wrap_sol(
+                ctx.program_id,
+                &ctx.accounts.user_transfer_authority,
+                wsol_account,
+                &ctx.accounts
+                    .native_mint
+                    .as_ref()
+                    .ok_or(SwapError::InvalidInput)?
+                    .to_account_info(),
+                &ctx.accounts
+                    .system_program
+                    .as_ref()
+                    .ok_or(SwapError::InvalidInput)?
+                    .to_account_info(),
+                &ctx.accounts.token_program,
+                amount,
+            )?;
+        }
+
         token::transfer(
             CpiContext::new(
                 ctx.accounts.token_program.clone(),
                 Transfer {
-                    from: ctx.accounts.user_token_a.to_account_info().clone(),
+                    from: source_a,
                     to: ctx.accounts.token_a.to_account_info().clone(),
                     authority: ctx.accounts.user_transfer_authority.clone(),
                 },
@@ -300,7 +501,7 @@ pub mod dexy {
             CpiContext::new(
                 ctx.accounts.token_program.clone(),
                 Transfer {
-                    from: ctx.accounts.user_token_b.to_account_info().clone(),
+                    from: source_b,
                     to: ctx.accounts.token_b.to_account_info().clone(),
                     authority: ctx.accounts.user_transfer_authority.clone(),
                 },
@@ -320,327 +521,3514 @@ pub mod dexy {
             ),
             pool_token_amount,
         )?;
+        ctx.accounts.record_position_deposit(pool_token_amount)?;
+
+        let pool_token_supply_before = ctx.accounts.pool_mint.supply;
+        ctx.accounts.pool_mint.reload()?;
+
+        let amm = &mut ctx.accounts.amm;
+        let (token_a_account, token_b_account) = (amm.token_a_account, amm.token_b_account);
+        amm.record_reserve_change(&token_a_account, token_a_amount, 0)?;
+        amm.record_reserve_change(&token_b_account, token_b_amount, 0)?;
+
+        emit!(DepositEvent {
+            amm: amm.key(),
+            user: *ctx.accounts.user_transfer_authority.key,
+            pool_token_amount,
+            token_a_amount,
+            token_b_amount,
+            pool_token_supply_before,
+            pool_token_supply_after: ctx.accounts.pool_mint.supply,
+        });
+
+        if let Some(wsol_account) = &ctx.accounts.wsol_account {
+            unwrap_sol(
+                &ctx.accounts.user_transfer_authority,
+                wsol_account,
+                &ctx.accounts.token_program,
+            )?;
+        }
 
         Ok(())
     }
 
-    pub fn withdraw_liquidity(
-        ctx: Context<WithdrawLiquidity>,
-        pool_token_amount: u64,
-        minimum_token_a_amount: u64,
-        minimum_token_b_amount: u64,
+    pub fn deposit_liquidity_by_tokens(
+        ctx: Context<DepositLiquidity>,
+        token_a_amount: u64,
+        token_b_amount: u64,
     ) -> Result<()> {
-        let amm = &mut ctx.accounts.amm;
+        let pool_mint_supply = u128::from(ctx.accounts.pool_mint.supply);
+        let pool_token_amount = if pool_mint_supply > 0 {
+            let pool_tokens_for_a = u128::from(token_a_amount)
+                .checked_mul(pool_mint_supply)
+                .and_then(|amount| amount.checked_div(u128::from(ctx.accounts.token_a.amount)))
+                .ok_or(SwapError::ZeroTradingTokens)?;
+            let pool_tokens_for_b = u128::from(token_b_amount)
+                .checked_mul(pool_mint_supply)
+                .and_then(|amount| amount.checked_div(u128::from(ctx.accounts.token_b.amount)))
+                .ok_or(SwapError::ZeroTradingTokens)?;
+            std::cmp::min(pool_tokens_for_a, pool_tokens_for_b)
+        } else {
+            ctx.accounts
+                .amm
+                .swap_curve()?
+                .calculator
+                .new_supply_pool(u128::from(token_a_amount), u128::from(token_b_amount))
+        };
 
-        if !amm.is_initialized {
-            return Err(SwapError::NotInitialized.into());
+        let pool_token_amount = to_u64(pool_token_amount)?;
+        if pool_token_amount == 0 {
+            return Err(SwapError::ZeroTradingTokens.into());
         }
 
-        let curve = build_curve(&amm.curve)?;
-        let fees = build_fees(&amm.fees)?;
+        deposit_liquidity(ctx, pool_token_amount, token_a_amount, token_b_amount)
+    }
 
-        let withdraw_fee = fees
-            .owner_withdraw_fee(u128::from(pool_token_amount))
-            .ok_or(SwapError::FeeCalculationFailure)?;
+    /// Deposits `pool_token_amount` paying at most `slippage_bps` above the
+    /// tokens the current reserves require, see `swap_with_slippage_bps`.
+    pub fn deposit_liquidity_with_slippage_bps(
+        ctx: Context<DepositLiquidity>,
+        pool_token_amount: u64,
+        slippage_bps: u16,
+    ) -> Result<()> {
+        if slippage_bps > BASIS_POINTS {
+            return Err(SwapError::InvalidInput.into());
+        }
 
-        let pool_token_amount_after_fee = u128::from(pool_token_amount)
-            .checked_sub(withdraw_fee)
-            .ok_or(SwapError::FeeCalculationFailure)?;
+        // An empty pool has no ratio to deposit at
+        if ctx.accounts.pool_mint.supply == 0 {
+            return Err(SwapError::EmptySupply.into());
+        }
 
-        let tokens = curve
+        let tokens = ctx
+            .accounts
+            .amm
+            .swap_curve()?
             .calculator
             .pool_tokens_to_trading_tokens(
-                pool_token_amount_after_fee,
+                u128::from(pool_token_amount),
                 u128::from(ctx.accounts.pool_mint.supply),
                 u128::from(ctx.accounts.token_a.amount),
                 u128::from(ctx.accounts.token_b.amount),
-                RoundDirection::Floor,
+                RoundDirection::Ceil,
             )
             .ok_or(SwapError::ZeroTradingTokens)?;
+        let maximum_token_a_amount = slippage_maximum(to_u64(tokens.token_a_amount)?, slippage_bps);
+        let maximum_token_b_amount = slippage_maximum(to_u64(tokens.token_b_amount)?, slippage_bps);
 
-        let token_a_amount = u64::try_from(tokens.token_a_amount)
-            .map_err(|_| SwapError::ConversionFailure)?;
-        let token_b_amount = u64::try_from(tokens.token_b_amount)
-            .map_err(|_| SwapError::ConversionFailure)?;
+        deposit_liquidity(
+            ctx,
+            pool_token_amount,
+            maximum_token_a_amount,
+            maximum_token_b_amount,
+        )
+    }
 
-        if token_a_amount < minimum_token_a_amount {
-            return Err(SwapError::ExceededSlippage.into());
+    /// Deposits as much of `token_a_amount` and `token_b_amount` as the pool
+    /// ratio allows as a balanced deposit, and the excess of the other token
+    /// as a single-token deposit, so nothing is left stranded.
+    pub fn smart_deposit(
+        ctx: Context<DepositLiquidity>,
+        token_a_amount: u64,
+        token_b_amount: u64,
+        minimum_pool_token_amount: u64,
+    ) -> Result<()> {
+        if ctx.accounts.wsol_account.is_some() {
+            return Err(SwapError::InvalidInput.into());
         }
-        if token_b_amount < minimum_token_b_amount {
+
+        let curve = ctx.accounts.validate_deposit(ctx.program_id)?;
+        let amm = &ctx.accounts.amm;
+        let fees = amm.curve_fees()?;
+
+        let pool_mint_supply = u128::from(ctx.accounts.pool_mint.supply);
+        let swap_token_a_amount = u128::from(ctx.accounts.token_a.amount);
+        let swap_token_b_amount = u128::from(ctx.accounts.token_b.amount);
+        if pool_mint_supply == 0 {
+            return Err(SwapError::EmptySupply.into());
+        }
+        // Curves that value one token through the other, like the constant
+        // price, may hold none of it
+        curve
+            .calculator
+            .validate_supply(ctx.accounts.token_a.amount, ctx.accounts.token_b.amount)?;
+
+        // Balanced portion, limited by the scarcer side. An empty reserve has
+        // no ratio to match, so everything goes in as a single-token deposit,
+        // which seeds that reserve
+        let balanced_pool_tokens = if swap_token_a_amount == 0 || swap_token_b_amount == 0 {
+            0
+        } else {
+            let pool_tokens_for_a = u128::from(token_a_amount)
+                .checked_mul(pool_mint_supply)
+                .and_then(|amount| amount.checked_div(swap_token_a_amount))
+                .ok_or(SwapError::ConversionFailure)?;
+            let pool_tokens_for_b = u128::from(token_b_amount)
+                .checked_mul(pool_mint_supply)
+                .and_then(|amount| amount.checked_div(swap_token_b_amount))
+                .ok_or(SwapError::ConversionFailure)?;
+            std::cmp::min(pool_tokens_for_a, pool_tokens_for_b)
+        };
+
+        let (balanced_a, balanced_b) = if balanced_pool_tokens > 0 {
+            let tokens = curve
+                .calculator
+                .pool_tokens_to_trading_tokens(
+                    balanced_pool_tokens,
+                    pool_mint_supply,
+                    swap_token_a_amount,
+                    swap_token_b_amount,
+                    RoundDirection::Ceil,
+                )
+                .ok_or(SwapError::ZeroTradingTokens)?;
+            (tokens.token_a_amount, tokens.token_b_amount)
+        } else {
+            (0, 0)
+        };
+
+        let excess_a = u128::from(token_a_amount)
+            .checked_sub(balanced_a)
+            .ok_or(SwapError::ExceededSlippage)?;
+        let excess_b = u128::from(token_b_amount)
+            .checked_sub(balanced_b)
+            .ok_or(SwapError::ExceededSlippage)?;
+
+        // Single-token portion on the side with the larger leftover value,
+        // priced against the reserves after the balanced portion
+        let swap_token_a_amount = swap_token_a_amount
+            .checked_add(balanced_a)
+            .ok_or(SwapError::ConversionFailure)?;
+        let swap_token_b_amount = swap_token_b_amount
+            .checked_add(balanced_b)
+            .ok_or(SwapError::ConversionFailure)?;
+        let pool_mint_supply = pool_mint_supply
+            .checked_add(balanced_pool_tokens)
+            .ok_or(SwapError::ConversionFailure)?;
+        let excess_a_value = excess_a
+            .checked_mul(swap_token_b_amount)
+            .ok_or(SwapError::ConversionFailure)?;
+        let excess_b_value = excess_b
+            .checked_mul(swap_token_a_amount)
+            .ok_or(SwapError::ConversionFailure)?;
+        // Against an empty reserve both values are zero, so the side that
+        // has an excess is taken
+        let (excess, trade_direction) = if excess_a_value > excess_b_value
+            || (excess_a_value == excess_b_value && excess_a > 0)
+        {
+            (excess_a, TradeDirection::AtoB)
+        } else {
+            (excess_b, TradeDirection::BtoA)
+        };
+        let single_pool_tokens = if excess > 0 {
+            curve
+                .deposit_single_token_type(
+                    excess,
+                    swap_token_a_amount,
+                    swap_token_b_amount,
+                    pool_mint_supply,
+                    trade_direction,
+                    RoundDirection::Floor,
+                    &fees,
+                )
+                .ok_or(SwapError::ZeroTradingTokens)?
+        } else {
+            0
+        };
+        let (deposit_a, deposit_b) = match trade_direction {
+            TradeDirection::AtoB if single_pool_tokens > 0 => (balanced_a + excess_a, balanced_b),
+            TradeDirection::BtoA if single_pool_tokens > 0 => (balanced_a, balanced_b + excess_b),
+            _ => (balanced_a, balanced_b),
+        };
+
+        let pool_token_amount = balanced_pool_tokens
+            .checked_add(single_pool_tokens)
+            .ok_or(SwapError::ConversionFailure)?;
+        if pool_token_amount == 0 {
+            return Err(SwapError::ZeroTradingTokens.into());
+        }
+        let pool_token_amount = to_u64(pool_token_amount)?;
+        if pool_token_amount < minimum_pool_token_amount {
             return Err(SwapError::ExceededSlippage.into());
         }
 
+        let deposit_a = to_u64(deposit_a)?;
+        let deposit_b = to_u64(deposit_b)?;
+        amm.validate_reserve_cap(
+            ctx.accounts.token_a.amount.saturating_add(deposit_a),
+            ctx.accounts.token_b.amount.saturating_add(deposit_b),
+        )?;
+
         let seeds = &[
             &amm.to_account_info().key().to_bytes(),
             &[amm.bump_seed][..],
         ];
 
-        token::burn(
+        token::transfer(
             CpiContext::new(
                 ctx.accounts.token_program.clone(),
-                token::Burn {
-                    mint: ctx.accounts.pool_mint.to_account_info().clone(),
-                    from: ctx.accounts.source_pool_account.to_account_info().clone(),
+                Transfer {
+                    from: ctx.accounts.user_token_a.to_account_info().clone(),
+                    to: ctx.accounts.token_a.to_account_info().clone(),
                     authority: ctx.accounts.user_transfer_authority.clone(),
                 },
             ),
-            pool_token_amount,
+            deposit_a,
         )?;
 
-        if withdraw_fee > 0 {
-            token::mint_to(
-                CpiContext::new_with_signer(
-                    ctx.accounts.token_program.clone(),
-                    MintTo {
-                        mint: ctx.accounts.pool_mint.to_account_info().clone(),
-                        to: ctx.accounts.fee_account.to_account_info().clone(),
-                        authority: ctx.accounts.authority.clone(),
-                    },
-                    &[&seeds[..]],
-                ),
-                u64::try_from(withdraw_fee).map_err(|_| SwapError::ConversionFailure)?,
-            )?;
-        }
-
         token::transfer(
-            CpiContext::new_with_signer(
+            CpiContext::new(
                 ctx.accounts.token_program.clone(),
                 Transfer {
-                    from: ctx.accounts.token_a.to_account_info().clone(),
-                    to: ctx.accounts.user_token_a.to_account_info().clone(),
-                    authority: ctx.accounts.authority.clone(),
+                    from: ctx.accounts.user_token_b.to_account_info().clone(),
+                    to: ctx.accounts.token_b.to_account_info().clone(),
+                    authority: ctx.accounts.user_transfer_authority.clone(),
                 },
-                &[&seeds[..]],
             ),
-            token_a_amount,
+            deposit_b,
         )?;
 
-        token::transfer(
+        token::mint_to(
             CpiContext::new_with_signer(
                 ctx.accounts.token_program.clone(),
-                Transfer {
-                    from: ctx.accounts.token_b.to_account_info().clone(),
-                    to: ctx.accounts.user_token_b.to_account_info().clone(),
+                MintTo {
+                    mint: ctx.accounts.pool_mint.to_account_info().clone(),
+                    to: ctx.accounts.user_pool_token.to_account_info().clone(),
                     authority: ctx.accounts.authority.clone(),
                 },
                 &[&seeds[..]],
             ),
-            token_b_amount,
+            pool_token_amount,
         )?;
+        ctx.accounts.record_position_deposit(pool_token_amount)?;
+
+        let pool_token_supply_before = ctx.accounts.pool_mint.supply;
+        ctx.accounts.pool_mint.reload()?;
+
+        let amm = &mut ctx.accounts.amm;
+        let (token_a_account, token_b_account) = (amm.token_a_account, amm.token_b_account);
+        amm.record_reserve_change(&token_a_account, deposit_a, 0)?;
+        amm.record_reserve_change(&token_b_account, deposit_b, 0)?;
+
+        emit!(DepositEvent {
+            amm: amm.key(),
+            user: *ctx.accounts.user_transfer_authority.key,
+            pool_token_amount,
+            token_a_amount: deposit_a,
+            token_b_amount: deposit_b,
+            pool_token_supply_before,
+            pool_token_supply_after: ctx.accounts.pool_mint.supply,
+        });
 
         Ok(())
     }
-}
 
-#[derive(Accounts)]
-pub struct Initialize<'info> {
-    /// CHECK: This is the authority for the swap. The validation is handled in the instruction logic.
-    #[account(mut)]
-    pub authority: AccountInfo<'info>,
-    /// CHECK: This is the initializer of the swap. The validation is handled in the instruction logic.
-    #[account(mut, signer)]
-    pub initializer: AccountInfo<'info>,
-    #[account(init, payer=initializer, space=999)]
-    pub amm: Box<Account<'info, Amm>>,
-    #[account(mut)]
-    pub pool_mint: Box<Account<'info, Mint>>,
-    #[account(mut)]
-    pub token_a: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub token_b: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub fee_account: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub destination: Account<'info, TokenAccount>,
-    /// CHECK: This is the Solana token program, which is a known, trusted program
-    pub token_program: AccountInfo<'info>,
-    pub system_program: Program<'info, System>,
-}
+    pub fn withdraw_liquidity(
+        ctx: Context<WithdrawLiquidity>,
+        pool_token_amount: u64,
+        minimum_token_a_amount: u64,
+        minimum_token_b_amount: u64,
+    ) -> Result<()> {
+        ctx.accounts.process_withdraw(
+            ctx.program_id,
+            pool_token_amount,
+            minimum_token_a_amount,
+            minimum_token_b_amount,
+            false,
+        )
+    }
 
-#[derive(Accounts)]
-pub struct Swap<'info> {
-    /// CHECK: This is the authority for the swap. The validation is handled in the instruction logic.
-    pub authority: AccountInfo<'info>,
-    pub amm: Box<Account<'info, Amm>>,
-    /// CHECK: This is the user transfer authority. The validation is handled in the instruction logic.
-    #[account(signer)]
-    pub user_transfer_authority: AccountInfo<'info>,
-    /// CHECK: This is the source token account. The validation is handled in the instruction logic.
-    #[account(mut)]
-    pub source_info: AccountInfo<'info>,
-    /// CHECK: This is the destination token account. The validation is handled in the instruction logic.
-    #[account(mut)]
-    pub destination_info: AccountInfo<'info>,
-    #[account(mut)]
-    pub swap_source: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub swap_destination: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub pool_mint: Account<'info, Mint>,
-    #[account(mut)]
-    pub pool_account: Account<'info, TokenAccount>,
-    /// CHECK: This is the Solana token program, which is a known, trusted program
-    pub token_program: AccountInfo<'info>,
-    /// CHECK: This is the system program, which is a known, trusted program
-    pub host_fee_account: AccountInfo<'info>,
-}
+    /// Withdraws the caller's whole pool token balance, read on-chain so it
+    /// can't change between the client's read and the withdrawal.
+    pub fn withdraw_all_liquidity(
+        ctx: Context<WithdrawLiquidity>,
+        minimum_token_a_amount: u64,
+        minimum_token_b_amount: u64,
+    ) -> Result<()> {
+        let pool_token_amount = ctx.accounts.source_pool_account.amount;
+        if pool_token_amount == 0 {
+            return Err(SwapError::InvalidInput.into());
+        }
 
-#[derive(Accounts)]
-pub struct DepositLiquidity<'info> {
-    /// CHECK: This is the authority for the swap. The validation is handled in the instruction logic.
-    pub authority: AccountInfo<'info>,
-    pub amm: Box<Account<'info, Amm>>,
-    /// CHECK: This is the user transfer authority. The validation is handled in the instruction logic.
-    #[account(signer)]
-    pub user_transfer_authority: AccountInfo<'info>,
-    #[account(mut)]
-    pub user_token_a: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub user_token_b: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub token_a: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub token_b: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub pool_mint: Account<'info, Mint>,
-    #[account(mut)]
-    pub user_pool_token: Account<'info, TokenAccount>,
-    /// CHECK: This is the Solana token program, which is a known, trusted program
-    pub token_program: AccountInfo<'info>,
-}
+        withdraw_liquidity(
+            ctx,
+            pool_token_amount,
+            minimum_token_a_amount,
+            minimum_token_b_amount,
+        )
+    }
 
-#[derive(Accounts)]
-pub struct WithdrawLiquidity<'info> {
-    /// CHECK: This is the authority for the swap. The validation is handled in the instruction logic.
-    pub authority: AccountInfo<'info>,
-    pub amm: Box<Account<'info, Amm>>,
-    /// CHECK: This is the user transfer authority. The validation is handled in the instruction logic.
-    #[account(signer)]
-    pub user_transfer_authority: AccountInfo<'info>,
-    #[account(mut)]
-    pub source_pool_account: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub token_a: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub token_b: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub user_token_a: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub user_token_b: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub pool_mint: Account<'info, Mint>,
-    #[account(mut)]
-    pub fee_account: Account<'info, TokenAccount>,
-    /// CHECK: This is the Solana token program, which is a known, trusted program
-    pub token_program: AccountInfo<'info>,
-}
+    /// Burns the whole pool token supply, held by the caller, for the whole
+    /// reserves rather than their share rounded down, so the pool is emptied
+    /// without stranded dust. Fails while a withdraw fee is due, which would
+    /// be minted as pool tokens over the emptied reserves.
+    pub fn sweep_liquidity(
+        ctx: Context<WithdrawLiquidity>,
+        minimum_token_a_amount: u64,
+        minimum_token_b_amount: u64,
+    ) -> Result<()> {
+        let pool_token_amount = ctx.accounts.pool_mint.supply;
+        ctx.accounts.process_withdraw(
+            ctx.program_id,
+            pool_token_amount,
+            minimum_token_a_amount,
+            minimum_token_b_amount,
+            true,
+        )
+    }
 
-impl<'info> Initialize<'info> {
-    fn validate_input_accounts(&self, swap_authority: Pubkey) -> Result<()> {
-        if self.amm.is_initialized {
-            return Err(SwapError::AlreadyInUse.into());
+    /// Withdraws `pool_token_amount` receiving at most `slippage_bps` less than
+    /// the current reserves pay after the withdraw fee, see
+    /// `swap_with_slippage_bps`.
+    pub fn withdraw_liquidity_with_slippage_bps(
+        ctx: Context<WithdrawLiquidity>,
+        pool_token_amount: u64,
+        slippage_bps: u16,
+    ) -> Result<()> {
+        if slippage_bps > BASIS_POINTS {
+            return Err(SwapError::InvalidInput.into());
         }
 
-        if *self.authority.key != swap_authority {
-            return Err(SwapError::InvalidProgramAddress.into());
+        if ctx.accounts.pool_mint.supply == 0 {
+            return Err(SwapError::EmptySupply.into());
         }
-        if *self.authority.key != self.token_a.owner || *self.authority.key != self.token_b.owner {
-            return Err(SwapError::InvalidOwner.into());
+
+        let (_, token_a_amount, token_b_amount) =
+            ctx.accounts.withdraw_amounts(pool_token_amount, false)?;
+
+        withdraw_liquidity(
+            ctx,
+            pool_token_amount,
+            slippage_minimum(token_a_amount, slippage_bps),
+            slippage_minimum(token_b_amount, slippage_bps),
+        )
+    }
+
+    pub fn get_price_curve(
+        ctx: Context<GetPriceCurve>,
+        amounts_in: Vec<u64>,
+        trade_direction: TradeDirection,
+    ) -> Result<Vec<u128>> {
+        let amm = &ctx.accounts.amm;
+
+        if amounts_in.len() > MAX_PRICE_CURVE_POINTS {
+            return Err(SwapError::InvalidInput.into());
         }
 
-        if *self.authority.key == self.fee_account.owner
-            && *self.authority.key == self.destination.owner
+        if *ctx.accounts.token_a.to_account_info().key != amm.token_a_account
+            || *ctx.accounts.token_b.to_account_info().key != amm.token_b_account
         {
-            return Err(SwapError::InvalidOuputOwner.into());
-        }
-        if COption::Some(*self.authority.key) != self.pool_mint.mint_authority {
-            return Err(SwapError::InvalidOwner.into());
-        }
-        if self.token_a.mint == self.token_b.mint {
-            return Err(SwapError::RepeatedMint.into());
+            return Err(SwapError::IncorrectSwapAccount.into());
         }
 
-        if self.token_a.delegate.is_some() || self.token_b.delegate.is_some() {
-            return Err(SwapError::InvalidDelegate.into());
-        }
+        let curve = amm.swap_curve()?;
+        let fees = amm.swap_fees(Clock::get()?.unix_timestamp)?;
 
-        if self.token_a.close_authority.is_some() || self.token_b.close_authority.is_some() {
-            return Err(SwapError::InvalidCloseAuthority.into());
-        }
+        let (swap_source_amount, swap_destination_amount) = match trade_direction {
+            TradeDirection::AtoB => (ctx.accounts.token_a.amount, ctx.accounts.token_b.amount),
+            TradeDirection::BtoA => (ctx.accounts.token_b.amount, ctx.accounts.token_a.amount),
+        };
 
-        if self.pool_mint.supply != 0 {
-            return Err(SwapError::InvalidSupply.into());
-        }
+        // Effective prices are destination tokens received per source token, as
+        // Q64.64, or 0 for sizes the pool cannot fill
+        let prices = amounts_in
+            .iter()
+            .map(|&amount_in| {
+                if amount_in == 0 {
+                    return 0;
+                }
+                compute_swap(
+                    amount_in,
+                    swap_source_amount,
+                    swap_destination_amount,
+                    &fees,
+                    &curve,
+                    trade_direction,
+                )
+                .map(|amounts| (u128::from(amounts.output_amount) << 64) / u128::from(amount_in))
+                .unwrap_or(0)
+            })
+            .collect();
 
-        if self.pool_mint.freeze_authority.is_some() {
-            return Err(SwapError::InvalidFreezeAuthority.into());
+        Ok(prices)
+    }
+
+    pub fn quote_swap(
+        ctx: Context<QuoteSwap>,
+        amount_in: u64,
+        trade_direction: TradeDirection,
+    ) -> Result<SwapAmounts> {
+        let amm = &ctx.accounts.amm;
+
+        if amount_in == 0 {
+            return Err(SwapError::InvalidInput.into());
         }
 
-        if *self.pool_mint.to_account_info().key != self.fee_account.mint {
-            return Err(SwapError::IncorrectPoolMint.into());
+        if *ctx.accounts.token_a.to_account_info().key != amm.token_a_account
+            || *ctx.accounts.token_b.to_account_info().key != amm.token_b_account
+        {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+
+        let curve = amm.swap_curve()?;
+        let fees = amm.swap_fees(Clock::get()?.unix_timestamp)?;
+
+        let (swap_source_amount, swap_destination_amount) = match trade_direction {
+            TradeDirection::AtoB => (ctx.accounts.token_a.amount, ctx.accounts.token_b.amount),
+            TradeDirection::BtoA => (ctx.accounts.token_b.amount, ctx.accounts.token_a.amount),
+        };
+
+        let amounts = compute_swap(
+            amount_in,
+            swap_source_amount,
+            swap_destination_amount,
+            &fees,
+            &curve,
+            trade_direction,
+        )?;
+
+        Ok(amounts)
+    }
+
+    /// Quotes the pool tokens a deposit of `source_amount` of one token would
+    /// mint, so clients can set the minimum of a single-sided deposit and
+    /// show the fee it pays on the half notionally swapped.
+    pub fn quote_deposit_single_token_type(
+        ctx: Context<QuoteDeposit>,
+        source_amount: u64,
+        trade_direction: TradeDirection,
+    ) -> Result<DepositAmounts> {
+        let amm = &ctx.accounts.amm;
+
+        if source_amount == 0 {
+            return Err(SwapError::InvalidInput.into());
+        }
+
+        if *ctx.accounts.token_a.to_account_info().key != amm.token_a_account
+            || *ctx.accounts.token_b.to_account_info().key != amm.token_b_account
+        {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+
+        if *ctx.accounts.pool_mint.to_account_info().key != amm.pool_mint {
+            return Err(SwapError::IncorrectPoolMint.into());
+        }
+
+        let curve = amm.swap_curve()?;
+        if !amm.deposits_enabled || !curve.calculator.allow_deposits() {
+            return Err(SwapError::DepositsDisabled.into());
+        }
+        if ctx.accounts.pool_mint.supply == 0 {
+            return Err(SwapError::EmptySupply.into());
+        }
+
+        let amounts = compute_deposit_single_token_type(
+            source_amount,
+            ctx.accounts.token_a.amount,
+            ctx.accounts.token_b.amount,
+            ctx.accounts.pool_mint.supply,
+            &amm.curve_fees()?,
+            &curve,
+            trade_direction,
+        )?;
+
+        Ok(amounts)
+    }
+
+    /// Returns the live reserve ratio in lowest terms, so wallets can propose
+    /// matching token amounts for `deposit_liquidity` without reimplementing
+    /// the reduction.
+    pub fn get_reserve_ratio(ctx: Context<QuoteSwap>) -> Result<ReserveRatio> {
+        let amm = &ctx.accounts.amm;
+
+        if *ctx.accounts.token_a.to_account_info().key != amm.token_a_account
+            || *ctx.accounts.token_b.to_account_info().key != amm.token_b_account
+        {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+
+        let ratio =
+            compute_reserve_ratio(ctx.accounts.token_a.amount, ctx.accounts.token_b.amount)?;
+
+        Ok(ratio)
+    }
+
+    /// Returns the smallest balanced and single-token deposits that mint at
+    /// least one pool token, so wallets can warn before a deposit that would
+    /// mint nothing.
+    pub fn get_minimum_deposit(ctx: Context<QuoteDeposit>) -> Result<MinimumDeposit> {
+        let amm = &ctx.accounts.amm;
+
+        if *ctx.accounts.token_a.to_account_info().key != amm.token_a_account
+            || *ctx.accounts.token_b.to_account_info().key != amm.token_b_account
+        {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+
+        if *ctx.accounts.pool_mint.to_account_info().key != amm.pool_mint {
+            return Err(SwapError::IncorrectPoolMint.into());
+        }
+
+        let curve = amm.swap_curve()?;
+        if !amm.deposits_enabled || !curve.calculator.allow_deposits() {
+            return Err(SwapError::DepositsDisabled.into());
+        }
+        if ctx.accounts.pool_mint.supply == 0 {
+            return Err(SwapError::EmptySupply.into());
+        }
+
+        let minimum = compute_minimum_deposit(
+            ctx.accounts.token_a.amount,
+            ctx.accounts.token_b.amount,
+            ctx.accounts.pool_mint.supply,
+            &amm.curve_fees()?,
+            &curve,
+        )?;
+
+        Ok(minimum)
+    }
+
+    /// Runs the swap and fee math of a constant product swap `iterations`
+    /// times against the given reserves, without any token transfers, so
+    /// test validator runs can measure compute units per evaluation.
+    ///
+    /// Only enabled with the `bench` feature. Anchor generates the
+    /// instruction whatever its `cfg`, so the feature gates its body.
+    pub fn bench_swap_math(
+        ctx: Context<BenchSwapMath>,
+        iterations: u32,
+        amount_in: u64,
+        swap_source_amount: u64,
+        swap_destination_amount: u64,
+    ) -> Result<u64> {
+        if !cfg!(feature = "bench") {
+            return Err(SwapError::InvalidInput.into());
+        }
+
+        let fees = ctx.accounts.amm.curve_fees()?;
+        let curve = SwapCurve::default();
+
+        let mut output_amount = 0;
+        for _ in 0..iterations {
+            output_amount = compute_swap(
+                std::hint::black_box(amount_in),
+                swap_source_amount,
+                swap_destination_amount,
+                &fees,
+                &curve,
+                TradeDirection::AtoB,
+            )?
+            .output_amount;
+        }
+
+        Ok(output_amount)
+    }
+
+    pub fn get_marginal_price(
+        ctx: Context<GetMarginalPrice>,
+        amount_in: u64,
+        trade_direction: TradeDirection,
+    ) -> Result<u128> {
+        let amm = &ctx.accounts.amm;
+
+        if *ctx.accounts.token_a.to_account_info().key != amm.token_a_account
+            || *ctx.accounts.token_b.to_account_info().key != amm.token_b_account
+        {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+
+        let curve = amm.swap_curve()?;
+
+        let (swap_source_amount, swap_destination_amount) = match trade_direction {
+            TradeDirection::AtoB => (ctx.accounts.token_a.amount, ctx.accounts.token_b.amount),
+            TradeDirection::BtoA => (ctx.accounts.token_b.amount, ctx.accounts.token_a.amount),
+        };
+
+        let price = curve
+            .calculator
+            .marginal_price_after(
+                u128::from(amount_in),
+                u128::from(swap_source_amount),
+                u128::from(swap_destination_amount),
+                trade_direction,
+            )
+            .ok_or(SwapError::ZeroTradingTokens)?;
+
+        // Destination tokens per source token, as Q64.64
+        let price = price
+            .checked_mul(&PreciseNumber::new(1 << 64).ok_or(SwapError::ConversionFailure)?)
+            .and_then(|price| price.to_imprecise())
+            .ok_or(SwapError::ConversionFailure)?;
+
+        Ok(price)
+    }
+
+    /// Returns the spot price before any swap, in destination tokens per
+    /// source token as Q64.64, ignoring fees. Unlike `get_marginal_price`, it
+    /// is defined for every curve whatever the reserve sizes.
+    pub fn get_spot_price(
+        ctx: Context<GetMarginalPrice>,
+        trade_direction: TradeDirection,
+    ) -> Result<u128> {
+        let amm = &ctx.accounts.amm;
+
+        if *ctx.accounts.token_a.to_account_info().key != amm.token_a_account
+            || *ctx.accounts.token_b.to_account_info().key != amm.token_b_account
+        {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+
+        let curve = amm.swap_curve()?;
+        let price = curve
+            .calculator
+            .spot_price(
+                u128::from(ctx.accounts.token_a.amount),
+                u128::from(ctx.accounts.token_b.amount),
+                trade_direction,
+            )
+            .ok_or(SwapError::ZeroTradingTokens)?;
+        let price = to_q64(&price).ok_or(SwapError::ConversionFailure)?;
+
+        Ok(price)
+    }
+
+    pub fn can_swap(
+        ctx: Context<CanSwap>,
+        amount_in: u64,
+        minimum_amount_out: u64,
+        trade_direction: TradeDirection,
+    ) -> Result<CanSwapResult> {
+        let amm = &ctx.accounts.amm;
+        let timestamp = Clock::get()?.unix_timestamp;
+
+        let check = || -> std::result::Result<(), SwapError> {
+            if !amm.is_initialized {
+                return Err(SwapError::NotInitialized);
+            }
+
+            if *ctx.accounts.token_a.to_account_info().key != amm.token_a_account
+                || *ctx.accounts.token_b.to_account_info().key != amm.token_b_account
+            {
+                return Err(SwapError::IncorrectSwapAccount);
+            }
+
+            let token_a_amount = ctx.accounts.token_a.amount;
+            let token_b_amount = ctx.accounts.token_b.amount;
+            let (swap_source_amount, swap_destination_amount) = match trade_direction {
+                TradeDirection::AtoB => (token_a_amount, token_b_amount),
+                TradeDirection::BtoA => (token_b_amount, token_a_amount),
+            };
+
+            if amount_in == 0 || minimum_amount_out > swap_destination_amount {
+                return Err(SwapError::InvalidInput);
+            }
+
+            let curve = amm
+                .swap_curve()
+                .map_err(|_| SwapError::CorruptedPoolState)?;
+            let fees = amm
+                .swap_fees(timestamp)
+                .map_err(|_| SwapError::CorruptedPoolState)?;
+
+            amm.validate_pool_value(&curve, token_a_amount, token_b_amount)?;
+
+            let amounts = compute_swap(
+                amount_in,
+                swap_source_amount,
+                swap_destination_amount,
+                &fees,
+                &curve,
+                trade_direction,
+            )?;
+
+            if amounts.output_amount < minimum_amount_out {
+                return Err(SwapError::ExceededSlippage);
+            }
+
+            amm.validate_price_impact(
+                &curve,
+                amount_in,
+                amounts.output_amount,
+                token_a_amount,
+                token_b_amount,
+                trade_direction,
+            )?;
+
+            amm.validate_swap_size(amounts.output_amount, swap_destination_amount)?;
+
+            Ok(())
+        };
+
+        Ok(match check() {
+            Ok(()) => CanSwapResult {
+                success: true,
+                error_code: 0,
+            },
+            Err(error) => CanSwapResult {
+                success: false,
+                error_code: error.into(),
+            },
+        })
+    }
+
+    pub fn rebalance(
+        ctx: Context<Rebalance>,
+        target_token_a_ratio: u64,
+        target_token_b_ratio: u64,
+        maximum_token_a_amount_in: u64,
+        maximum_token_b_amount_in: u64,
+        minimum_token_a_amount_out: u64,
+        minimum_token_b_amount_out: u64,
+    ) -> Result<()> {
+        let amm = &mut ctx.accounts.amm;
+
+        if !amm.is_initialized {
+            return Err(SwapError::NotInitialized.into());
+        }
+
+        if *ctx.accounts.owner.key != amm.owner {
+            return Err(SwapError::Unauthorized.into());
+        }
+
+        amm.validate_not_in_progress()?;
+
+        if *ctx.accounts.authority.key
+            != authority_key(ctx.program_id, amm.to_account_info().key(), amm.bump_seed)?
+        {
+            return Err(SwapError::InvalidProgramAddress.into());
+        }
+
+        if *ctx.accounts.token_a.to_account_info().key != amm.token_a_account
+            || *ctx.accounts.token_b.to_account_info().key != amm.token_b_account
+        {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+
+        if *ctx.accounts.token_program.key != amm.token_program_id {
+            return Err(SwapError::IncorrectTokenProgramId.into());
+        }
+
+        if target_token_a_ratio == 0 || target_token_b_ratio == 0 {
+            return Err(SwapError::InvalidInput.into());
+        }
+
+        let token_a_amount = ctx.accounts.token_a.amount;
+        let token_b_amount = ctx.accounts.token_b.amount;
+        amm.update_price_cumulatives(token_a_amount, token_b_amount)?;
+
+        let spot_price = amm
+            .swap_curve()?
+            .calculator
+            .spot_price(
+                u128::from(token_a_amount),
+                u128::from(token_b_amount),
+                TradeDirection::AtoB,
+            )
+            .ok_or(SwapError::ZeroTradingTokens)?;
+        let (new_token_a_amount, new_token_b_amount) = rebalanced_reserves(
+            token_a_amount,
+            token_b_amount,
+            &spot_price,
+            target_token_a_ratio,
+            target_token_b_ratio,
+        )
+        .ok_or(SwapError::ZeroTradingTokens)?;
+
+        let seeds = &[
+            &amm.to_account_info().key().to_bytes(),
+            &[amm.bump_seed][..],
+        ];
+
+        for (
+            new_amount,
+            current_amount,
+            maximum_amount_in,
+            minimum_amount_out,
+            reserve,
+            owner_account,
+        ) in [
+            (
+                new_token_a_amount,
+                token_a_amount,
+                maximum_token_a_amount_in,
+                minimum_token_a_amount_out,
+                ctx.accounts.token_a.to_account_info(),
+                ctx.accounts.owner_token_a.to_account_info(),
+            ),
+            (
+                new_token_b_amount,
+                token_b_amount,
+                maximum_token_b_amount_in,
+                minimum_token_b_amount_out,
+                ctx.accounts.token_b.to_account_info(),
+                ctx.accounts.owner_token_b.to_account_info(),
+            ),
+        ] {
+            let reserve_key = *reserve.key;
+            if new_amount > current_amount {
+                let amount_in = new_amount - current_amount;
+                if amount_in > maximum_amount_in {
+                    return Err(SwapError::ExceededSlippage.into());
+                }
+                token::transfer(
+                    CpiContext::new(
+                        ctx.accounts.token_program.clone(),
+                        Transfer {
+                            from: owner_account,
+                            to: reserve,
+                            authority: ctx.accounts.owner.clone(),
+                        },
+                    ),
+                    amount_in,
+                )?;
+                amm.record_reserve_change(&reserve_key, amount_in, 0)?;
+            } else if new_amount < current_amount {
+                let amount_out = current_amount - new_amount;
+                if amount_out < minimum_amount_out {
+                    return Err(SwapError::ExceededSlippage.into());
+                }
+                token::transfer(
+                    CpiContext::new_with_signer(
+                        ctx.accounts.token_program.clone(),
+                        Transfer {
+                            from: reserve,
+                            to: owner_account,
+                            authority: ctx.accounts.authority.clone(),
+                        },
+                        &[&seeds[..]],
+                    ),
+                    amount_out,
+                )?;
+                amm.record_reserve_change(&reserve_key, 0, amount_out)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn set_deposits_enabled(ctx: Context<UpdateAmm>, deposits_enabled: bool) -> Result<()> {
+        let amm = &mut ctx.accounts.amm;
+
+        if *ctx.accounts.owner.key != amm.owner {
+            return Err(SwapError::Unauthorized.into());
+        }
+
+        amm.validate_not_in_progress()?;
+
+        amm.deposits_enabled = deposits_enabled;
+
+        Ok(())
+    }
+
+    pub fn set_fee_free_until(ctx: Context<UpdateAmm>, fee_free_until: i64) -> Result<()> {
+        let amm = &mut ctx.accounts.amm;
+
+        if *ctx.accounts.owner.key != amm.owner {
+            return Err(SwapError::Unauthorized.into());
+        }
+
+        amm.validate_not_in_progress()?;
+
+        amm.fee_free_until = fee_free_until;
+
+        Ok(())
+    }
+
+    pub fn set_max_price_impact_bps(
+        ctx: Context<UpdateAmm>,
+        max_price_impact_bps: u16,
+    ) -> Result<()> {
+        let amm = &mut ctx.accounts.amm;
+
+        if *ctx.accounts.owner.key != amm.owner {
+            return Err(SwapError::Unauthorized.into());
+        }
+
+        amm.validate_not_in_progress()?;
+
+        if max_price_impact_bps > BASIS_POINTS {
+            return Err(SwapError::InvalidInput.into());
+        }
+
+        amm.max_price_impact_bps = max_price_impact_bps;
+
+        Ok(())
+    }
+
+    pub fn set_max_swap_fraction_bps(
+        ctx: Context<UpdateAmm>,
+        max_swap_fraction_bps: u16,
+    ) -> Result<()> {
+        let amm = &mut ctx.accounts.amm;
+
+        if *ctx.accounts.owner.key != amm.owner {
+            return Err(SwapError::Unauthorized.into());
+        }
+
+        amm.validate_not_in_progress()?;
+
+        if max_swap_fraction_bps > BASIS_POINTS {
+            return Err(SwapError::InvalidInput.into());
+        }
+
+        amm.max_swap_fraction_bps = max_swap_fraction_bps;
+
+        Ok(())
+    }
+
+    /// Sets the account host fees are minted to when a swap has no host,
+    /// `Pubkey::default()` to fold them into the owner fee instead.
+    pub fn set_default_host_fee_account(
+        ctx: Context<UpdateAmm>,
+        default_host_fee_account: Pubkey,
+    ) -> Result<()> {
+        let amm = &mut ctx.accounts.amm;
+
+        if *ctx.accounts.owner.key != amm.owner {
+            return Err(SwapError::Unauthorized.into());
+        }
+
+        amm.validate_not_in_progress()?;
+
+        amm.default_host_fee_account = default_host_fee_account;
+
+        Ok(())
+    }
+
+    /// Proposes `pending_owner` as the next owner of the pool, who becomes
+    /// owner by calling `accept_owner`. The current owner stays in charge
+    /// until then, and can withdraw the proposal with `Pubkey::default()`.
+    pub fn propose_owner(ctx: Context<UpdateAmm>, pending_owner: Pubkey) -> Result<()> {
+        let amm = &mut ctx.accounts.amm;
+
+        if *ctx.accounts.owner.key != amm.owner {
+            return Err(SwapError::Unauthorized.into());
+        }
+
+        amm.validate_not_in_progress()?;
+
+        amm.pending_owner = pending_owner;
+
+        Ok(())
+    }
+
+    /// Makes the signing pending owner the owner of the pool.
+    pub fn accept_owner(ctx: Context<AcceptOwner>) -> Result<()> {
+        let amm = &mut ctx.accounts.amm;
+
+        if amm.pending_owner == Pubkey::default()
+            || *ctx.accounts.pending_owner.key != amm.pending_owner
+        {
+            return Err(SwapError::Unauthorized.into());
+        }
+
+        amm.validate_not_in_progress()?;
+
+        let old_owner = amm.owner;
+        amm.owner = amm.pending_owner;
+        amm.pending_owner = Pubkey::default();
+
+        emit!(OwnerChanged {
+            amm: amm.key(),
+            old_owner,
+            new_owner: amm.owner,
+        });
+
+        Ok(())
+    }
+
+    /// Makes swaps check the live reserve balances against the pool's
+    /// accounted reserves, rejecting the swap if either reserve was drained.
+    pub fn set_reconcile_reserves(ctx: Context<UpdateAmm>, reconcile_reserves: bool) -> Result<()> {
+        let amm = &mut ctx.accounts.amm;
+
+        if *ctx.accounts.owner.key != amm.owner {
+            return Err(SwapError::Unauthorized.into());
+        }
+
+        amm.validate_not_in_progress()?;
+
+        amm.reconcile_reserves = reconcile_reserves;
+
+        Ok(())
+    }
+
+    /// Caps the balance deposits can bring either reserve to, or lifts the
+    /// cap with 0.
+    ///
+    /// The constant product invariant of two u64 reserves stays below 2^128
+    /// and is computed in 256 bits, so swaps don't overflow at any reserve
+    /// size. Other products are kept in u128, like the pool value
+    /// `token_a + token_b * price` of a constant price pool, which only fits
+    /// while `max_reserve * (price + 1)` stays below 2^128. The cap lets an
+    /// owner keep a pool well inside such bounds.
+    pub fn set_max_reserve(ctx: Context<UpdateAmm>, max_reserve: u64) -> Result<()> {
+        let amm = &mut ctx.accounts.amm;
+
+        if *ctx.accounts.owner.key != amm.owner {
+            return Err(SwapError::Unauthorized.into());
+        }
+
+        amm.validate_not_in_progress()?;
+
+        amm.max_reserve = max_reserve;
+
+        Ok(())
+    }
+
+    /// Replaces the recipients of the owner trade fee. An empty split sends
+    /// the fee back to the pool fee account.
+    pub fn set_fee_split(ctx: Context<UpdateAmm>, fee_split: Vec<FeeRecipient>) -> Result<()> {
+        let amm = &mut ctx.accounts.amm;
+
+        if *ctx.accounts.owner.key != amm.owner {
+            return Err(SwapError::Unauthorized.into());
+        }
+
+        amm.validate_not_in_progress()?;
+
+        if !fee_split.is_empty() {
+            validate_fee_split(&fee_split)?;
+        }
+
+        amm.fee_split = [FeeRecipient::default(); MAX_FEE_RECIPIENTS];
+        amm.fee_split[..fee_split.len()].copy_from_slice(&fee_split);
+
+        Ok(())
+    }
+
+    /// Lends `amount` from one of the reserves and invokes `callback_program`
+    /// with `data` and the remaining accounts. The callback must return the
+    /// loan plus the flash loan fee to the reserve before it finishes.
+    pub fn flash_loan(ctx: Context<FlashLoan>, amount: u64, data: Vec<u8>) -> Result<()> {
+        validate_remaining_accounts(ctx.remaining_accounts)?;
+
+        let amm = &mut ctx.accounts.amm;
+        if !amm.is_initialized {
+            return Err(SwapError::NotInitialized.into());
+        }
+
+        amm.validate_not_in_progress()?;
+
+        if *ctx.accounts.authority.key
+            != authority_key(ctx.program_id, amm.to_account_info().key(), amm.bump_seed)?
+        {
+            return Err(SwapError::InvalidProgramAddress.into());
+        }
+
+        if !(*ctx.accounts.reserve.to_account_info().key == amm.token_a_account
+            || *ctx.accounts.reserve.to_account_info().key == amm.token_b_account)
+        {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+
+        if *ctx.accounts.token_program.key != amm.token_program_id {
+            return Err(SwapError::IncorrectTokenProgramId.into());
+        }
+
+        // Re-entering the pool would let the callback trade against the
+        // depleted reserve
+        if ctx.accounts.callback_program.key == ctx.program_id {
+            return Err(SwapError::InvalidInput.into());
+        }
+
+        if amount == 0 || amount > ctx.accounts.reserve.amount {
+            return Err(SwapError::InvalidInput.into());
+        }
+
+        let fees = amm.curve_fees()?;
+        let fee = fees
+            .flash_loan_fee(u128::from(amount))
+            .ok_or(SwapError::FeeCalculationFailure)?;
+        let required_balance = u128::from(ctx.accounts.reserve.amount)
+            .checked_add(fee)
+            .ok_or(SwapError::FeeCalculationFailure)?;
+
+        let seeds = &[
+            &amm.to_account_info().key().to_bytes(),
+            &[amm.bump_seed][..],
+        ];
+
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.clone(),
+                token::Transfer {
+                    from: ctx.accounts.reserve.to_account_info().clone(),
+                    to: ctx.accounts.destination.clone(),
+                    authority: ctx.accounts.authority.clone(),
+                },
+                &[&seeds[..]],
+            ),
+            amount,
+        )?;
+
+        let callback = Instruction {
+            program_id: *ctx.accounts.callback_program.key,
+            accounts: ctx
+                .remaining_accounts
+                .iter()
+                .map(|account| AccountMeta {
+                    pubkey: *account.key,
+                    is_signer: account.is_signer,
+                    is_writable: account.is_writable,
+                })
+                .collect(),
+            data,
+        };
+
+        // Accounts are only written back when the instruction exits, so the
+        // flag is persisted by hand for the callback to see it
+        amm.in_progress = true;
+        amm.exit(ctx.program_id)?;
+        invoke(&callback, ctx.remaining_accounts)?;
+        amm.in_progress = false;
+
+        ctx.accounts.reserve.reload()?;
+        if u128::from(ctx.accounts.reserve.amount) < required_balance {
+            return Err(SwapError::FlashLoanNotRepaid.into());
+        }
+
+        // The fee stays with liquidity providers, anything repaid above it
+        // is surplus
+        amm.record_reserve_change(ctx.accounts.reserve.to_account_info().key, to_u64(fee)?, 0)?;
+
+        Ok(())
+    }
+
+    /// Sends the reserve balances in excess of the pool's accounted reserves,
+    /// such as tokens transferred to the reserves directly, to the owner's
+    /// destination accounts.
+    pub fn skim(ctx: Context<Skim>) -> Result<()> {
+        let amm = &ctx.accounts.amm;
+
+        if !amm.is_initialized {
+            return Err(SwapError::NotInitialized.into());
+        }
+
+        if *ctx.accounts.owner.key != amm.owner {
+            return Err(SwapError::Unauthorized.into());
+        }
+
+        amm.validate_not_in_progress()?;
+
+        if *ctx.accounts.authority.key
+            != authority_key(ctx.program_id, amm.to_account_info().key(), amm.bump_seed)?
+        {
+            return Err(SwapError::InvalidProgramAddress.into());
+        }
+
+        validate_reserves(amm, &ctx.accounts.token_a, &ctx.accounts.token_b)?;
+
+        if *ctx.accounts.token_program.key != amm.token_program_id {
+            return Err(SwapError::IncorrectTokenProgramId.into());
+        }
+
+        let seeds = &[
+            &amm.to_account_info().key().to_bytes(),
+            &[amm.bump_seed][..],
+        ];
+
+        for (reserve, tracked, destination) in [
+            (
+                &ctx.accounts.token_a,
+                amm.reserve_a,
+                &ctx.accounts.destination_a,
+            ),
+            (
+                &ctx.accounts.token_b,
+                amm.reserve_b,
+                &ctx.accounts.destination_b,
+            ),
+        ] {
+            let surplus = reserve.amount.saturating_sub(tracked);
+            if surplus > 0 {
+                token::transfer(
+                    CpiContext::new_with_signer(
+                        ctx.accounts.token_program.clone(),
+                        Transfer {
+                            from: reserve.to_account_info().clone(),
+                            to: destination.to_account_info().clone(),
+                            authority: ctx.accounts.authority.clone(),
+                        },
+                        &[&seeds[..]],
+                    ),
+                    surplus,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn set_exit_fee(
+        ctx: Context<UpdateAmm>,
+        exit_fee_bps: u16,
+        exit_fee_decay_period: i64,
+    ) -> Result<()> {
+        let amm = &mut ctx.accounts.amm;
+
+        if *ctx.accounts.owner.key != amm.owner {
+            return Err(SwapError::Unauthorized.into());
+        }
+
+        amm.validate_not_in_progress()?;
+
+        if exit_fee_bps >= BASIS_POINTS || exit_fee_decay_period < 0 {
+            return Err(SwapError::InvalidInput.into());
+        }
+
+        amm.exit_fee_bps = exit_fee_bps;
+        amm.exit_fee_decay_period = exit_fee_decay_period;
+
+        Ok(())
+    }
+
+    /// Replaces the pool curve, which must accept the current reserves. The
+    /// change bumps the last update slot, so quotes made on the old curve are
+    /// rejected by `swap_with_expected_slot`.
+    pub fn set_curve(ctx: Context<SetCurve>, curve_input: CurveInput) -> Result<()> {
+        let amm = &mut ctx.accounts.amm;
+
+        if !amm.is_initialized {
+            return Err(SwapError::NotInitialized.into());
+        }
+
+        if *ctx.accounts.owner.key != amm.owner {
+            return Err(SwapError::Unauthorized.into());
+        }
+
+        amm.validate_not_in_progress()?;
+
+        if *ctx.accounts.token_a.to_account_info().key != amm.token_a_account
+            || *ctx.accounts.token_b.to_account_info().key != amm.token_b_account
+        {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+
+        let token_a_amount = ctx.accounts.token_a.amount;
+        let token_b_amount = ctx.accounts.token_b.amount;
+
+        let curve = build_curve(&curve_input)?;
+        curve.calculator.validate()?;
+        curve
+            .calculator
+            .validate_supply(token_a_amount, token_b_amount)?;
+        amm.validate_pool_value(&curve, token_a_amount, token_b_amount)?;
+
+        let old_curve_type = amm.swap_curve()?.curve_type;
+
+        amm.update_price_cumulatives(token_a_amount, token_b_amount)?;
+        amm.curve = curve_input;
+
+        emit!(CurveChanged {
+            amm: amm.key(),
+            old_curve_type,
+            new_curve_type: curve.curve_type,
+        });
+
+        Ok(())
+    }
+
+    pub fn open_position(ctx: Context<OpenPosition>) -> Result<()> {
+        let amm = &ctx.accounts.amm;
+
+        if !amm.is_initialized {
+            return Err(SwapError::NotInitialized.into());
+        }
+
+        if ctx.accounts.pool_token_account.mint != amm.pool_mint {
+            return Err(SwapError::IncorrectPoolMint.into());
+        }
+
+        if ctx.accounts.pool_token_account.owner != ctx.accounts.position.key() {
+            return Err(SwapError::InvalidOwner.into());
+        }
+
+        let position = &mut ctx.accounts.position;
+        position.amm = amm.key();
+        position.owner = *ctx.accounts.owner.key;
+        position.pool_token_account = ctx.accounts.pool_token_account.key();
+        position.pool_token_amount = 0;
+        position.bump_seed = ctx.bumps.position;
+        position.deposited_pool_tokens = 0;
+        position.deposit_ts = 0;
+
+        Ok(())
+    }
+
+    pub fn claim_position(ctx: Context<ClaimPosition>) -> Result<()> {
+        let position = &mut ctx.accounts.position;
+
+        if *ctx.accounts.owner.key != position.owner {
+            return Err(SwapError::Unauthorized.into());
+        }
+
+        if ctx.accounts.pool_token_account.key() != position.pool_token_account {
+            return Err(SwapError::IncorrectFeeAccount.into());
+        }
+
+        let amount = position.pool_token_amount;
+        position.pool_token_amount = 0;
+
+        let seeds = &[
+            POSITION_SEED,
+            position.amm.as_ref(),
+            position.owner.as_ref(),
+            &[position.bump_seed][..],
+        ];
+
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.clone(),
+                Transfer {
+                    from: ctx.accounts.pool_token_account.to_account_info().clone(),
+                    to: ctx.accounts.destination.to_account_info().clone(),
+                    authority: position.to_account_info().clone(),
+                },
+                &[&seeds[..]],
+            ),
+            amount,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_pool_info(ctx: Context<GetPoolInfo>) -> Result<PoolInfo> {
+        let amm = &ctx.accounts.amm;
+
+        Ok(PoolInfo {
+            cumulative_trade_fee: amm.cumulative_trade_fee,
+            cumulative_owner_fee: amm.cumulative_owner_fee,
+            last_update_slot: amm.last_update_slot,
+            volume_a_in: amm.volume_a_in,
+            volume_b_in: amm.volume_b_in,
+            total_fee_bps: fee_bps(amm.fees.trade_fee_numerator, amm.fees.trade_fee_denominator)
+                .saturating_add(fee_bps(
+                    amm.fees.owner_trade_fee_numerator,
+                    amm.fees.owner_trade_fee_denominator,
+                )),
+            reserve_a: amm.reserve_a,
+            reserve_b: amm.reserve_b,
+        })
+    }
+
+    pub fn get_price_cumulatives(ctx: Context<GetPriceCumulatives>) -> Result<PriceCumulatives> {
+        let amm = &ctx.accounts.amm;
+
+        if *ctx.accounts.token_a.to_account_info().key != amm.token_a_account
+            || *ctx.accounts.token_b.to_account_info().key != amm.token_b_account
+        {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+
+        let timestamp = Clock::get()?.unix_timestamp;
+        let (price_cumulative_a, price_cumulative_b) = amm.price_cumulatives_at(
+            ctx.accounts.token_a.amount,
+            ctx.accounts.token_b.amount,
+            timestamp,
+        )?;
+
+        Ok(PriceCumulatives {
+            price_cumulative_a,
+            price_cumulative_b,
+            timestamp,
+        })
+    }
+
+    /// Returns a hash of the pool configuration, for clients to pin a pool
+    /// to a known-good configuration before using it.
+    pub fn get_config_hash(ctx: Context<GetConfigHash>) -> Result<[u8; 32]> {
+        ctx.accounts.amm.config_hash()
+    }
+
+    /// Returns the swap authority of the pool and its bump seed, as derived
+    /// by `initialize`.
+    pub fn get_authority(ctx: Context<GetAuthority>) -> Result<AuthorityInfo> {
+        let (authority, bump_seed) = Amm::authority(&ctx.accounts.amm.key(), ctx.program_id);
+        Ok(AuthorityInfo {
+            authority,
+            bump_seed,
+        })
+    }
+
+    /// Returns the fair value of one pool token given external prices for
+    /// both tokens, `2 * sqrt(reserve_a * price_a * reserve_b * price_b) / supply`.
+    /// Unlike summing reserve values, this can't be inflated by trading the
+    /// reserves away from the external price. Prices and the result are Q64.64
+    /// amounts of a common quote unit per token unit.
+    pub fn fair_lp_value(
+        ctx: Context<GetFairLpValue>,
+        price_a: u128,
+        price_b: u128,
+    ) -> Result<u128> {
+        let amm = &ctx.accounts.amm;
+
+        validate_reserves(amm, &ctx.accounts.token_a, &ctx.accounts.token_b)?;
+
+        if *ctx.accounts.pool_mint.to_account_info().key != amm.pool_mint {
+            return Err(SwapError::IncorrectPoolMint.into());
+        }
+
+        if ctx.accounts.pool_mint.supply == 0 {
+            return Err(SwapError::EmptySupply.into());
+        }
+
+        let fair_value = || -> Option<u128> {
+            let q64 = PreciseNumber::new(1 << 64)?;
+            let value_a = PreciseNumber::new(price_a)?
+                .checked_mul(&PreciseNumber::new(u128::from(
+                    ctx.accounts.token_a.amount,
+                ))?)?
+                .checked_div(&q64)?;
+            let value_b = PreciseNumber::new(price_b)?
+                .checked_mul(&PreciseNumber::new(u128::from(
+                    ctx.accounts.token_b.amount,
+                ))?)?
+                .checked_div(&q64)?;
+            // sqrt(a) * sqrt(b) keeps the product of both values in range
+            let pool_value = value_a
+                .sqrt()?
+                .checked_mul(&value_b.sqrt()?)?
+                .checked_mul(&PreciseNumber::new(2)?)?;
+            pool_value
+                .checked_div(&PreciseNumber::new(u128::from(
+                    ctx.accounts.pool_mint.supply,
+                ))?)?
+                .checked_mul(&q64)?
+                .to_imprecise()
+        };
+
+        let value = fair_value().ok_or(SwapError::ConversionFailure)?;
+
+        Ok(value)
+    }
+
+    /// Returns the pool configuration in a self-describing form, with fees
+    /// also given as basis points.
+    pub fn describe_pool(ctx: Context<DescribePool>) -> Result<PoolDescription> {
+        let amm = &ctx.accounts.amm;
+        let curve = amm.swap_curve()?;
+        let fees = &amm.fees;
+
+        Ok(PoolDescription {
+            curve_type: curve.curve_type,
+            curve_params: amm.curve.curve_params,
+            fees: fees.clone(),
+            token_a_mint: amm.token_a_mint,
+            token_b_mint: amm.token_b_mint,
+            pool_mint: amm.pool_mint,
+            trade_fee_bps: fee_bps(fees.trade_fee_numerator, fees.trade_fee_denominator),
+            owner_trade_fee_bps: fee_bps(
+                fees.owner_trade_fee_numerator,
+                fees.owner_trade_fee_denominator,
+            ),
+            owner_withdraw_fee_bps: fee_bps(
+                fees.owner_withdraw_fee_numerator,
+                fees.owner_withdraw_fee_denominator,
+            ),
+            host_fee_bps: fee_bps(fees.host_fee_numerator, fees.host_fee_denominator),
+            flash_loan_fee_bps: fee_bps(
+                fees.flash_loan_fee_numerator,
+                fees.flash_loan_fee_denominator,
+            ),
+        })
+    }
+
+    /// Emits the current reserves and pool token supply, letting indexers
+    /// checkpoint the pool without replaying its transfers.
+    pub fn emit_pool_snapshot(ctx: Context<EmitPoolSnapshot>) -> Result<()> {
+        let amm = &ctx.accounts.amm;
+
+        validate_reserves(amm, &ctx.accounts.token_a, &ctx.accounts.token_b)?;
+
+        if *ctx.accounts.pool_mint.to_account_info().key != amm.pool_mint {
+            return Err(SwapError::IncorrectPoolMint.into());
+        }
+
+        emit!(PoolSnapshot {
+            amm: amm.key(),
+            token_a_amount: ctx.accounts.token_a.amount,
+            token_b_amount: ctx.accounts.token_b.amount,
+            pool_token_supply: ctx.accounts.pool_mint.supply,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    /// Returns the pool tokens making up `share_bps` of the pool supply and
+    /// the reserve amounts they would withdraw, before the withdraw fee.
+    pub fn get_pool_share(ctx: Context<GetPoolShare>, share_bps: u16) -> Result<PoolShare> {
+        let amm = &ctx.accounts.amm;
+
+        if share_bps > BASIS_POINTS {
+            return Err(SwapError::InvalidInput.into());
+        }
+
+        validate_reserves(amm, &ctx.accounts.token_a, &ctx.accounts.token_b)?;
+
+        if *ctx.accounts.pool_mint.to_account_info().key != amm.pool_mint {
+            return Err(SwapError::IncorrectPoolMint.into());
+        }
+
+        let pool_mint_supply = u128::from(ctx.accounts.pool_mint.supply);
+        let pool_token_amount = pool_mint_supply
+            .checked_mul(u128::from(share_bps))
+            .and_then(|amount| amount.checked_div(u128::from(BASIS_POINTS)))
+            .ok_or(SwapError::ConversionFailure)?;
+        if pool_token_amount == 0 {
+            return Ok(PoolShare::default());
+        }
+
+        let tokens = amm
+            .swap_curve()?
+            .calculator
+            .pool_tokens_to_trading_tokens(
+                pool_token_amount,
+                pool_mint_supply,
+                u128::from(ctx.accounts.token_a.amount),
+                u128::from(ctx.accounts.token_b.amount),
+                RoundDirection::Floor,
+            )
+            .ok_or(SwapError::ZeroTradingTokens)?;
+
+        Ok(PoolShare {
+            pool_token_amount: to_u64(pool_token_amount)?,
+            token_a_amount: to_u64(tokens.token_a_amount)?,
+            token_b_amount: to_u64(tokens.token_b_amount)?,
+        })
+    }
+
+    /// Creates a weighted pool over the reserve token accounts passed as
+    /// remaining accounts, one per weight, and mints the initial pool tokens
+    /// to `destination`. The reserves must already be funded.
+    pub fn initialize_weighted_pool<'info>(
+        ctx: Context<'_, '_, '_, 'info, InitializeWeightedPool<'info>>,
+        fees_input: FeeInput,
+        weights: Vec<u64>,
+    ) -> Result<()> {
+        ctx.accounts
+            .process_initialize(ctx.program_id, ctx.remaining_accounts, fees_input, weights)
+    }
+
+    /// Swaps between two reserves of a weighted pool.
+    pub fn swap_weighted(
+        ctx: Context<SwapWeighted>,
+        amount_in: u64,
+        minimum_amount_out: u64,
+    ) -> Result<()> {
+        ctx.accounts
+            .process_swap(ctx.program_id, amount_in, minimum_amount_out)
+    }
+
+    /// Deposits every reserve of a weighted pool in proportion for
+    /// `pool_token_amount` pool tokens. The remaining accounts are the user's
+    /// source account and the reserve account of each reserve, in pool order.
+    pub fn deposit_weighted<'info>(
+        ctx: Context<'_, '_, '_, 'info, DepositWeighted<'info>>,
+        pool_token_amount: u64,
+        maximum_token_amounts: Vec<u64>,
+    ) -> Result<()> {
+        ctx.accounts.process_deposit(
+            ctx.program_id,
+            ctx.remaining_accounts,
+            pool_token_amount,
+            &maximum_token_amounts,
+        )
+    }
+
+    /// Burns `pool_token_amount` pool tokens for their share of every reserve
+    /// of a weighted pool. The remaining accounts are the reserve account and
+    /// the user's destination account of each reserve, in pool order.
+    pub fn withdraw_weighted<'info>(
+        ctx: Context<'_, '_, '_, 'info, WithdrawWeighted<'info>>,
+        pool_token_amount: u64,
+        minimum_token_amounts: Vec<u64>,
+    ) -> Result<()> {
+        ctx.accounts.process_withdraw(
+            ctx.program_id,
+            ctx.remaining_accounts,
+            pool_token_amount,
+            &minimum_token_amounts,
+        )
+    }
+}
+
+#[derive(Accounts)]
+pub struct Initialize<'info> {
+    /// CHECK: This is the authority for the swap. The validation is handled in the instruction logic.
+    #[account(mut)]
+    pub authority: AccountInfo<'info>,
+    /// CHECK: This is the initializer of the swap. The validation is handled in the instruction logic.
+    #[account(mut, signer)]
+    pub initializer: AccountInfo<'info>,
+    #[account(init, payer=initializer, space = 8 + Amm::LEN)]
+    pub amm: Box<Account<'info, Amm>>,
+    #[account(mut)]
+    pub pool_mint: Box<Account<'info, Mint>>,
+    #[account(mut)]
+    pub token_a: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub token_b: Account<'info, TokenAccount>,
+    pub token_a_mint: Box<Account<'info, Mint>>,
+    pub token_b_mint: Box<Account<'info, Mint>>,
+    #[account(mut)]
+    pub fee_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub destination: Account<'info, TokenAccount>,
+    /// CHECK: This is the Solana token program, which is a known, trusted program
+    pub token_program: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+    /// Initializer's token A account, required by `initialize_with_funding`
+    #[account(mut)]
+    pub initializer_token_a: Option<Account<'info, TokenAccount>>,
+    /// Initializer's token B account, required by `initialize_with_funding`
+    #[account(mut)]
+    pub initializer_token_b: Option<Account<'info, TokenAccount>>,
+    /// Global config, restricting initialization to its allowlist when passed
+    #[account(seeds = [CONFIG_SEED], bump = config.bump_seed)]
+    pub config: Option<Account<'info, GlobalConfig>>,
+}
+
+#[derive(Accounts)]
+pub struct Swap<'info> {
+    /// CHECK: This is the authority for the swap. The validation is handled in the instruction logic.
+    pub authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub amm: Box<Account<'info, Amm>>,
+    /// CHECK: This is the user transfer authority. The validation is handled in the instruction logic.
+    #[account(signer)]
+    pub user_transfer_authority: AccountInfo<'info>,
+    /// CHECK: This is the source token account. The validation is handled in the instruction logic.
+    #[account(mut)]
+    pub source_info: AccountInfo<'info>,
+    /// CHECK: This is the destination token account. The validation is handled in the instruction logic.
+    #[account(mut)]
+    pub destination_info: AccountInfo<'info>,
+    #[account(
+        mut,
+        constraint = swap_source.key() == amm.token_a_account
+            || swap_source.key() == amm.token_b_account @ SwapError::IncorrectSwapAccount,
+        constraint = swap_source.key() != source_info.key() @ SwapError::InvalidInput,
+    )]
+    pub swap_source: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = swap_destination.key() == amm.token_a_account
+            || swap_destination.key() == amm.token_b_account @ SwapError::IncorrectSwapAccount,
+        constraint = swap_destination.key() != swap_source.key() @ SwapError::InvalidInput,
+        constraint = swap_destination.key() != destination_info.key() @ SwapError::InvalidInput,
+    )]
+    pub swap_destination: Account<'info, TokenAccount>,
+    #[account(mut, constraint = pool_mint.key() == amm.pool_mint @ SwapError::IncorrectPoolMint)]
+    pub pool_mint: Account<'info, Mint>,
+    #[account(
+        mut,
+        constraint = pool_account.key() == amm.pool_fee_account @ SwapError::IncorrectFeeAccount,
+    )]
+    pub pool_account: Account<'info, TokenAccount>,
+    /// CHECK: This is the Solana token program, which is a known, trusted program
+    #[account(
+        constraint = token_program.key() == amm.token_program_id
+            @ SwapError::IncorrectTokenProgramId,
+    )]
+    pub token_program: AccountInfo<'info>,
+    /// CHECK: Host pool token account, validated in the instruction logic. Without it the
+    /// host fee goes to the owner.
+    #[account(mut)]
+    pub host_fee_account: Option<AccountInfo<'info>>,
+    /// CHECK: Default host pool token account of the pool, validated in the instruction logic.
+    /// Required when the pool sets one and the swap has no host.
+    #[account(mut)]
+    pub default_host_fee_account: Option<AccountInfo<'info>>,
+    /// Host position that host fees are reinvested into instead of a plain host account
+    #[account(mut)]
+    pub host_position: Option<Account<'info, Position>>,
+    /// CHECK: Temporary wrapped SOL account of the user, created and closed by the instruction.
+    /// It is funded by the user transfer authority, which must then be writable.
+    #[account(mut)]
+    pub wsol_account: Option<AccountInfo<'info>>,
+    pub native_mint: Option<Account<'info, Mint>>,
+    pub system_program: Option<Program<'info, System>>,
+}
+
+#[derive(Accounts)]
+pub struct DepositLiquidity<'info> {
+    /// CHECK: This is the authority for the swap. The validation is handled in the instruction logic.
+    pub authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub amm: Box<Account<'info, Amm>>,
+    /// CHECK: This is the user transfer authority. The validation is handled in the instruction logic.
+    #[account(signer)]
+    pub user_transfer_authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub user_token_a: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_token_b: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub token_a: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub token_b: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub pool_mint: Account<'info, Mint>,
+    #[account(mut)]
+    pub user_pool_token: Account<'info, TokenAccount>,
+    /// CHECK: This is the Solana token program, which is a known, trusted program
+    pub token_program: AccountInfo<'info>,
+    /// Depositor's position, which records the deposit time for the exit fee
+    #[account(mut)]
+    pub position: Option<Account<'info, Position>>,
+    /// CHECK: Temporary wrapped SOL account of the user, created and closed by the instruction.
+    /// It is funded by the user transfer authority, which must then be writable.
+    #[account(mut)]
+    pub wsol_account: Option<AccountInfo<'info>>,
+    pub native_mint: Option<Account<'info, Mint>>,
+    pub system_program: Option<Program<'info, System>>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawLiquidity<'info> {
+    /// CHECK: This is the authority for the swap. The validation is handled in the instruction logic.
+    pub authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub amm: Box<Account<'info, Amm>>,
+    /// CHECK: This is the user transfer authority. The validation is handled in the instruction logic.
+    #[account(signer)]
+    pub user_transfer_authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub source_pool_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub token_a: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub token_b: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_token_a: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub user_token_b: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub pool_mint: Account<'info, Mint>,
+    #[account(mut)]
+    pub fee_account: Account<'info, TokenAccount>,
+    /// CHECK: This is the Solana token program, which is a known, trusted program
+    pub token_program: AccountInfo<'info>,
+    /// Withdrawer's position, without it the full exit fee is charged
+    #[account(mut)]
+    pub position: Option<Account<'info, Position>>,
+}
+
+#[derive(Accounts)]
+pub struct GetPriceCurve<'info> {
+    pub amm: Box<Account<'info, Amm>>,
+    pub token_a: Account<'info, TokenAccount>,
+    pub token_b: Account<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct QuoteSwap<'info> {
+    pub amm: Box<Account<'info, Amm>>,
+    pub token_a: Account<'info, TokenAccount>,
+    pub token_b: Account<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct QuoteDeposit<'info> {
+    pub amm: Box<Account<'info, Amm>>,
+    pub token_a: Account<'info, TokenAccount>,
+    pub token_b: Account<'info, TokenAccount>,
+    pub pool_mint: Account<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct GetMarginalPrice<'info> {
+    pub amm: Box<Account<'info, Amm>>,
+    pub token_a: Account<'info, TokenAccount>,
+    pub token_b: Account<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct CanSwap<'info> {
+    pub amm: Box<Account<'info, Amm>>,
+    pub token_a: Account<'info, TokenAccount>,
+    pub token_b: Account<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct Rebalance<'info> {
+    /// CHECK: This is the authority for the swap. The validation is handled in the instruction logic.
+    pub authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub amm: Box<Account<'info, Amm>>,
+    /// CHECK: This is the pool owner. The validation is handled in the instruction logic.
+    #[account(signer)]
+    pub owner: AccountInfo<'info>,
+    #[account(mut)]
+    pub token_a: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub token_b: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub owner_token_a: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub owner_token_b: Account<'info, TokenAccount>,
+    /// CHECK: This is the Solana token program, which is a known, trusted program
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct FlashLoan<'info> {
+    /// CHECK: This is the authority for the swap. The validation is handled in the instruction logic.
+    pub authority: AccountInfo<'info>,
+    #[account(mut)]
+    pub amm: Box<Account<'info, Amm>>,
+    #[account(mut)]
+    pub reserve: Account<'info, TokenAccount>,
+    /// CHECK: This is the borrower's token account. The validation is handled by the token program.
+    #[account(mut)]
+    pub destination: AccountInfo<'info>,
+    /// CHECK: This is the borrower's program, invoked with the remaining accounts.
+    pub callback_program: AccountInfo<'info>,
+    /// CHECK: This is the Solana token program, which is a known, trusted program
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct Skim<'info> {
+    /// CHECK: This is the authority for the swap. The validation is handled in the instruction logic.
+    pub authority: AccountInfo<'info>,
+    pub amm: Box<Account<'info, Amm>>,
+    /// CHECK: This is the pool owner. The validation is handled in the instruction logic.
+    #[account(signer)]
+    pub owner: AccountInfo<'info>,
+    #[account(mut)]
+    pub token_a: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub token_b: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub destination_a: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub destination_b: Account<'info, TokenAccount>,
+    /// CHECK: This is the Solana token program, which is a known, trusted program
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetCurve<'info> {
+    #[account(mut)]
+    pub amm: Box<Account<'info, Amm>>,
+    /// CHECK: This is the pool owner. The validation is handled in the instruction logic.
+    #[account(signer)]
+    pub owner: AccountInfo<'info>,
+    pub token_a: Account<'info, TokenAccount>,
+    pub token_b: Account<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeConfig<'info> {
+    /// CHECK: This is the admin of the config, checked against the upgrade authority
+    #[account(mut, signer)]
+    pub admin: AccountInfo<'info>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + GlobalConfig::LEN,
+        seeds = [CONFIG_SEED],
+        bump,
+    )]
+    pub config: Account<'info, GlobalConfig>,
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()))]
+    pub program: Program<'info, crate::program::Dexy>,
+    #[account(
+        constraint = program_data.upgrade_authority_address == Some(admin.key())
+            @ SwapError::Unauthorized,
+    )]
+    pub program_data: Account<'info, ProgramData>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    #[account(mut, seeds = [CONFIG_SEED], bump = config.bump_seed)]
+    pub config: Account<'info, GlobalConfig>,
+    /// CHECK: This is the config admin. The validation is handled in the instruction logic.
+    #[account(signer)]
+    pub admin: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptOwner<'info> {
+    #[account(mut)]
+    pub amm: Box<Account<'info, Amm>>,
+    /// CHECK: This is the proposed owner. The validation is handled in the instruction logic.
+    #[account(signer)]
+    pub pending_owner: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateAmm<'info> {
+    #[account(mut)]
+    pub amm: Box<Account<'info, Amm>>,
+    /// CHECK: This is the pool owner. The validation is handled in the instruction logic.
+    #[account(signer)]
+    pub owner: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct OpenPosition<'info> {
+    pub amm: Box<Account<'info, Amm>>,
+    /// CHECK: This is the owner of the position. The validation is handled in the instruction logic.
+    #[account(mut, signer)]
+    pub owner: AccountInfo<'info>,
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + Position::LEN,
+        seeds = [POSITION_SEED, amm.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub position: Account<'info, Position>,
+    pub pool_token_account: Account<'info, TokenAccount>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimPosition<'info> {
+    #[account(mut)]
+    pub position: Account<'info, Position>,
+    /// CHECK: This is the owner of the position. The validation is handled in the instruction logic.
+    #[account(signer)]
+    pub owner: AccountInfo<'info>,
+    #[account(mut)]
+    pub pool_token_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub destination: Account<'info, TokenAccount>,
+    /// CHECK: This is the Solana token program, which is a known, trusted program
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct BenchSwapMath<'info> {
+    pub amm: Box<Account<'info, Amm>>,
+}
+
+#[derive(Accounts)]
+pub struct GetPoolInfo<'info> {
+    pub amm: Box<Account<'info, Amm>>,
+}
+
+#[derive(Accounts)]
+pub struct GetPriceCumulatives<'info> {
+    pub amm: Box<Account<'info, Amm>>,
+    pub token_a: Account<'info, TokenAccount>,
+    pub token_b: Account<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct GetConfigHash<'info> {
+    pub amm: Box<Account<'info, Amm>>,
+}
+
+#[derive(Accounts)]
+pub struct GetAuthority<'info> {
+    pub amm: Box<Account<'info, Amm>>,
+}
+
+#[derive(Accounts)]
+pub struct GetFairLpValue<'info> {
+    pub amm: Box<Account<'info, Amm>>,
+    pub token_a: Account<'info, TokenAccount>,
+    pub token_b: Account<'info, TokenAccount>,
+    pub pool_mint: Account<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct DescribePool<'info> {
+    pub amm: Box<Account<'info, Amm>>,
+}
+
+#[derive(Accounts)]
+pub struct EmitPoolSnapshot<'info> {
+    pub amm: Box<Account<'info, Amm>>,
+    pub token_a: Account<'info, TokenAccount>,
+    pub token_b: Account<'info, TokenAccount>,
+    pub pool_mint: Account<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct GetPoolShare<'info> {
+    pub amm: Box<Account<'info, Amm>>,
+    pub token_a: Account<'info, TokenAccount>,
+    pub token_b: Account<'info, TokenAccount>,
+    pub pool_mint: Account<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeWeightedPool<'info> {
+    /// CHECK: This is the authority for the pool. The validation is handled in the instruction logic.
+    pub authority: AccountInfo<'info>,
+    /// CHECK: This is the initializer of the pool. The validation is handled in the instruction logic.
+    #[account(mut, signer)]
+    pub initializer: AccountInfo<'info>,
+    #[account(init, payer = initializer, space = 8 + WeightedPool::LEN)]
+    pub pool: Box<Account<'info, WeightedPool>>,
+    #[account(mut)]
+    pub pool_mint: Box<Account<'info, Mint>>,
+    pub fee_account: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub destination: Account<'info, TokenAccount>,
+    /// CHECK: This is the Solana token program, which is a known, trusted program
+    pub token_program: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SwapWeighted<'info> {
+    /// CHECK: This is the authority for the pool. The validation is handled in the instruction logic.
+    pub authority: AccountInfo<'info>,
+    pub pool: Box<Account<'info, WeightedPool>>,
+    /// CHECK: This is the user transfer authority. The validation is handled in the instruction logic.
+    #[account(signer)]
+    pub user_transfer_authority: AccountInfo<'info>,
+    /// CHECK: This is the source token account. The validation is handled in the instruction logic.
+    #[account(mut)]
+    pub source_info: AccountInfo<'info>,
+    /// CHECK: This is the destination token account. The validation is handled in the instruction logic.
+    #[account(mut)]
+    pub destination_info: AccountInfo<'info>,
+    #[account(mut, constraint = swap_source.key() != source_info.key() @ SwapError::InvalidInput)]
+    pub swap_source: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = swap_destination.key() != swap_source.key() @ SwapError::InvalidInput,
+        constraint = swap_destination.key() != destination_info.key() @ SwapError::InvalidInput,
+    )]
+    pub swap_destination: Account<'info, TokenAccount>,
+    #[account(mut, constraint = pool_mint.key() == pool.pool_mint @ SwapError::IncorrectPoolMint)]
+    pub pool_mint: Account<'info, Mint>,
+    #[account(
+        mut,
+        constraint = pool_account.key() == pool.pool_fee_account @ SwapError::IncorrectFeeAccount,
+    )]
+    pub pool_account: Account<'info, TokenAccount>,
+    /// CHECK: This is the Solana token program, which is a known, trusted program
+    #[account(
+        constraint = token_program.key() == pool.token_program_id
+            @ SwapError::IncorrectTokenProgramId,
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DepositWeighted<'info> {
+    /// CHECK: This is the authority for the pool. The validation is handled in the instruction logic.
+    pub authority: AccountInfo<'info>,
+    pub pool: Box<Account<'info, WeightedPool>>,
+    /// CHECK: This is the user transfer authority. The validation is handled in the instruction logic.
+    #[account(signer)]
+    pub user_transfer_authority: AccountInfo<'info>,
+    #[account(mut, constraint = pool_mint.key() == pool.pool_mint @ SwapError::IncorrectPoolMint)]
+    pub pool_mint: Account<'info, Mint>,
+    #[account(mut)]
+    pub destination: Account<'info, TokenAccount>,
+    /// CHECK: This is the Solana token program, which is a known, trusted program
+    #[account(
+        constraint = token_program.key() == pool.token_program_id
+            @ SwapError::IncorrectTokenProgramId,
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawWeighted<'info> {
+    /// CHECK: This is the authority for the pool. The validation is handled in the instruction logic.
+    pub authority: AccountInfo<'info>,
+    pub pool: Box<Account<'info, WeightedPool>>,
+    /// CHECK: This is the user transfer authority. The validation is handled in the instruction logic.
+    #[account(signer)]
+    pub user_transfer_authority: AccountInfo<'info>,
+    #[account(mut, constraint = pool_mint.key() == pool.pool_mint @ SwapError::IncorrectPoolMint)]
+    pub pool_mint: Account<'info, Mint>,
+    #[account(mut)]
+    pub source_pool_account: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = fee_account.key() == pool.pool_fee_account @ SwapError::IncorrectFeeAccount,
+    )]
+    pub fee_account: Account<'info, TokenAccount>,
+    /// CHECK: This is the Solana token program, which is a known, trusted program
+    #[account(
+        constraint = token_program.key() == pool.token_program_id
+            @ SwapError::IncorrectTokenProgramId,
+    )]
+    pub token_program: AccountInfo<'info>,
+}
+
+impl<'info> Swap<'info> {
+    /// Swaps `amount_in` from the source reserve's token into the destination
+    /// reserve's token and returns the amount sent to the user. Reserves and
+    /// the pool mint are reloaded afterwards. With `fee_free` set no fees are
+    /// charged. With `simulate` set it returns right after validation, leaving
+    /// the pool and the token accounts as is.
+    fn process_swap(
+        &mut self,
+        program_id: &Pubkey,
+        fee_recipient_infos: &[AccountInfo<'info>],
+        amount_in: u64,
+        minimum_amount_out: u64,
+        fee_free: bool,
+        simulate: bool,
+    ) -> Result<u64> {
+        if amount_in == 0 {
+            return Err(SwapError::InvalidInput.into());
+        }
+
+        if minimum_amount_out > self.swap_destination.amount {
+            return Err(SwapError::InvalidInput.into());
+        }
+
+        let amm = &mut self.amm;
+        if amm.to_account_info().owner != program_id {
+            return Err(SwapError::InvalidOwner.into());
+        }
+
+        amm.validate_not_in_progress()?;
+
+        if *self.authority.key
+            != authority_key(program_id, amm.to_account_info().key(), amm.bump_seed)?
+        {
+            return Err(SwapError::InvalidProgramAddress.into());
+        }
+
+        validate_mint_authority(&self.pool_mint, self.authority.key)?;
+
+        // A host fee account set to the system program stands for no host, for
+        // clients that can't leave the account out
+        let host_fee_account = self
+            .host_fee_account
+            .clone()
+            .filter(|account| *account.key != system_program::ID);
+        match (&self.host_position, &host_fee_account) {
+            (Some(position), Some(host_fee_account)) => {
+                if position.amm != amm.key() || *host_fee_account.key != position.pool_token_account
+                {
+                    return Err(SwapError::IncorrectFeeAccount.into());
+                }
+            }
+            (Some(_), None) => {
+                return Err(SwapError::IncorrectFeeAccount.into());
+            }
+            (None, Some(host_fee_account)) => {
+                if *host_fee_account.key != amm.pool_fee_account {
+                    return Err(SwapError::IncorrectFeeAccount.into());
+                }
+            }
+            (None, None) => {}
+        }
+
+        // Without a host, host fees go to the pool's default host if it has one
+        let host_fee_account = match host_fee_account {
+            None if amm.default_host_fee_account != Pubkey::default() => {
+                let account = self
+                    .default_host_fee_account
+                    .clone()
+                    .ok_or(SwapError::IncorrectFeeAccount)?;
+                if *account.key != amm.default_host_fee_account {
+                    return Err(SwapError::IncorrectFeeAccount.into());
+                }
+                Some(account)
+            }
+            host_fee_account => host_fee_account,
+        };
+
+        if self.swap_source.is_frozen() || self.swap_destination.is_frozen() {
+            return Err(SwapError::ReserveFrozen.into());
+        }
+
+        let trade_direction = if *self.swap_source.to_account_info().key == amm.token_a_account {
+            TradeDirection::AtoB
+        } else {
+            TradeDirection::BtoA
+        };
+
+        let curve = amm.swap_curve()?;
+        let fees = if fee_free {
+            CurveFees::default()
+        } else {
+            amm.swap_fees(Clock::get()?.unix_timestamp)?
+        };
+
+        let (token_a_amount, token_b_amount) = match trade_direction {
+            TradeDirection::AtoB => (self.swap_source.amount, self.swap_destination.amount),
+            TradeDirection::BtoA => (self.swap_destination.amount, self.swap_source.amount),
+        };
+
+        amm.validate_pool_value(&curve, token_a_amount, token_b_amount)?;
+        amm.validate_reserve_balances(token_a_amount, token_b_amount)?;
+
+        let SwapAmounts {
+            output_amount,
+            trade_fee,
+            owner_fee,
+            host_fee,
+        } = compute_swap(
+            amount_in,
+            self.swap_source.amount,
+            self.swap_destination.amount,
+            &fees,
+            &curve,
+            trade_direction,
+        )?;
+
+        // Without a host account the host's share of the fee stays with the owner
+        let (owner_fee, host_fee) = if host_fee_account.is_none() {
+            let owner_fee = owner_fee
+                .checked_add(host_fee)
+                .ok_or(SwapError::FeeCalculationFailure)?;
+            (owner_fee, 0)
+        } else {
+            (owner_fee, host_fee)
+        };
+
+        if output_amount < minimum_amount_out {
+            return Err(SwapError::ExceededSlippage.into());
+        }
+
+        amm.validate_price_impact(
+            &curve,
+            amount_in,
+            output_amount,
+            token_a_amount,
+            token_b_amount,
+            trade_direction,
+        )?;
+
+        amm.validate_swap_size(output_amount, self.swap_destination.amount)?;
+
+        if simulate {
+            return Ok(output_amount);
+        }
+
+        amm.update_price_cumulatives(token_a_amount, token_b_amount)?;
+
+        amm.cumulative_trade_fee = amm
+            .cumulative_trade_fee
+            .checked_add(trade_fee)
+            .ok_or(SwapError::FeeCalculationFailure)?;
+        amm.cumulative_owner_fee = amm
+            .cumulative_owner_fee
+            .checked_add(owner_fee)
+            .ok_or(SwapError::FeeCalculationFailure)?;
+
+        match trade_direction {
+            TradeDirection::AtoB => {
+                amm.volume_a_in = amm
+                    .volume_a_in
+                    .checked_add(u128::from(amount_in))
+                    .ok_or(SwapError::ConversionFailure)?;
+            }
+            TradeDirection::BtoA => {
+                amm.volume_b_in = amm
+                    .volume_b_in
+                    .checked_add(u128::from(amount_in))
+                    .ok_or(SwapError::ConversionFailure)?;
+            }
+        }
+
+        amm.record_reserve_change(self.swap_source.to_account_info().key, amount_in, 0)?;
+        amm.record_reserve_change(
+            self.swap_destination.to_account_info().key,
+            0,
+            output_amount,
+        )?;
+
+        let seeds = &[
+            &amm.to_account_info().key().to_bytes(),
+            &[amm.bump_seed][..],
+        ];
+
+        if let Some(wsol_account) = &self.wsol_account {
+            if wsol_account.key != self.source_info.key || self.swap_source.mint != native_mint::ID
+            {
+                return Err(SwapError::InvalidInput.into());
+            }
+            wrap_sol(
+                program_id,
+                &self.user_transfer_authority,
+                wsol_account,
+                &self
+                    .native_mint
+                    .as_ref()
+                    .ok_or(SwapError::InvalidInput)?
+                    .to_account_info(),
+                &self
+                    .system_program
+                    .as_ref()
+                    .ok_or(SwapError::InvalidInput)?
+                    .to_account_info(),
+                &self.token_program,
+                amount_in,
+            )?;
+        }
+
+        token::transfer(
+            CpiContext::new(
+                self.token_program.clone(),
+                token::Transfer {
+                    from: self.source_info.clone(),
+                    to: self.swap_source.to_account_info().clone(),
+                    authority: self.user_transfer_authority.clone(),
+                },
+            ),
+            amount_in,
+        )?;
+
+        token::transfer(
+            CpiContext::new_with_signer(
+                self.token_program.clone(),
+                token::Transfer {
+                    from: self.swap_destination.to_account_info().clone(),
+                    to: self.destination_info.clone(),
+                    authority: self.authority.clone(),
+                },
+                &[&seeds[..]],
+            ),
+            output_amount,
+        )?;
+
+        // Pools leaving every fee to liquidity providers have no owner or host
+        // fee to mint pool tokens for, and skip the fee accounting. Without
+        // pool tokens outstanding there is no share to mint the fees as
+        // either, so they stay in the reserves for the next depositors.
+        let lp_fees_only = owner_fee == 0 && host_fee == 0;
+        let mint_fees = !lp_fees_only && self.pool_mint.supply > 0;
+
+        // The owner and host fees are taken from the output, so they are
+        // minted as a deposit of destination tokens into the reserves as they
+        // stand after the swap, and checked against the pool's value in
+        // destination tokens
+        let swap_source_amount = u128::from(self.swap_source.amount)
+            .checked_add(u128::from(amount_in))
+            .ok_or(SwapError::FeeCalculationFailure)?;
+        let swap_destination_amount = u128::from(self.swap_destination.amount)
+            .checked_sub(u128::from(output_amount))
+            .ok_or(SwapError::FeeCalculationFailure)?;
+        let (fee_token_a_amount, fee_token_b_amount, fee_direction) = match trade_direction {
+            TradeDirection::AtoB => (
+                swap_source_amount,
+                swap_destination_amount,
+                TradeDirection::BtoA,
+            ),
+            TradeDirection::BtoA => (
+                swap_destination_amount,
+                swap_source_amount,
+                TradeDirection::AtoB,
+            ),
+        };
+        let fee_pool_value = if mint_fees {
+            pool_value(
+                &curve,
+                fee_token_a_amount,
+                fee_token_b_amount,
+                trade_direction,
+            )?
+        } else {
+            0
+        };
+
+        let mint_pool_tokens = |to: &AccountInfo<'info>, amount: u64| {
+            token::mint_to(
+                CpiContext::new_with_signer(
+                    self.token_program.clone(),
+                    MintTo {
+                        mint: self.pool_mint.to_account_info().clone(),
+                        to: to.clone(),
+                        authority: self.authority.clone(),
+                    },
+                    &[&seeds[..]],
+                ),
+                amount,
+            )
+        };
+
+        if owner_fee > 0 && mint_fees {
+            let fee_recipients = self.amm.fee_recipients();
+            let pool_mint_amount = curve
+                .calculator
+                .deposit_single_token_type(
+                    owner_fee,
+                    fee_token_a_amount,
+                    fee_token_b_amount,
+                    u128::from(self.pool_mint.supply),
+                    fee_direction,
+                    RoundDirection::Floor,
+                )
+                .ok_or(SwapError::ZeroOwnerTradeFee)?;
+            validate_fee_mint(
+                owner_fee,
+                pool_mint_amount,
+                fee_pool_value,
+                self.pool_mint.supply,
+            )?;
+
+            mint_owner_fee(
+                pool_mint_amount,
+                &self.pool_account.to_account_info(),
+                &fee_recipients,
+                fee_recipient_infos,
+                mint_pool_tokens,
+            )?;
+        }
+
+        if host_fee > 0 && mint_fees {
+            let host_fee_account = host_fee_account
+                .as_ref()
+                .ok_or(SwapError::IncorrectFeeAccount)?;
+            let host_fee_mint_amount = curve
+                .calculator
+                .deposit_single_token_type(
+                    host_fee,
+                    fee_token_a_amount,
+                    fee_token_b_amount,
+                    u128::from(self.pool_mint.supply),
+                    fee_direction,
+                    RoundDirection::Floor,
+                )
+                .ok_or(SwapError::ZeroHostFee)?;
+            validate_fee_mint(
+                host_fee,
+                host_fee_mint_amount,
+                fee_pool_value,
+                self.pool_mint.supply,
+            )?;
+
+            mint_pool_tokens(host_fee_account, to_u64(host_fee_mint_amount)?)?;
+
+            if let Some(position) = &mut self.host_position {
+                position.pool_token_amount = position
+                    .pool_token_amount
+                    .checked_add(to_u64(host_fee_mint_amount)?)
+                    .ok_or(SwapError::FeeCalculationFailure)?;
+            }
+        }
+
+        if let Some(wsol_account) = &self.wsol_account {
+            unwrap_sol(
+                &self.user_transfer_authority,
+                wsol_account,
+                &self.token_program,
+            )?;
+        }
+
+        self.swap_source.reload()?;
+        self.swap_destination.reload()?;
+        if mint_fees {
+            self.pool_mint.reload()?;
+        }
+
+        Ok(output_amount)
+    }
+}
+
+impl<'info> DepositLiquidity<'info> {
+    /// Checks shared by all deposits, returning the pool curve. Also
+    /// accumulates prices.
+    fn validate_deposit(&mut self, program_id: &Pubkey) -> Result<SwapCurve> {
+        let amm = &mut self.amm;
+
+        if !amm.is_initialized {
+            return Err(SwapError::NotInitialized.into());
+        }
+
+        amm.validate_not_in_progress()?;
+
+        if *self.authority.key
+            != authority_key(program_id, amm.to_account_info().key(), amm.bump_seed)?
+        {
+            return Err(SwapError::InvalidProgramAddress.into());
+        }
+
+        validate_reserves(amm, &self.token_a, &self.token_b)?;
+
+        if self.user_token_a.mint == self.user_token_b.mint {
+            return Err(SwapError::RepeatedMint.into());
+        }
+        if self.user_token_a.mint != amm.token_a_mint || self.user_token_b.mint != amm.token_b_mint
+        {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        }
+
+        if *self.pool_mint.to_account_info().key != amm.pool_mint {
+            return Err(SwapError::IncorrectPoolMint.into());
+        }
+
+        if *self.token_program.key != amm.token_program_id {
+            return Err(SwapError::IncorrectTokenProgramId.into());
+        }
+
+        validate_mint_authority(&self.pool_mint, self.authority.key)?;
+
+        // Without the position the deposit isn't recorded, and its pool
+        // tokens would pay the full exit fee however long they are held
+        match &self.position {
+            Some(position) => {
+                if position.amm != amm.key() || position.owner != *self.user_transfer_authority.key
+                {
+                    return Err(SwapError::Unauthorized.into());
+                }
+            }
+            None => {
+                if amm.exit_fee_bps > 0 {
+                    return Err(SwapError::PositionRequired.into());
+                }
+            }
+        }
+
+        let curve = amm.swap_curve()?;
+
+        if !amm.deposits_enabled || !curve.calculator.allow_deposits() {
+            return Err(SwapError::DepositsDisabled.into());
+        }
+
+        amm.update_price_cumulatives(self.token_a.amount, self.token_b.amount)?;
+
+        Ok(curve)
+    }
+}
+
+impl<'info> DepositLiquidity<'info> {
+    /// Records `pool_token_amount` minted by the deposit on the position, if
+    /// one is passed.
+    fn record_position_deposit(&mut self, pool_token_amount: u64) -> Result<()> {
+        if let Some(position) = &mut self.position {
+            position
+                .record_deposit(pool_token_amount, Clock::get()?.unix_timestamp)
+                .ok_or(SwapError::ConversionFailure)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'info> WithdrawLiquidity<'info> {
+    /// Burns `pool_token_amount` for its share of the reserves, less the
+    /// withdraw fee. With `sweep`, the whole supply is burned for the whole
+    /// reserves, see `sweep_liquidity`.
+    fn process_withdraw(
+        &mut self,
+        program_id: &Pubkey,
+        pool_token_amount: u64,
+        minimum_token_a_amount: u64,
+        minimum_token_b_amount: u64,
+        sweep: bool,
+    ) -> Result<()> {
+        let amm = &mut self.amm;
+
+        if !amm.is_initialized {
+            return Err(SwapError::NotInitialized.into());
+        }
+
+        amm.validate_not_in_progress()?;
+
+        if *self.authority.key
+            != authority_key(program_id, amm.to_account_info().key(), amm.bump_seed)?
+        {
+            return Err(SwapError::InvalidProgramAddress.into());
+        }
+
+        validate_reserves(amm, &self.token_a, &self.token_b)?;
+
+        if *self.pool_mint.to_account_info().key != amm.pool_mint {
+            return Err(SwapError::IncorrectPoolMint.into());
+        }
+
+        if *self.token_program.key != amm.token_program_id {
+            return Err(SwapError::IncorrectTokenProgramId.into());
+        }
+
+        validate_mint_authority(&self.pool_mint, self.authority.key)?;
+
+        if *self.fee_account.to_account_info().key != amm.pool_fee_account
+            || self.fee_account.mint != amm.pool_mint
+        {
+            return Err(SwapError::IncorrectFeeAccount.into());
+        }
+
+        if self.pool_mint.supply == 0 {
+            return Err(SwapError::EmptySupply.into());
+        }
+
+        if pool_token_amount > self.source_pool_account.amount {
+            return Err(SwapError::InsufficientPoolTokens.into());
+        }
+
+        amm.update_price_cumulatives(self.token_a.amount, self.token_b.amount)?;
+
+        let (withdraw_fee, token_a_amount, token_b_amount) =
+            self.withdraw_amounts(pool_token_amount, sweep)?;
+        if let Some(position) = &mut self.position {
+            position.deposited_pool_tokens = position
+                .deposited_pool_tokens
+                .saturating_sub(pool_token_amount);
+        }
+        let amm = &mut self.amm;
+
+        if token_a_amount < minimum_token_a_amount {
+            return Err(SwapError::ExceededSlippage.into());
+        }
+        if token_b_amount < minimum_token_b_amount {
+            return Err(SwapError::ExceededSlippage.into());
+        }
+
+        let seeds = &[
+            &amm.to_account_info().key().to_bytes(),
+            &[amm.bump_seed][..],
+        ];
+
+        token::burn(
+            CpiContext::new(
+                self.token_program.clone(),
+                token::Burn {
+                    mint: self.pool_mint.to_account_info().clone(),
+                    from: self.source_pool_account.to_account_info().clone(),
+                    authority: self.user_transfer_authority.clone(),
+                },
+            ),
+            pool_token_amount,
+        )?;
+
+        if withdraw_fee > 0 {
+            token::mint_to(
+                CpiContext::new_with_signer(
+                    self.token_program.clone(),
+                    MintTo {
+                        mint: self.pool_mint.to_account_info().clone(),
+                        to: self.fee_account.to_account_info().clone(),
+                        authority: self.authority.clone(),
+                    },
+                    &[&seeds[..]],
+                ),
+                to_u64(withdraw_fee)?,
+            )?;
+        }
+
+        token::transfer(
+            CpiContext::new_with_signer(
+                self.token_program.clone(),
+                Transfer {
+                    from: self.token_a.to_account_info().clone(),
+                    to: self.user_token_a.to_account_info().clone(),
+                    authority: self.authority.clone(),
+                },
+                &[&seeds[..]],
+            ),
+            token_a_amount,
+        )?;
+
+        token::transfer(
+            CpiContext::new_with_signer(
+                self.token_program.clone(),
+                Transfer {
+                    from: self.token_b.to_account_info().clone(),
+                    to: self.user_token_b.to_account_info().clone(),
+                    authority: self.authority.clone(),
+                },
+                &[&seeds[..]],
+            ),
+            token_b_amount,
+        )?;
+
+        let (token_a_account, token_b_account) = (amm.token_a_account, amm.token_b_account);
+        amm.record_reserve_change(&token_a_account, 0, token_a_amount)?;
+        amm.record_reserve_change(&token_b_account, 0, token_b_amount)?;
+
+        let pool_token_supply_before = self.pool_mint.supply;
+        self.pool_mint.reload()?;
+
+        emit!(WithdrawEvent {
+            amm: amm.key(),
+            user: *self.user_transfer_authority.key,
+            pool_token_amount,
+            token_a_amount,
+            token_b_amount,
+            pool_token_supply_before,
+            pool_token_supply_after: self.pool_mint.supply,
+        });
+
+        Ok(())
+    }
+
+    /// Withdraw fee on `pool_token_amount` and the tokens A and B paid out
+    /// for the pool tokens left after it. A `sweep` of the whole supply pays
+    /// out the whole reserves.
+    fn withdraw_amounts(&self, pool_token_amount: u64, sweep: bool) -> Result<(u128, u64, u64)> {
+        let curve = self.amm.swap_curve()?;
+        let fees = self.amm.curve_fees()?;
+
+        // Only pool tokens the position records as deposited see the exit fee
+        // decay, the rest may have been deposited by anyone at any time
+        let (deposited_pool_tokens, deposit_ts) = match &self.position {
+            Some(position) => {
+                if position.amm != self.amm.key()
+                    || position.owner != *self.user_transfer_authority.key
+                {
+                    return Err(SwapError::Unauthorized.into());
+                }
+                (position.deposited_pool_tokens, position.deposit_ts)
+            }
+            None => (0, 0),
+        };
+
+        if sweep && pool_token_amount != self.pool_mint.supply {
+            return Err(SwapError::InvalidInput.into());
+        }
+
+        let withdraw_fee = self
+            .amm
+            .withdraw_fee(
+                &fees,
+                u128::from(pool_token_amount),
+                u128::from(deposited_pool_tokens.min(pool_token_amount)),
+                deposit_ts,
+                Clock::get()?.unix_timestamp,
+            )
+            .ok_or(SwapError::FeeCalculationFailure)?;
+
+        // A fee would be minted as pool tokens backed by the emptied reserves
+        if sweep && withdraw_fee > 0 {
+            return Err(SwapError::WithdrawFeeDue.into());
+        }
+
+        let pool_token_amount_after_fee = u128::from(pool_token_amount)
+            .checked_sub(withdraw_fee)
+            .ok_or(SwapError::FeeCalculationFailure)?;
+
+        let (token_a_amount, token_b_amount) = if sweep {
+            (self.token_a.amount, self.token_b.amount)
+        } else {
+            let tokens = curve
+                .calculator
+                .pool_tokens_to_trading_tokens(
+                    pool_token_amount_after_fee,
+                    u128::from(self.pool_mint.supply),
+                    u128::from(self.token_a.amount),
+                    u128::from(self.token_b.amount),
+                    RoundDirection::Floor,
+                )
+                .ok_or(SwapError::ZeroTradingTokens)?;
+            (
+                to_u64(tokens.token_a_amount)?,
+                to_u64(tokens.token_b_amount)?,
+            )
+        };
+
+        Ok((withdraw_fee, token_a_amount, token_b_amount))
+    }
+}
+
+impl<'info> Initialize<'info> {
+    fn process_initialize(
+        &mut self,
+        program_id: &Pubkey,
+        fees_input: FeeInput,
+        curve_input: CurveInput,
+        min_pool_value: u64,
+        max_price_impact_bps: u16,
+    ) -> Result<()> {
+        if max_price_impact_bps > BASIS_POINTS {
+            return Err(SwapError::InvalidInput.into());
+        }
+
+        if let Some(config) = &self.config {
+            if !config.allowlist.contains(self.initializer.key) {
+                return Err(SwapError::InitializerNotAllowlisted.into());
+            }
+        }
+
+        let (swap_authority, bump_seed) =
+            Amm::authority(self.amm.to_account_info().key, program_id);
+        let curve = self.validate_amm_fees_and_curve(&fees_input, &curve_input)?;
+        let _ = &self.validate_input_accounts(swap_authority)?;
+        let _ = &mut self.mint_create_state_account(
+            bump_seed,
+            curve_input,
+            fees_input,
+            min_pool_value,
+            max_price_impact_bps,
+            &curve,
+        )?;
+
+        Ok(())
+    }
+
+    fn fund_reserves(&mut self, token_a_amount: u64, token_b_amount: u64) -> Result<()> {
+        let (Some(initializer_token_a), Some(initializer_token_b)) =
+            (&self.initializer_token_a, &self.initializer_token_b)
+        else {
+            return Err(SwapError::InvalidInput.into());
+        };
+
+        // The seeded amounts must be the whole initial reserves
+        if self.token_a.amount != 0 || self.token_b.amount != 0 {
+            return Err(SwapError::InvalidSupply.into());
+        }
+
+        for (source, destination, amount) in [
+            (initializer_token_a, &self.token_a, token_a_amount),
+            (initializer_token_b, &self.token_b, token_b_amount),
+        ] {
+            token::transfer(
+                CpiContext::new(
+                    self.token_program.clone(),
+                    token::Transfer {
+                        from: source.to_account_info().clone(),
+                        to: destination.to_account_info().clone(),
+                        authority: self.initializer.clone(),
+                    },
+                ),
+                amount,
+            )?;
+        }
+
+        self.token_a.reload()?;
+        self.token_b.reload()?;
+
+        Ok(())
+    }
+
+    fn validate_input_accounts(&self, swap_authority: Pubkey) -> Result<()> {
+        if self.amm.is_initialized {
+            return Err(SwapError::AlreadyInUse.into());
+        }
+
+        if *self.authority.key != swap_authority {
+            return Err(SwapError::InvalidProgramAddress.into());
+        }
+        if *self.authority.key != self.token_a.owner || *self.authority.key != self.token_b.owner {
+            return Err(SwapError::InvalidOwner.into());
+        }
+
+        if *self.authority.key == self.fee_account.owner
+            && *self.authority.key == self.destination.owner
+        {
+            return Err(SwapError::InvalidOuputOwner.into());
+        }
+        if COption::Some(*self.authority.key) != self.pool_mint.mint_authority {
+            return Err(SwapError::InvalidOwner.into());
+        }
+        if self.token_a.mint == self.token_b.mint {
+            return Err(SwapError::RepeatedMint.into());
+        }
+
+        if self.token_a.mint != self.token_a_mint.key()
+            || self.token_b.mint != self.token_b_mint.key()
+        {
+            return Err(SwapError::IncorrectMint.into());
+        }
+
+        if self.token_a.delegate.is_some() || self.token_b.delegate.is_some() {
+            return Err(SwapError::InvalidDelegate.into());
+        }
+
+        if self.token_a.close_authority.is_some() || self.token_b.close_authority.is_some() {
+            return Err(SwapError::InvalidCloseAuthority.into());
+        }
+
+        if self.pool_mint.supply != 0 {
+            return Err(SwapError::InvalidSupply.into());
+        }
+
+        if self.pool_mint.freeze_authority.is_some() {
+            return Err(SwapError::InvalidFreezeAuthority.into());
+        }
+
+        if *self.pool_mint.to_account_info().key != self.fee_account.mint
+            || *self.pool_mint.to_account_info().key != self.destination.mint
+        {
+            return Err(SwapError::IncorrectPoolMint.into());
+        }
+
+        let rent = Rent::get()?;
+        for account in [
+            self.token_a.to_account_info(),
+            self.token_b.to_account_info(),
+            self.fee_account.to_account_info(),
+            self.destination.to_account_info(),
+        ] {
+            if account.owner != self.token_program.key {
+                return Err(SwapError::IncorrectTokenProgramId.into());
+            }
+            if !rent.is_exempt(account.lamports(), account.data_len()) {
+                return Err(SwapError::NotRentExempt.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn mint_create_state_account(
+        &mut self,
+        bump_seed: u8,
+        curve_input: CurveInput,
+        fee_input: FeeInput,
+        min_pool_value: u64,
+        max_price_impact_bps: u16,
+        curve: &SwapCurve,
+    ) -> Result<()> {
+        let seeds = &[
+            &self.amm.to_account_info().key().to_bytes(),
+            &[bump_seed][..],
+        ];
+
+        let initial_ammount = curve.calculator.new_supply_pool(
+            u128::from(self.token_a.amount),
+            u128::from(self.token_b.amount),
+        );
+
+        let mint_initial_amt_cpi_ctx = CpiContext::new(
+            self.token_program.clone(),
+            MintTo {
+                mint: self.pool_mint.to_account_info().clone(),
+                to: self.destination.to_account_info().clone(),
+                authority: self.authority.clone(),
+            },
+        );
+
+        token::mint_to(
+            mint_initial_amt_cpi_ctx.with_signer(&[&seeds[..]]),
+            to_u64(initial_ammount)?,
+        )?;
+
+        // Token A is the mint with the lower key, so a pair always gets the
+        // same orientation whichever order its reserves are passed in
+        let (token_a, token_b, token_a_mint, token_b_mint) =
+            if self.token_a.mint <= self.token_b.mint {
+                (
+                    &self.token_a,
+                    &self.token_b,
+                    &self.token_a_mint,
+                    &self.token_b_mint,
+                )
+            } else {
+                (
+                    &self.token_b,
+                    &self.token_a,
+                    &self.token_b_mint,
+                    &self.token_a_mint,
+                )
+            };
+
+        let amm = &mut self.amm;
+        amm.is_initialized = true;
+        amm.bump_seed = bump_seed;
+        amm.owner = *self.initializer.key;
+        amm.fee_owner = self.fee_account.owner;
+        amm.token_program_id = *self.token_program.key;
+        amm.token_a_account = *token_a.to_account_info().key;
+        amm.token_b_account = *token_b.to_account_info().key;
+        amm.pool_mint = *self.pool_mint.to_account_info().key;
+        amm.token_a_mint = token_a.mint;
+        amm.token_b_mint = token_b.mint;
+        amm.token_a_decimals = token_a_mint.decimals;
+        amm.token_b_decimals = token_b_mint.decimals;
+        amm.pool_mint_decimals = self.pool_mint.decimals;
+        amm.reserve_a = token_a.amount;
+        amm.reserve_b = token_b.amount;
+        amm.deposits_enabled = true;
+        amm.pool_fee_account = *self.fee_account.to_account_info().key;
+        amm.fees = fee_input;
+        amm.curve = curve_input;
+        amm.min_pool_value = min_pool_value;
+        amm.max_price_impact_bps = max_price_impact_bps;
+        let clock = Clock::get()?;
+        amm.last_update_ts = clock.unix_timestamp;
+        amm.last_update_slot = clock.slot;
+
+        Ok(())
+    }
+
+    fn validate_amm_fees_and_curve(
+        &self,
+        fees_input: &FeeInput,
+        curve_input: &CurveInput,
+    ) -> Result<SwapCurve> {
+        let curve = build_curve(curve_input)?;
+        curve
+            .calculator
+            .validate_supply(self.token_a.amount, self.token_b.amount)?;
+
+        // The other curves' parameters refer to token B, so their tokens
+        // can't be reordered and must be passed already sorted
+        if curve.curve_type != CurveType::ConstantProduct && self.token_a.mint > self.token_b.mint {
+            return Err(SwapError::UnsortedMints.into());
+        }
+
+        let fees = build_fees(fees_input)?;
+        fees.validate()?;
+        fees.validate_total_trade_fee()?;
+        curve.calculator.validate()?;
+        Ok(curve)
+    }
+}
+
+impl<'info> InitializeWeightedPool<'info> {
+    fn process_initialize(
+        &mut self,
+        program_id: &Pubkey,
+        reserve_infos: &[AccountInfo<'info>],
+        fees_input: FeeInput,
+        weights: Vec<u64>,
+    ) -> Result<()> {
+        if self.pool.is_initialized {
+            return Err(SwapError::AlreadyInUse.into());
+        }
+
+        if weights.len() < 2
+            || weights.len() > MAX_WEIGHTED_TOKENS
+            || weights.len() != reserve_infos.len()
+        {
+            return Err(SwapError::InvalidInput.into());
+        }
+        if weights
+            .iter()
+            .any(|weight| *weight == 0 || *weight > MAX_WEIGHT)
+        {
+            return Err(SwapError::InvalidCurve.into());
+        }
+
+        let fees = build_fees(&fees_input)?;
+        fees.validate()?;
+        fees.validate_total_trade_fee()?;
+
+        let (pool_authority, bump_seed) =
+            WeightedPool::authority(self.pool.to_account_info().key, program_id);
+        if *self.authority.key != pool_authority {
+            return Err(SwapError::InvalidProgramAddress.into());
+        }
+
+        if COption::Some(*self.authority.key) != self.pool_mint.mint_authority {
+            return Err(SwapError::InvalidOwner.into());
+        }
+        if self.pool_mint.supply != 0 {
+            return Err(SwapError::InvalidSupply.into());
+        }
+        if self.pool_mint.freeze_authority.is_some() {
+            return Err(SwapError::InvalidFreezeAuthority.into());
+        }
+        if *self.pool_mint.to_account_info().key != self.fee_account.mint
+            || *self.pool_mint.to_account_info().key != self.destination.mint
+        {
+            return Err(SwapError::IncorrectPoolMint.into());
+        }
+        if *self.authority.key == self.fee_account.owner
+            && *self.authority.key == self.destination.owner
+        {
+            return Err(SwapError::InvalidOuputOwner.into());
+        }
+
+        let rent = Rent::get()?;
+        let mut reserves: Vec<WeightedReserve> = Vec::with_capacity(weights.len());
+        for (info, weight) in reserve_infos.iter().zip(weights) {
+            if info.owner != self.token_program.key {
+                return Err(SwapError::IncorrectTokenProgramId.into());
+            }
+            if !rent.is_exempt(info.lamports(), info.data_len()) {
+                return Err(SwapError::NotRentExempt.into());
+            }
+            let reserve = TokenAccount::try_deserialize(&mut &info.try_borrow_data()?[..])?;
+            if reserve.owner != *self.authority.key {
+                return Err(SwapError::InvalidOwner.into());
+            }
+            if reserve.delegate.is_some() {
+                return Err(SwapError::InvalidDelegate.into());
+            }
+            if reserve.close_authority.is_some() {
+                return Err(SwapError::InvalidCloseAuthority.into());
+            }
+            if reserve.amount == 0 {
+                return Err(SwapError::EmptySupply.into());
+            }
+            if reserves.iter().any(|other| other.mint == reserve.mint) {
+                return Err(SwapError::RepeatedMint.into());
+            }
+            reserves.push(WeightedReserve {
+                account: *info.key,
+                mint: reserve.mint,
+                weight,
+            });
+        }
+
+        let seeds = &[
+            &self.pool.to_account_info().key().to_bytes(),
+            &[bump_seed][..],
+        ];
+
+        token::mint_to(
+            CpiContext::new_with_signer(
+                self.token_program.clone(),
+                MintTo {
+                    mint: self.pool_mint.to_account_info().clone(),
+                    to: self.destination.to_account_info().clone(),
+                    authority: self.authority.clone(),
+                },
+                &[&seeds[..]],
+            ),
+            to_u64(INITIAL_SWAP_POOL_AMOUNT)?,
+        )?;
+
+        let pool = &mut self.pool;
+        pool.is_initialized = true;
+        pool.bump_seed = bump_seed;
+        pool.owner = *self.initializer.key;
+        pool.token_program_id = *self.token_program.key;
+        pool.pool_mint = *self.pool_mint.to_account_info().key;
+        pool.pool_fee_account = *self.fee_account.to_account_info().key;
+        pool.fees = fees_input;
+        pool.reserves = reserves;
+
+        Ok(())
+    }
+}
+
+impl<'info> SwapWeighted<'info> {
+    fn process_swap(
+        &self,
+        program_id: &Pubkey,
+        amount_in: u64,
+        minimum_amount_out: u64,
+    ) -> Result<()> {
+        if amount_in == 0 {
+            return Err(SwapError::InvalidInput.into());
+        }
+
+        let pool = &self.pool;
+        if !pool.is_initialized {
+            return Err(SwapError::NotInitialized.into());
+        }
+
+        if *self.authority.key != authority_key(program_id, pool.key(), pool.bump_seed)? {
+            return Err(SwapError::InvalidProgramAddress.into());
+        }
+
+        validate_mint_authority(&self.pool_mint, self.authority.key)?;
+
+        let source_weight =
+            pool.reserves[pool.reserve_index(self.swap_source.to_account_info().key)?].weight;
+        let destination_weight =
+            pool.reserves[pool.reserve_index(self.swap_destination.to_account_info().key)?].weight;
+
+        if self.swap_source.is_frozen() || self.swap_destination.is_frozen() {
+            return Err(SwapError::ReserveFrozen.into());
+        }
+
+        if u128::from(amount_in) > u128::from(self.swap_source.amount) / MAX_IN_RATIO_DENOMINATOR {
+            return Err(SwapError::SwapTooLarge.into());
+        }
+
+        // Unlike `Amm`, which takes its fees from the output, weighted pools
+        // take them from the input. The trade fee stays in the source reserve
+        // and the owner fee is minted as pool tokens. Without host accounts,
+        // the host's share of the owner fee stays with the owner.
+        let fees = pool.curve_fees()?;
+        let trade_fee = fees
+            .trading_fee(u128::from(amount_in))
+            .ok_or(SwapError::FeeCalculationFailure)?;
+        let owner_fee = fees
+            .owner_trading_fee(u128::from(amount_in))
+            .ok_or(SwapError::FeeCalculationFailure)?;
+        let source_amount_less_fees = u128::from(amount_in)
+            .checked_sub(trade_fee)
+            .and_then(|amount| amount.checked_sub(owner_fee))
+            .ok_or(SwapError::FeeCalculationFailure)?;
+
+        let output_amount = WeightedCurve
+            .swap(
+                source_amount_less_fees,
+                u128::from(self.swap_source.amount),
+                source_weight,
+                u128::from(self.swap_destination.amount),
+                destination_weight,
+            )
+            .ok_or(SwapError::ZeroTradingTokens)?
+            .destination_amount_swapped;
+        let output_amount = to_u64(output_amount)?;
+        if output_amount < minimum_amount_out {
+            return Err(SwapError::ExceededSlippage.into());
+        }
+
+        let seeds = &[&pool.key().to_bytes(), &[pool.bump_seed][..]];
+
+        token::transfer(
+            CpiContext::new(
+                self.token_program.clone(),
+                Transfer {
+                    from: self.source_info.clone(),
+                    to: self.swap_source.to_account_info().clone(),
+                    authority: self.user_transfer_authority.clone(),
+                },
+            ),
+            amount_in,
+        )?;
+
+        token::transfer(
+            CpiContext::new_with_signer(
+                self.token_program.clone(),
+                Transfer {
+                    from: self.swap_destination.to_account_info().clone(),
+                    to: self.destination_info.clone(),
+                    authority: self.authority.clone(),
+                },
+                &[&seeds[..]],
+            ),
+            output_amount,
+        )?;
+
+        // As in `Amm` swaps, there is no share to mint the owner fee as
+        // without pool tokens outstanding, so it stays in the reserve
+        if owner_fee > 0 && self.pool_mint.supply > 0 {
+            // The owner fee is deposited into the source reserve as it stands
+            // after the rest of the swap
+            let swap_source_amount = u128::from(self.swap_source.amount)
+                .checked_add(u128::from(amount_in))
+                .and_then(|amount| amount.checked_sub(owner_fee))
+                .ok_or(SwapError::FeeCalculationFailure)?;
+            let pool_mint_amount = WeightedCurve
+                .deposit_single_token_type(
+                    owner_fee,
+                    swap_source_amount,
+                    source_weight,
+                    pool.total_weight(),
+                    u128::from(self.pool_mint.supply),
+                )
+                .ok_or(SwapError::ZeroOwnerTradeFee)?;
+            // Priced by the weights, the whole pool is worth the source
+            // reserve scaled up by the source token's share of the weight
+            let pool_value = swap_source_amount
+                .checked_add(owner_fee)
+                .and_then(|amount| amount.checked_mul(u128::from(pool.total_weight())))
+                .and_then(|amount| amount.checked_div(u128::from(source_weight)))
+                .ok_or(SwapError::FeeCalculationFailure)?;
+            validate_fee_mint(
+                owner_fee,
+                pool_mint_amount,
+                pool_value,
+                self.pool_mint.supply,
+            )?;
+
+            mint_owner_fee(
+                pool_mint_amount,
+                &self.pool_account.to_account_info(),
+                &[],
+                &[],
+                |to: &AccountInfo<'info>, amount| {
+                    token::mint_to(
+                        CpiContext::new_with_signer(
+                            self.token_program.clone(),
+                            MintTo {
+                                mint: self.pool_mint.to_account_info().clone(),
+                                to: to.clone(),
+                                authority: self.authority.clone(),
+                            },
+                            &[&seeds[..]],
+                        ),
+                        amount,
+                    )
+                },
+            )?;
         }
 
         Ok(())
     }
+}
 
-    fn mint_create_state_account(
-        &mut self,
-        bump_seed: u8,
-        curve_input: CurveInput,
-        fee_input: FeeInput,
-        curve: &SwapCurve,
+impl<'info> DepositWeighted<'info> {
+    fn process_deposit(
+        &self,
+        program_id: &Pubkey,
+        remaining_accounts: &[AccountInfo<'info>],
+        pool_token_amount: u64,
+        maximum_token_amounts: &[u64],
     ) -> Result<()> {
-        let seeds = &[
-            &self.amm.to_account_info().key().to_bytes(),
-            &[bump_seed][..],
-        ];
+        let pool = &self.pool;
+        if !pool.is_initialized {
+            return Err(SwapError::NotInitialized.into());
+        }
 
-        let initial_ammount = curve.calculator.new_supply_pool();
+        if *self.authority.key != authority_key(program_id, pool.key(), pool.bump_seed)? {
+            return Err(SwapError::InvalidProgramAddress.into());
+        }
 
-        let mint_initial_amt_cpi_ctx = CpiContext::new(
-            self.token_program.clone(),
-            MintTo {
-                mint: self.pool_mint.to_account_info().clone(),
-                to: self.destination.to_account_info().clone(),
-                authority: self.authority.clone(),
-            },
-        );
+        validate_mint_authority(&self.pool_mint, self.authority.key)?;
+
+        if remaining_accounts.len() != pool.reserves.len() * 2
+            || maximum_token_amounts.len() != pool.reserves.len()
+        {
+            return Err(SwapError::InvalidInput.into());
+        }
+
+        let pool_mint_supply = u128::from(self.pool_mint.supply);
+        if pool_mint_supply == 0 {
+            return Err(SwapError::EmptySupply.into());
+        }
+
+        for ((reserve, accounts), maximum_token_amount) in pool
+            .reserves
+            .iter()
+            .zip(remaining_accounts.chunks(2))
+            .zip(maximum_token_amounts)
+        {
+            let (source, reserve_info) = (&accounts[0], &accounts[1]);
+            let reserve_account = load_weighted_reserve(reserve, reserve_info)?;
+            let token_amount = WeightedCurve
+                .pool_tokens_to_trading_tokens(
+                    u128::from(pool_token_amount),
+                    pool_mint_supply,
+                    u128::from(reserve_account.amount),
+                    RoundDirection::Ceil,
+                )
+                .ok_or(SwapError::ZeroTradingTokens)?;
+            let token_amount = to_u64(token_amount)?;
+            if token_amount > *maximum_token_amount {
+                return Err(SwapError::ExceededSlippage.into());
+            }
+            if token_amount == 0 {
+                return Err(SwapError::ZeroTradingTokens.into());
+            }
+
+            token::transfer(
+                CpiContext::new(
+                    self.token_program.clone(),
+                    Transfer {
+                        from: source.clone(),
+                        to: reserve_info.clone(),
+                        authority: self.user_transfer_authority.clone(),
+                    },
+                ),
+                token_amount,
+            )?;
+        }
+
+        let seeds = &[&pool.key().to_bytes(), &[pool.bump_seed][..]];
 
         token::mint_to(
-            mint_initial_amt_cpi_ctx.with_signer(&[&seeds[..]]),
-            u64::try_from(initial_ammount).unwrap(),
+            CpiContext::new_with_signer(
+                self.token_program.clone(),
+                MintTo {
+                    mint: self.pool_mint.to_account_info().clone(),
+                    to: self.destination.to_account_info().clone(),
+                    authority: self.authority.clone(),
+                },
+                &[&seeds[..]],
+            ),
+            pool_token_amount,
         )?;
 
-        let amm = &mut self.amm;
-        amm.is_initialized = true;
-        amm.bump_seed = bump_seed;
-        amm.token_program_id = *self.token_program.key;
-        amm.token_a_account = *self.token_a.to_account_info().key;
-        amm.token_b_account = *self.token_b.to_account_info().key;
-        amm.pool_mint = *self.pool_mint.to_account_info().key;
-        amm.token_a_mint = self.token_a.mint;
-        amm.token_b_mint = self.token_b.mint;
-        amm.pool_fee_account = *self.fee_account.to_account_info().key;
-        amm.fees = fee_input;
-        amm.curve = curve_input;
-
         Ok(())
     }
+}
 
-    fn validate_amm_fees_and_curve(
+impl<'info> WithdrawWeighted<'info> {
+    fn process_withdraw(
         &self,
-        fees_input: &FeeInput,
-        curve_input: &CurveInput,
-    ) -> Result<SwapCurve> {
-        let curve = build_curve(curve_input).unwrap();
-        curve
-            .calculator
-            .validate_supply(self.token_a.amount, self.token_b.amount)?;
+        program_id: &Pubkey,
+        remaining_accounts: &[AccountInfo<'info>],
+        pool_token_amount: u64,
+        minimum_token_amounts: &[u64],
+    ) -> Result<()> {
+        let pool = &self.pool;
+        if !pool.is_initialized {
+            return Err(SwapError::NotInitialized.into());
+        }
 
-        let fees = build_fees(fees_input)?;
-        fees.validate()?;
-        curve.calculator.validate()?;
-        Ok(curve)
+        if *self.authority.key != authority_key(program_id, pool.key(), pool.bump_seed)? {
+            return Err(SwapError::InvalidProgramAddress.into());
+        }
+
+        validate_mint_authority(&self.pool_mint, self.authority.key)?;
+
+        if remaining_accounts.len() != pool.reserves.len() * 2
+            || minimum_token_amounts.len() != pool.reserves.len()
+        {
+            return Err(SwapError::InvalidInput.into());
+        }
+
+        let pool_mint_supply = u128::from(self.pool_mint.supply);
+        if pool_mint_supply == 0 {
+            return Err(SwapError::EmptySupply.into());
+        }
+
+        if pool_token_amount > self.source_pool_account.amount {
+            return Err(SwapError::InsufficientPoolTokens.into());
+        }
+
+        let withdraw_fee = pool
+            .curve_fees()?
+            .owner_withdraw_fee(u128::from(pool_token_amount))
+            .ok_or(SwapError::FeeCalculationFailure)?;
+        let pool_token_amount_after_fee = u128::from(pool_token_amount)
+            .checked_sub(withdraw_fee)
+            .ok_or(SwapError::FeeCalculationFailure)?;
+
+        let seeds = &[&pool.key().to_bytes(), &[pool.bump_seed][..]];
+
+        token::burn(
+            CpiContext::new(
+                self.token_program.clone(),
+                Burn {
+                    mint: self.pool_mint.to_account_info().clone(),
+                    from: self.source_pool_account.to_account_info().clone(),
+                    authority: self.user_transfer_authority.clone(),
+                },
+            ),
+            pool_token_amount,
+        )?;
+
+        if withdraw_fee > 0 {
+            token::mint_to(
+                CpiContext::new_with_signer(
+                    self.token_program.clone(),
+                    MintTo {
+                        mint: self.pool_mint.to_account_info().clone(),
+                        to: self.fee_account.to_account_info().clone(),
+                        authority: self.authority.clone(),
+                    },
+                    &[&seeds[..]],
+                ),
+                to_u64(withdraw_fee)?,
+            )?;
+        }
+
+        for ((reserve, accounts), minimum_token_amount) in pool
+            .reserves
+            .iter()
+            .zip(remaining_accounts.chunks(2))
+            .zip(minimum_token_amounts)
+        {
+            let (reserve_info, destination) = (&accounts[0], &accounts[1]);
+            let reserve_account = load_weighted_reserve(reserve, reserve_info)?;
+            let token_amount = WeightedCurve
+                .pool_tokens_to_trading_tokens(
+                    pool_token_amount_after_fee,
+                    pool_mint_supply,
+                    u128::from(reserve_account.amount),
+                    RoundDirection::Floor,
+                )
+                .ok_or(SwapError::ZeroTradingTokens)?;
+            let token_amount = to_u64(token_amount)?;
+            if token_amount < *minimum_token_amount {
+                return Err(SwapError::ExceededSlippage.into());
+            }
+
+            token::transfer(
+                CpiContext::new_with_signer(
+                    self.token_program.clone(),
+                    Transfer {
+                        from: reserve_info.clone(),
+                        to: destination.clone(),
+                        authority: self.authority.clone(),
+                    },
+                    &[&seeds[..]],
+                ),
+                token_amount,
+            )?;
+        }
+
+        Ok(())
     }
 }
 
@@ -649,7 +4037,7 @@ pub struct Amm {
     pub is_initialized: bool,
     pub bump_seed: u8,
     pub token_program_id: Pubkey,
-    // Token A liquidity Account
+    // Token A liquidity Account, token A is always the mint with the lower key
     pub token_a_account: Pubkey,
     // Token B liquidity Account
     pub token_b_account: Pubkey,
@@ -662,8 +4050,555 @@ pub struct Amm {
     pub pool_fee_account: Pubkey,
     // Fees associated with swap
     pub fees: FeeInput,
-    // Curve associated with swap
-    pub curve: CurveInput,
+    // Curve associated with swap
+    pub curve: CurveInput,
+    // Minimum normalized pool value required before swaps are allowed, 0 to disable
+    pub min_pool_value: u64,
+    // Total trade fees collected by swaps
+    pub cumulative_trade_fee: u128,
+    // Total owner fees collected by swaps
+    pub cumulative_owner_fee: u128,
+    // Time-weighted sum of the price of token A in token B, as Q64.64
+    pub price_cumulative_a: u128,
+    // Time-weighted sum of the price of token B in token A, as Q64.64
+    pub price_cumulative_b: u128,
+    // Timestamp of the last price accumulator update
+    pub last_update_ts: i64,
+    // Slot of the last swap, deposit, withdrawal or rebalance
+    pub last_update_slot: u64,
+    // Account allowed to perform admin operations on the pool
+    pub owner: Pubkey,
+    // Owner of the pool fee account accruing protocol fees
+    pub fee_owner: Pubkey,
+    // Token A mint decimals
+    pub token_a_decimals: u8,
+    // Token B mint decimals
+    pub token_b_decimals: u8,
+    // Whether deposits are accepted, swaps and withdrawals are unaffected
+    pub deposits_enabled: bool,
+    // Swaps before this timestamp pay no fees, 0 to disable
+    pub fee_free_until: i64,
+    // Maximum price impact of a single swap in basis points, 0 to disable
+    pub max_price_impact_bps: u16,
+    // Withdraw fee in basis points right after a deposit, 0 to disable
+    pub exit_fee_bps: u16,
+    // Seconds for the exit fee to decay to the owner withdraw fee
+    pub exit_fee_decay_period: i64,
+    // Total token A swapped into the pool
+    pub volume_a_in: u128,
+    // Total token B swapped into the pool
+    pub volume_b_in: u128,
+    // Maximum output of a single swap as basis points of the destination reserve, 0 to disable
+    pub max_swap_fraction_bps: u16,
+    // Pool token account receiving host fees of swaps without a host, default to disable
+    pub default_host_fee_account: Pubkey,
+    // Token A reserve as accounted by the pool, excluding tokens sent to the reserve directly
+    pub reserve_a: u64,
+    // Token B reserve as accounted by the pool
+    pub reserve_b: u64,
+    // Whether swaps reject reserve balances below the accounted reserves
+    pub reconcile_reserves: bool,
+    // Maximum balance of either reserve after a deposit, 0 to disable
+    pub max_reserve: u64,
+    // Split of the owner trade fee, unused entries have no share. Without
+    // any share the fee goes to the pool fee account.
+    pub fee_split: [FeeRecipient; MAX_FEE_RECIPIENTS],
+    // Set while a flash loan's callback runs, so it can't re-enter the pool
+    pub in_progress: bool,
+    // Proposed owner, who takes over once they accept, default if none
+    pub pending_owner: Pubkey,
+    // Pool mint decimals
+    pub pool_mint_decimals: u8,
+}
+
+impl Amm {
+    pub const LEN: usize = 1
+        + 1
+        + 32 * 7
+        + FeeInput::LEN
+        + CurveInput::LEN
+        + 8
+        + 16 * 4
+        + 8
+        + 8
+        + 32 * 2
+        + 1
+        + 1
+        + 1
+        + 8
+        + 2
+        + 2
+        + 8
+        + 16 * 2
+        + 2
+        + 32
+        + 8 * 2
+        + 1
+        + 8
+        + FeeRecipient::LEN * MAX_FEE_RECIPIENTS
+        + 1
+        + 32
+        + 1;
+
+    /// Swap authority of the pool at `amm_key`, the PDA owning its reserves
+    /// and minting its pool tokens, with its bump seed.
+    pub fn authority(amm_key: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[&amm_key.to_bytes()], program_id)
+    }
+
+    /// Moves the accounted amount of the reserve held in `reserve` by
+    /// `amount_in` tokens in and `amount_out` tokens out. Outflows priced off
+    /// live balances can include surplus, so the amount floors at zero.
+    pub fn record_reserve_change(
+        &mut self,
+        reserve: &Pubkey,
+        amount_in: u64,
+        amount_out: u64,
+    ) -> Result<()> {
+        let tracked = if *reserve == self.token_a_account {
+            &mut self.reserve_a
+        } else if *reserve == self.token_b_account {
+            &mut self.reserve_b
+        } else {
+            return Err(SwapError::IncorrectSwapAccount.into());
+        };
+        *tracked = tracked
+            .checked_add(amount_in)
+            .ok_or(SwapError::ConversionFailure)?
+            .saturating_sub(amount_out);
+        Ok(())
+    }
+
+    /// Fees charged on a swap at `timestamp`, which are all zero during the
+    /// fee-free window.
+    pub fn swap_fees(&self, timestamp: i64) -> Result<CurveFees> {
+        if timestamp < self.fee_free_until {
+            return Ok(CurveFees::default());
+        }
+        self.curve_fees()
+    }
+
+    /// SHA-256 over every field an admin or initializer can configure.
+    /// Reserves, accumulators and other state moved by trading are left out.
+    pub fn config_hash(&self) -> Result<[u8; 32]> {
+        let fees = self.fees.try_to_vec()?;
+        let curve = self.curve.try_to_vec()?;
+        let fee_split = self.fee_split.try_to_vec()?;
+        let hash = hashv(&[
+            self.owner.as_ref(),
+            self.fee_owner.as_ref(),
+            self.token_a_account.as_ref(),
+            self.token_b_account.as_ref(),
+            self.pool_mint.as_ref(),
+            self.pool_fee_account.as_ref(),
+            &fees,
+            &curve,
+            &self.min_pool_value.to_le_bytes(),
+            &[u8::from(self.deposits_enabled)],
+            &self.fee_free_until.to_le_bytes(),
+            &self.max_price_impact_bps.to_le_bytes(),
+            &self.exit_fee_bps.to_le_bytes(),
+            &self.exit_fee_decay_period.to_le_bytes(),
+            &self.max_swap_fraction_bps.to_le_bytes(),
+            self.default_host_fee_account.as_ref(),
+            &[u8::from(self.reconcile_reserves)],
+            &self.max_reserve.to_le_bytes(),
+            &fee_split,
+            self.pending_owner.as_ref(),
+        ]);
+        Ok(hash.to_bytes())
+    }
+
+    /// Withdraw fee on `pool_tokens`. With an exit fee configured, it decays
+    /// linearly from `exit_fee_bps` to the owner withdraw fee over
+    /// `exit_fee_decay_period` since `deposit_ts`, on the
+    /// `deposited_pool_tokens` a position recorded. Pool tokens are fungible,
+    /// so the rest pay the full exit fee.
+    pub fn withdraw_fee(
+        &self,
+        fees: &CurveFees,
+        pool_tokens: u128,
+        deposited_pool_tokens: u128,
+        deposit_ts: i64,
+        timestamp: i64,
+    ) -> Option<u128> {
+        let base_fee = fees.owner_withdraw_fee(pool_tokens)?;
+        if self.exit_fee_bps == 0 || self.exit_fee_decay_period == 0 {
+            return Some(base_fee);
+        }
+        let exit_fee = pool_tokens
+            .checked_mul(u128::from(self.exit_fee_bps))?
+            .checked_div(u128::from(BASIS_POINTS))?;
+        if exit_fee <= base_fee {
+            return Some(base_fee);
+        }
+
+        // (exit - base) * deposited / pool_tokens * elapsed / period is waived
+        let period = self.exit_fee_decay_period as u128;
+        let elapsed = (timestamp.saturating_sub(deposit_ts).max(0) as u128).min(period);
+        let waived_fee = U256::from(exit_fee - base_fee)
+            .checked_mul(U256::from(deposited_pool_tokens.min(pool_tokens)))?
+            .checked_mul(U256::from(elapsed))?
+            .checked_div(U256::from(pool_tokens).checked_mul(U256::from(period))?)?;
+        exit_fee.checked_sub(u128::try_from(waived_fee).ok()?)
+    }
+
+    /// Curve stored in the pool. It was validated at initialization, so
+    /// failing to build it means the account data is corrupted.
+    pub fn swap_curve(&self) -> Result<SwapCurve> {
+        build_curve(&self.curve).map_err(|_| SwapError::CorruptedPoolState.into())
+    }
+
+    /// Fees stored in the pool, see `swap_curve`.
+    pub fn curve_fees(&self) -> Result<CurveFees> {
+        let fees = build_fees(&self.fees).map_err(|_| SwapError::CorruptedPoolState)?;
+        fees.validate().map_err(|_| SwapError::CorruptedPoolState)?;
+        Ok(fees)
+    }
+
+    /// Rejects swaps while the pool's normalized value is below `min_pool_value`.
+    pub fn validate_pool_value(
+        &self,
+        curve: &SwapCurve,
+        token_a_amount: u64,
+        token_b_amount: u64,
+    ) -> std::result::Result<(), SwapError> {
+        if self.min_pool_value == 0 {
+            return Ok(());
+        }
+        let pool_value = curve
+            .calculator
+            .normalized_value(u128::from(token_a_amount), u128::from(token_b_amount))
+            .ok_or(SwapError::ZeroTradingTokens)?;
+        let min_pool_value = PreciseNumber::new(u128::from(self.min_pool_value))
+            .ok_or(SwapError::ConversionFailure)?;
+        if pool_value.less_than(&min_pool_value) {
+            return Err(SwapError::PoolTooThin);
+        }
+        Ok(())
+    }
+
+    /// Rejects swaps whose execution price, after fees, is further than
+    /// `max_price_impact_bps` below the curve's spot price at the reserves.
+    pub fn validate_price_impact(
+        &self,
+        curve: &SwapCurve,
+        amount_in: u64,
+        output_amount: u64,
+        token_a_amount: u64,
+        token_b_amount: u64,
+        trade_direction: TradeDirection,
+    ) -> std::result::Result<(), SwapError> {
+        if self.max_price_impact_bps == 0 {
+            return Ok(());
+        }
+        let spot_price = curve
+            .calculator
+            .spot_price(
+                u128::from(token_a_amount),
+                u128::from(token_b_amount),
+                trade_direction,
+            )
+            .ok_or(SwapError::ConversionFailure)?;
+        // output / amount_in >= (1 - max_impact) * spot_price
+        let received = PreciseNumber::new(u128::from(output_amount) * u128::from(BASIS_POINTS))
+            .ok_or(SwapError::ConversionFailure)?;
+        let required = PreciseNumber::new(
+            u128::from(amount_in) * u128::from(BASIS_POINTS - self.max_price_impact_bps),
+        )
+        .and_then(|x| x.checked_mul(&spot_price))
+        .ok_or(SwapError::ConversionFailure)?;
+        if required.greater_than(&received) {
+            return Err(SwapError::PriceImpactTooHigh);
+        }
+        Ok(())
+    }
+
+    /// With `reconcile_reserves` set, rejects reserve balances below the
+    /// accounted reserves, which only a transfer out of the pool's control
+    /// can cause. Balances above them are surplus left for `skim`.
+    pub fn validate_reserve_balances(
+        &self,
+        token_a_amount: u64,
+        token_b_amount: u64,
+    ) -> std::result::Result<(), SwapError> {
+        if self.reconcile_reserves
+            && (token_a_amount < self.reserve_a || token_b_amount < self.reserve_b)
+        {
+            return Err(SwapError::ReserveMismatch);
+        }
+        Ok(())
+    }
+
+    /// Checks that the pool account still holds enough lamports to be rent
+    /// exempt at its current size. Instructions that realloc the account to
+    /// make room for new fields call this once it has grown, with the rent
+    /// sysvar.
+    pub fn validate_rent_exempt(amm_info: &AccountInfo, rent: &Rent) -> Result<()> {
+        if !rent.is_exempt(amm_info.lamports(), amm_info.data_len()) {
+            return Err(SwapError::NotRentExempt.into());
+        }
+        Ok(())
+    }
+
+    /// Rejects instructions invoked while the pool is lending out a reserve,
+    /// from within a flash loan callback.
+    pub fn validate_not_in_progress(&self) -> std::result::Result<(), SwapError> {
+        if self.in_progress {
+            return Err(SwapError::Reentrancy);
+        }
+        Ok(())
+    }
+
+    /// Recipients with a share of the owner trade fee, in order.
+    pub fn fee_recipients(&self) -> Vec<FeeRecipient> {
+        self.fee_split
+            .iter()
+            .filter(|fee_recipient| fee_recipient.share_bps > 0)
+            .copied()
+            .collect()
+    }
+
+    /// With `max_reserve` set, rejects reserve balances above it.
+    pub fn validate_reserve_cap(
+        &self,
+        token_a_amount: u64,
+        token_b_amount: u64,
+    ) -> std::result::Result<(), SwapError> {
+        if self.max_reserve != 0
+            && (token_a_amount > self.max_reserve || token_b_amount > self.max_reserve)
+        {
+            return Err(SwapError::ReserveCapExceeded);
+        }
+        Ok(())
+    }
+
+    /// Rejects swaps sending out more than `max_swap_fraction_bps` of the
+    /// destination reserve.
+    pub fn validate_swap_size(
+        &self,
+        output_amount: u64,
+        swap_destination_amount: u64,
+    ) -> std::result::Result<(), SwapError> {
+        if self.max_swap_fraction_bps == 0 {
+            return Ok(());
+        }
+        let output = u128::from(output_amount) * u128::from(BASIS_POINTS);
+        let max_output =
+            u128::from(swap_destination_amount) * u128::from(self.max_swap_fraction_bps);
+        if output > max_output {
+            return Err(SwapError::SwapTooLarge);
+        }
+        Ok(())
+    }
+
+    /// Returns the price accumulators as they would be at `timestamp`, given the
+    /// reserves held since the last update. Prices are Q64.64 fixed point and the
+    /// sums wrap on overflow, so consumers should only ever use the difference
+    /// between two observations, as with Uniswap V2.
+    pub fn price_cumulatives_at(
+        &self,
+        reserve_a: u64,
+        reserve_b: u64,
+        timestamp: i64,
+    ) -> Result<(u128, u128)> {
+        let elapsed = timestamp.saturating_sub(self.last_update_ts);
+        if elapsed <= 0 || reserve_a == 0 || reserve_b == 0 {
+            return Ok((self.price_cumulative_a, self.price_cumulative_b));
+        }
+        let elapsed = elapsed as u128;
+        // The constant product price is the reserve ratio, kept exact in
+        // integers. Other curves price through their calculator.
+        let (price_a, price_b) = if self.curve.curve_type == CurveType::ConstantProduct as u8 {
+            (
+                (u128::from(reserve_b) << 64) / u128::from(reserve_a),
+                (u128::from(reserve_a) << 64) / u128::from(reserve_b),
+            )
+        } else {
+            let curve = self.swap_curve()?;
+            let spot_price_q64 = |trade_direction| {
+                curve
+                    .calculator
+                    .spot_price(
+                        u128::from(reserve_a),
+                        u128::from(reserve_b),
+                        trade_direction,
+                    )
+                    .as_ref()
+                    .and_then(to_q64)
+                    .ok_or(SwapError::ConversionFailure)
+            };
+            (
+                spot_price_q64(TradeDirection::AtoB)?,
+                spot_price_q64(TradeDirection::BtoA)?,
+            )
+        };
+        Ok((
+            self.price_cumulative_a
+                .wrapping_add(price_a.wrapping_mul(elapsed)),
+            self.price_cumulative_b
+                .wrapping_add(price_b.wrapping_mul(elapsed)),
+        ))
+    }
+
+    /// Accumulates prices up to the current time and records the slot as the
+    /// last one modifying the pool. Must be called with the reserves from
+    /// before the instruction moves any tokens.
+    pub fn update_price_cumulatives(&mut self, reserve_a: u64, reserve_b: u64) -> Result<()> {
+        let clock = Clock::get()?;
+        let timestamp = clock.unix_timestamp;
+        let (price_cumulative_a, price_cumulative_b) =
+            self.price_cumulatives_at(reserve_a, reserve_b, timestamp)?;
+        self.price_cumulative_a = price_cumulative_a;
+        self.price_cumulative_b = price_cumulative_b;
+        self.last_update_ts = timestamp;
+        self.last_update_slot = clock.slot;
+        Ok(())
+    }
+}
+
+/// Denominator for values expressed in basis points.
+pub const BASIS_POINTS: u16 = 10_000;
+
+pub const POSITION_SEED: &[u8] = b"position";
+
+/// Seed of the global config.
+pub const CONFIG_SEED: &[u8] = b"config";
+
+/// Maximum number of recipients the owner trade fee can be split between.
+pub const MAX_FEE_RECIPIENTS: usize = 4;
+
+/// Maximum number of initializers in the global config allowlist.
+pub const MAX_ALLOWLIST_LEN: usize = 32;
+
+/// Seed of the temporary wrapped SOL account, along with the user's key.
+pub const WSOL_SEED: &[u8] = b"wsol";
+
+/// Upper bound on `remaining_accounts` accepted by instructions that take a
+/// variable list of accounts, keeping compute usage bounded.
+pub const MAX_REMAINING_ACCOUNTS: usize = 8;
+
+/// Maximum number of legs accepted by `swap_batch`.
+pub const MAX_BATCH_LEGS: usize = 8;
+
+/// Maximum number of swap sizes accepted by `get_price_curve`.
+pub const MAX_PRICE_CURVE_POINTS: usize = 16;
+
+/// Pool tokens accrued on behalf of a partner, held in a pool token account
+/// owned by the position itself.
+#[account]
+pub struct Position {
+    pub amm: Pubkey,
+    pub owner: Pubkey,
+    // Pool token account owned by this position
+    pub pool_token_account: Pubkey,
+    // Pool tokens accrued in the position
+    pub pool_token_amount: u64,
+    pub bump_seed: u8,
+    // Average time of the owner's deposits through this position, weighted by
+    // the pool tokens they minted, for the exit fee
+    pub deposit_ts: i64,
+    // Pool tokens minted by those deposits and not withdrawn since
+    pub deposited_pool_tokens: u64,
+}
+
+impl Position {
+    pub const LEN: usize = 32 + 32 + 32 + 8 + 1 + 8 + 8;
+
+    /// Adds `pool_token_amount` deposited at `timestamp`, moving the deposit
+    /// time to the average over all deposited pool tokens.
+    pub fn record_deposit(&mut self, pool_token_amount: u64, timestamp: i64) -> Option<()> {
+        let deposited = i128::from(self.deposited_pool_tokens);
+        let added = i128::from(pool_token_amount);
+        let total = deposited.checked_add(added)?;
+        if total == 0 {
+            return Some(());
+        }
+        let deposit_ts = deposited
+            .checked_mul(i128::from(self.deposit_ts))?
+            .checked_add(added.checked_mul(i128::from(timestamp))?)?
+            .checked_div(total)?;
+        self.deposit_ts = i64::try_from(deposit_ts).ok()?;
+        self.deposited_pool_tokens = u64::try_from(total).ok()?;
+        Some(())
+    }
+}
+
+/// Program-wide settings, at the PDA of `CONFIG_SEED`. Initialization is
+/// only gated when the config is passed, so pools created without it stay
+/// permissionless.
+#[account]
+pub struct GlobalConfig {
+    // Account allowed to edit the allowlist
+    pub admin: Pubkey,
+    pub bump_seed: u8,
+    // Initializers allowed to create pools
+    pub allowlist: Vec<Pubkey>,
+}
+
+impl GlobalConfig {
+    pub const LEN: usize = 32 + 1 + 4 + 32 * MAX_ALLOWLIST_LEN;
+}
+
+/// Pool of two to `MAX_WEIGHTED_TOKENS` reserves priced by the weighted
+/// constant product curve. It has its own instructions and is independent
+/// of `Amm`.
+#[account]
+pub struct WeightedPool {
+    pub is_initialized: bool,
+    pub bump_seed: u8,
+    pub token_program_id: Pubkey,
+    pub pool_mint: Pubkey,
+    // Pool fee account
+    pub pool_fee_account: Pubkey,
+    // Account allowed to perform admin operations on the pool
+    pub owner: Pubkey,
+    // Fees associated with swap
+    pub fees: FeeInput,
+    // Reserves of the pool, in the order given at initialization
+    pub reserves: Vec<WeightedReserve>,
+}
+
+impl WeightedPool {
+    pub const LEN: usize =
+        1 + 1 + 32 * 4 + FeeInput::LEN + 4 + WeightedReserve::LEN * MAX_WEIGHTED_TOKENS;
+
+    /// Authority of the pool at `pool_key`, derived as for `Amm`.
+    pub fn authority(pool_key: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[&pool_key.to_bytes()], program_id)
+    }
+
+    pub fn total_weight(&self) -> u64 {
+        self.reserves.iter().map(|reserve| reserve.weight).sum()
+    }
+
+    /// Index of the reserve held in `account`.
+    pub fn reserve_index(&self, account: &Pubkey) -> Result<usize> {
+        self.reserves
+            .iter()
+            .position(|reserve| reserve.account == *account)
+            .ok_or(SwapError::IncorrectSwapAccount.into())
+    }
+
+    /// Fees stored in the pool, see `Amm::curve_fees`.
+    pub fn curve_fees(&self) -> Result<CurveFees> {
+        let fees = build_fees(&self.fees).map_err(|_| SwapError::CorruptedPoolState)?;
+        fees.validate().map_err(|_| SwapError::CorruptedPoolState)?;
+        Ok(fees)
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct WeightedReserve {
+    // Reserve token account, owned by the pool authority
+    pub account: Pubkey,
+    pub mint: Pubkey,
+    // Weight of the reserve, out of the sum of all weights
+    pub weight: u64,
+}
+
+impl WeightedReserve {
+    pub const LEN: usize = 32 + 32 + 8;
 }
 
 #[error_code]
@@ -716,6 +4651,58 @@ pub enum SwapError {
     InvalidPercentage,
     #[msg("AMM not initialized")]
     NotInitialized,
+    #[msg("Pool reserves are below the minimum required for swaps")]
+    PoolTooThin,
+    #[msg("Signer is not the pool owner")]
+    Unauthorized,
+    #[msg("Too many remaining accounts")]
+    TooManyAccounts,
+    #[msg("Token mint does not match the token account")]
+    IncorrectMint,
+    #[msg("Deposits are disabled for this pool")]
+    DepositsDisabled,
+    #[msg("Invalid curve parameters")]
+    InvalidCurve,
+    #[msg("Swap moves the price more than the pool allows")]
+    PriceImpactTooHigh,
+    #[msg("Flash loan was not repaid with its fee")]
+    FlashLoanNotRepaid,
+    #[msg("Curve type is not supported")]
+    UnsupportedCurveType,
+    #[msg("Pool state is corrupted")]
+    CorruptedPoolState,
+    #[msg("Pool changed since the quote was made")]
+    StaleQuote,
+    #[msg("Pool reserve account is frozen")]
+    ReserveFrozen,
+    #[msg("Token A mint must sort before token B mint for this curve")]
+    UnsortedMints,
+    #[msg("Pool token amount exceeds the source account balance")]
+    InsufficientPoolTokens,
+    #[msg("Swap output exceeds the maximum fraction of the reserve")]
+    SwapTooLarge,
+    #[msg("Account is not rent exempt")]
+    NotRentExempt,
+    #[msg("Transaction executed after its deadline")]
+    DeadlineExceeded,
+    #[msg("Reserve balance is below the reserve accounted by the pool")]
+    ReserveMismatch,
+    #[msg("Deposit would take a reserve past the pool's reserve cap")]
+    ReserveCapExceeded,
+    #[msg("Initializer is not in the allowlist")]
+    InitializerNotAllowlisted,
+    #[msg("Allowlist is full")]
+    AllowlistFull,
+    #[msg("Fee split shares must add up to 10000 basis points")]
+    InvalidFeeSplit,
+    #[msg("Pool re-entered while an operation is in progress")]
+    Reentrancy,
+    #[msg("Swap would take the whole destination reserve")]
+    InsufficientLiquidity,
+    #[msg("Pool charges an exit fee, so deposits must go through a position")]
+    PositionRequired,
+    #[msg("Reserves can't be swept while a withdraw fee is due")]
+    WithdrawFeeDue,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
@@ -728,6 +4715,93 @@ pub struct FeeInput {
     pub owner_withdraw_fee_denominator: u64,
     pub host_fee_numerator: u64,
     pub host_fee_denominator: u64,
+    pub flash_loan_fee_numerator: u64,
+    pub flash_loan_fee_denominator: u64,
+    pub dynamic_fee_numerator: u64,
+    pub dynamic_fee_denominator: u64,
+}
+
+impl FeeInput {
+    pub const LEN: usize = 8 * 12;
+
+    /// Fees given in basis points, over a denominator of 10000. `host_bps`
+    /// is the host's share of the owner trade fee. Flash loan and dynamic
+    /// fees are left off.
+    ///
+    /// ```rust
+    /// use dexy::{build_fees, FeeInput};
+    ///
+    /// let fee_input = FeeInput::from_bps(25, 5, 10, 2000);
+    /// let fees = build_fees(&fee_input).unwrap();
+    /// assert!(fees.validate().is_ok());
+    /// assert_eq!(fees.trade_fee_numerator, 25);
+    /// assert_eq!(fees.trade_fee_denominator, 10000);
+    /// assert_eq!(fees.owner_trade_fee_numerator, 5);
+    /// assert_eq!(fees.owner_withdraw_fee_numerator, 10);
+    /// assert_eq!(fees.host_fee_numerator, 2000);
+    /// assert_eq!(fees.host_fee_denominator, 10000);
+    ///
+    /// assert!(build_fees(&FeeInput::zero()).unwrap().validate().is_ok());
+    /// ```
+    pub fn from_bps(trade_bps: u16, owner_bps: u16, withdraw_bps: u16, host_bps: u16) -> Self {
+        let denominator = u64::from(BASIS_POINTS);
+        Self {
+            trade_fee_numerator: u64::from(trade_bps),
+            trade_fee_denominator: denominator,
+            owner_trade_fee_numerator: u64::from(owner_bps),
+            owner_trade_fee_denominator: denominator,
+            owner_withdraw_fee_numerator: u64::from(withdraw_bps),
+            owner_withdraw_fee_denominator: denominator,
+            host_fee_numerator: u64::from(host_bps),
+            host_fee_denominator: denominator,
+            ..Self::zero()
+        }
+    }
+
+    /// No fees at all, for fee-free pools.
+    pub fn zero() -> Self {
+        Self::default()
+    }
+}
+
+/// Recipient of `share_bps` of the owner trade fee.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct FeeRecipient {
+    // Pool token account receiving the share
+    pub recipient: Pubkey,
+    pub share_bps: u16,
+}
+
+impl FeeRecipient {
+    pub const LEN: usize = 32 + 2;
+}
+
+/// Checks that a fee split names at most `MAX_FEE_RECIPIENTS` recipients,
+/// each with a share, and that the shares add up to the whole fee.
+pub fn validate_fee_split(fee_split: &[FeeRecipient]) -> Result<()> {
+    if fee_split.is_empty() || fee_split.len() > MAX_FEE_RECIPIENTS {
+        return Err(SwapError::InvalidFeeSplit.into());
+    }
+    let mut total_bps: u32 = 0;
+    for fee_recipient in fee_split {
+        if fee_recipient.share_bps == 0 || fee_recipient.recipient == Pubkey::default() {
+            return Err(SwapError::InvalidFeeSplit.into());
+        }
+        total_bps += u32::from(fee_recipient.share_bps);
+    }
+    if total_bps != u32::from(BASIS_POINTS) {
+        return Err(SwapError::InvalidFeeSplit.into());
+    }
+    Ok(())
+}
+
+/// Converts a fee fraction to basis points, rounding down.
+pub fn fee_bps(numerator: u64, denominator: u64) -> u16 {
+    if denominator == 0 {
+        return 0;
+    }
+    let bps = u128::from(numerator) * u128::from(BASIS_POINTS) / u128::from(denominator);
+    u16::try_from(bps).unwrap_or(u16::MAX)
 }
 
 pub fn build_fees(fee_input: &FeeInput) -> Result<CurveFees> {
@@ -740,22 +4814,216 @@ pub fn build_fees(fee_input: &FeeInput) -> Result<CurveFees> {
         owner_withdraw_fee_denominator: fee_input.owner_withdraw_fee_denominator,
         host_fee_numerator: fee_input.host_fee_numerator,
         host_fee_denominator: fee_input.host_fee_denominator,
+        flash_loan_fee_numerator: fee_input.flash_loan_fee_numerator,
+        flash_loan_fee_denominator: fee_input.flash_loan_fee_denominator,
+        dynamic_fee_numerator: fee_input.dynamic_fee_numerator,
+        dynamic_fee_denominator: fee_input.dynamic_fee_denominator,
     };
     Ok(fees)
 }
 
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct PoolInfo {
+    pub cumulative_trade_fee: u128,
+    pub cumulative_owner_fee: u128,
+    // Last slot in which the pool was modified, for `swap_with_expected_slot`
+    pub last_update_slot: u64,
+    // Total token A and token B swapped into the pool
+    pub volume_a_in: u128,
+    pub volume_b_in: u128,
+    // Trade fee plus owner trade fee in basis points, what a swap costs
+    pub total_fee_bps: u16,
+    // Reserves as accounted by the pool, see `Amm::reserve_a`
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct CanSwapResult {
+    pub success: bool,
+    // Error code the swap would fail with, 0 on success
+    pub error_code: u32,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct PriceCumulatives {
+    pub price_cumulative_a: u128,
+    pub price_cumulative_b: u128,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CurveChanged {
+    pub amm: Pubkey,
+    pub old_curve_type: CurveType,
+    pub new_curve_type: CurveType,
+}
+
+#[event]
+pub struct OwnerChanged {
+    pub amm: Pubkey,
+    pub old_owner: Pubkey,
+    pub new_owner: Pubkey,
+}
+
+#[event]
+pub struct PoolSnapshot {
+    pub amm: Pubkey,
+    pub token_a_amount: u64,
+    pub token_b_amount: u64,
+    pub pool_token_supply: u64,
+    pub timestamp: i64,
+}
+
+/// Emitted on balanced and smart deposits. The supply before and after give
+/// the price of a pool token in reserve tokens at the time of the deposit.
+#[event]
+pub struct DepositEvent {
+    pub amm: Pubkey,
+    pub user: Pubkey,
+    pub pool_token_amount: u64,
+    pub token_a_amount: u64,
+    pub token_b_amount: u64,
+    pub pool_token_supply_before: u64,
+    pub pool_token_supply_after: u64,
+}
+
+/// Emitted on balanced withdrawals. `pool_token_amount` is burned, and the
+/// supply after includes any withdraw fee minted to the fee account.
+#[event]
+pub struct WithdrawEvent {
+    pub amm: Pubkey,
+    pub user: Pubkey,
+    pub pool_token_amount: u64,
+    pub token_a_amount: u64,
+    pub token_b_amount: u64,
+    pub pool_token_supply_before: u64,
+    pub pool_token_supply_after: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct PoolDescription {
+    pub curve_type: CurveType,
+    pub curve_params: [u8; 32],
+    pub fees: FeeInput,
+    pub token_a_mint: Pubkey,
+    pub token_b_mint: Pubkey,
+    pub pool_mint: Pubkey,
+    // Fees as basis points of the traded or withdrawn amount, rounded down
+    pub trade_fee_bps: u16,
+    pub owner_trade_fee_bps: u16,
+    pub owner_withdraw_fee_bps: u16,
+    // Share of the owner trade fee paid to the host
+    pub host_fee_bps: u16,
+    pub flash_loan_fee_bps: u16,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct SwapLeg {
+    pub amount_in: u64,
+    pub minimum_amount_out: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct AuthorityInfo {
+    pub authority: Pubkey,
+    pub bump_seed: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct PoolShare {
+    pub pool_token_amount: u64,
+    pub token_a_amount: u64,
+    pub token_b_amount: u64,
+}
+
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
 pub struct CurveInput {
     pub curve_type: u8,
-    pub curve_params: u64,
+    // Calculator parameters, in the layout the curve packs into `SwapCurve`
+    pub curve_params: [u8; 32],
+}
+
+impl CurveInput {
+    pub const LEN: usize = 1 + 32;
+}
+
+/// Typed form of `CurveInput`, so pools can be built without knowing the
+/// curve type numbers or the parameter layout. `CurveInput` remains the
+/// format stored in `Amm` and passed to instructions.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Curve {
+    ConstantProduct,
+    // Amount of token A required to get 1 token B
+    ConstantPrice { price: u64 },
+    // Amount to offset the token B reserve by
+    Offset { token_b_offset: u64 },
+}
+
+impl From<Curve> for CurveInput {
+    fn from(curve: Curve) -> Self {
+        let mut curve_params = [0u8; 32];
+        let curve_type = match curve {
+            Curve::ConstantProduct => CurveType::ConstantProduct,
+            Curve::ConstantPrice { price } => {
+                ConstantPriceCurve {
+                    token_b_price: price,
+                }
+                .pack_into_slice(&mut curve_params);
+                CurveType::ConstantPrice
+            }
+            Curve::Offset { token_b_offset } => {
+                OffsetCurve { token_b_offset }.pack_into_slice(&mut curve_params);
+                CurveType::ConstantProductWithOffset
+            }
+        };
+        CurveInput {
+            curve_type: curve_type as u8,
+            curve_params,
+        }
+    }
+}
+
+impl TryFrom<CurveInput> for Curve {
+    type Error = SwapError;
+
+    fn try_from(curve_input: CurveInput) -> std::result::Result<Self, Self::Error> {
+        let curve_type = CurveType::try_from(curve_input.curve_type)
+            .map_err(|_| SwapError::UnsupportedCurveType)?;
+        let curve_params = &curve_input.curve_params;
+        let curve = match curve_type {
+            CurveType::ConstantProduct => Curve::ConstantProduct,
+            CurveType::ConstantPrice => Curve::ConstantPrice {
+                price: ConstantPriceCurve::unpack_from_slice(curve_params)
+                    .map_err(|_| SwapError::InvalidCurve)?
+                    .token_b_price,
+            },
+            CurveType::ConstantProductWithOffset => Curve::Offset {
+                token_b_offset: OffsetCurve::unpack_from_slice(curve_params)
+                    .map_err(|_| SwapError::InvalidCurve)?
+                    .token_b_offset,
+            },
+        };
+        Ok(curve)
+    }
 }
 
+/// Builds the swap curve described by `curve_input`. `SwapCurve` compares
+/// and clones through its packed form, so every curve type must survive a
+/// pack and unpack unchanged.
 pub fn build_curve(curve_input: &CurveInput) -> Result<SwapCurve> {
-    let curve_type = CurveType::try_from(curve_input.curve_type).unwrap();
+    let curve_type =
+        CurveType::try_from(curve_input.curve_type).map_err(|_| SwapError::UnsupportedCurveType)?;
     let calculator: Box<dyn CurveCalculator> = match curve_type {
-        CurveType::ConstantProduct => Box::new(ConstantProductCurve {}),
-        CurveType::ConstantPrice => unimplemented!(),
-        CurveType::ConstantProductWithOffset => unimplemented!(),
+        CurveType::ConstantProduct => Box::new(ConstantProductCurve::unpack_from_slice(
+            &curve_input.curve_params,
+        )?),
+        CurveType::ConstantPrice => Box::new(ConstantPriceCurve::unpack_from_slice(
+            &curve_input.curve_params,
+        )?),
+        CurveType::ConstantProductWithOffset => {
+            Box::new(OffsetCurve::unpack_from_slice(&curve_input.curve_params)?)
+        }
     };
     let curve = SwapCurve {
         curve_type,
@@ -764,7 +5032,734 @@ pub fn build_curve(curve_input: &CurveInput) -> Result<SwapCurve> {
     Ok(curve)
 }
 
+/// Converts a price to Q64.64, the fixed point format of the price views and
+/// the price cumulatives.
+fn to_q64(price: &PreciseNumber) -> Option<u128> {
+    price
+        .checked_mul(&PreciseNumber::new(1 << 64)?)?
+        .to_imprecise()
+}
+
+/// Computes the reserves holding the same value as `token_a_amount` and
+/// `token_b_amount` at `spot_price`, in token B per token A, split according
+/// to the target ratio. Both sides are rounded up so the pool never loses
+/// value.
+pub fn rebalanced_reserves(
+    token_a_amount: u64,
+    token_b_amount: u64,
+    spot_price: &PreciseNumber,
+    target_token_a_ratio: u64,
+    target_token_b_ratio: u64,
+) -> Option<(u64, u64)> {
+    let token_a_amount = PreciseNumber::new(u128::from(token_a_amount))?;
+    let token_b_amount = PreciseNumber::new(u128::from(token_b_amount))?;
+    let ratio_a = PreciseNumber::new(u128::from(target_token_a_ratio))?;
+    let ratio_b = PreciseNumber::new(u128::from(target_token_b_ratio))?;
+
+    // Value in token B is a * p + b, so keeping it constant along
+    // a' / b' = ratio_a / ratio_b gives a' = value * ratio_a / (ratio_a * p + ratio_b).
+    let value = token_a_amount
+        .checked_mul(spot_price)?
+        .checked_add(&token_b_amount)?;
+    let denominator = ratio_a.checked_mul(spot_price)?.checked_add(&ratio_b)?;
+    let new_token_a_amount = value
+        .checked_mul(&ratio_a)?
+        .checked_div(&denominator)?
+        .ceiling()?
+        .to_imprecise()?;
+    let new_token_b_amount = value
+        .checked_mul(&ratio_b)?
+        .checked_div(&denominator)?
+        .ceiling()?
+        .to_imprecise()?;
+
+    Some((
+        u64::try_from(new_token_a_amount).ok()?,
+        u64::try_from(new_token_b_amount).ok()?,
+    ))
+}
+
+/// Least of `amount` accepted with `slippage_bps` of slippage, which must be
+/// at most `BASIS_POINTS`.
+pub fn slippage_minimum(amount: u64, slippage_bps: u16) -> u64 {
+    let amount =
+        u128::from(amount) * u128::from(BASIS_POINTS - slippage_bps) / u128::from(BASIS_POINTS);
+    // Never above the original amount
+    amount as u64
+}
+
+/// Most of `amount` paid with `slippage_bps` of slippage, saturating at
+/// `u64::MAX`.
+pub fn slippage_maximum(amount: u64, slippage_bps: u16) -> u64 {
+    let amount = u128::from(amount) * (u128::from(BASIS_POINTS) + u128::from(slippage_bps))
+        / u128::from(BASIS_POINTS);
+    u64::try_from(amount).unwrap_or(u64::MAX)
+}
+
+/// Checks the pool mint can still be minted by the swap authority before any
+/// mint or burn CPI is attempted.
+pub fn validate_mint_authority(pool_mint: &Mint, authority: &Pubkey) -> Result<()> {
+    if pool_mint.mint_authority != COption::Some(*authority) {
+        return Err(SwapError::InvalidOwner.into());
+    }
+    Ok(())
+}
+
+/// Value of a pool holding `token_a_amount` and `token_b_amount` at the
+/// curve's spot price, in the destination token of `trade_direction`.
+pub fn pool_value(
+    curve: &SwapCurve,
+    token_a_amount: u128,
+    token_b_amount: u128,
+    trade_direction: TradeDirection,
+) -> Result<u128> {
+    let (source_amount, destination_amount) = match trade_direction {
+        TradeDirection::AtoB => (token_a_amount, token_b_amount),
+        TradeDirection::BtoA => (token_b_amount, token_a_amount),
+    };
+    let spot_price = curve
+        .calculator
+        .spot_price(token_a_amount, token_b_amount, trade_direction)
+        .ok_or(SwapError::FeeCalculationFailure)?;
+    let value = PreciseNumber::new(source_amount)
+        .and_then(|amount| amount.checked_mul(&spot_price))
+        .and_then(|amount| amount.floor())
+        .and_then(|amount| amount.to_imprecise())
+        .ok_or(SwapError::FeeCalculationFailure)?;
+    Ok(value
+        .checked_add(destination_amount)
+        .ok_or(SwapError::FeeCalculationFailure)?)
+}
+
+/// Checks that `pool_mint_amount` pool tokens minted for a fee of `fee` tokens
+/// claim no more than the fee's worth of a pool valued at `pool_value`, both
+/// measured in the token the fee is charged in, so fee minting can't dilute
+/// liquidity providers.
+pub fn validate_fee_mint(
+    fee: u128,
+    pool_mint_amount: u128,
+    pool_value: u128,
+    pool_supply: u64,
+) -> Result<()> {
+    let claim = U256::from(pool_mint_amount)
+        .checked_mul(U256::from(pool_value))
+        .and_then(|x| {
+            x.checked_div(U256::from(u128::from(pool_supply)) + U256::from(pool_mint_amount))
+        })
+        .ok_or(SwapError::FeeCalculationFailure)?;
+    if claim > U256::from(fee) {
+        return Err(SwapError::FeeCalculationFailure.into());
+    }
+    Ok(())
+}
+
+/// Mints the `pool_mint_amount` pool tokens of an owner trade fee with
+/// `mint_to`, to `pool_account` or split between `fee_recipients`, whose
+/// accounts are given in the same order in `fee_recipient_infos`.
+pub fn mint_owner_fee<'info>(
+    pool_mint_amount: u128,
+    pool_account: &AccountInfo<'info>,
+    fee_recipients: &[FeeRecipient],
+    fee_recipient_infos: &[AccountInfo<'info>],
+    mint_to: impl Fn(&AccountInfo<'info>, u64) -> Result<()>,
+) -> Result<()> {
+    if fee_recipients.is_empty() {
+        return mint_to(pool_account, to_u64(pool_mint_amount)?);
+    }
+    if fee_recipient_infos.len() < fee_recipients.len() {
+        return Err(SwapError::IncorrectFeeAccount.into());
+    }
+
+    // Shares round down, and the last recipient takes the dust
+    let mut remaining_amount = pool_mint_amount;
+    for (index, (fee_recipient, info)) in fee_recipients.iter().zip(fee_recipient_infos).enumerate()
+    {
+        if *info.key != fee_recipient.recipient {
+            return Err(SwapError::IncorrectFeeAccount.into());
+        }
+        let amount = if index == fee_recipients.len() - 1 {
+            remaining_amount
+        } else {
+            pool_mint_amount
+                .checked_mul(u128::from(fee_recipient.share_bps))
+                .map(|amount| amount / u128::from(BASIS_POINTS))
+                .ok_or(SwapError::FeeCalculationFailure)?
+        };
+        remaining_amount = remaining_amount
+            .checked_sub(amount)
+            .ok_or(SwapError::FeeCalculationFailure)?;
+        if amount == 0 {
+            continue;
+        }
+        mint_to(info, to_u64(amount)?)?;
+    }
+    Ok(())
+}
+
+/// Checks that `token_a` and `token_b` are the pool's reserves.
+pub fn validate_reserves(
+    amm: &Amm,
+    token_a: &Account<TokenAccount>,
+    token_b: &Account<TokenAccount>,
+) -> Result<()> {
+    if *token_a.to_account_info().key != amm.token_a_account
+        || *token_b.to_account_info().key != amm.token_b_account
+    {
+        return Err(SwapError::IncorrectSwapAccount.into());
+    }
+    if token_a.mint != amm.token_a_mint || token_b.mint != amm.token_b_mint {
+        return Err(SwapError::IncorrectMint.into());
+    }
+    Ok(())
+}
+
+/// Reads the reserve token account `info`, which must be the account of
+/// `reserve`.
+pub fn load_weighted_reserve(
+    reserve: &WeightedReserve,
+    info: &AccountInfo,
+) -> Result<TokenAccount> {
+    if *info.key != reserve.account {
+        return Err(SwapError::IncorrectSwapAccount.into());
+    }
+    TokenAccount::try_deserialize(&mut &info.try_borrow_data()?[..])
+}
+
+pub fn validate_remaining_accounts(remaining_accounts: &[AccountInfo]) -> Result<()> {
+    if remaining_accounts.len() > MAX_REMAINING_ACCOUNTS {
+        return Err(SwapError::TooManyAccounts.into());
+    }
+    Ok(())
+}
+
+/// Creates the temporary wrapped SOL account of `owner`, a PDA so that no
+/// extra signer is needed, holding `amount` on top of its rent reserve.
+pub fn wrap_sol<'info>(
+    program_id: &Pubkey,
+    owner: &AccountInfo<'info>,
+    wsol_account: &AccountInfo<'info>,
+    native_mint: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+    amount: u64,
+) -> Result<()> {
+    let (wsol_key, bump_seed) =
+        Pubkey::find_program_address(&[WSOL_SEED, owner.key.as_ref()], program_id);
+    if *wsol_account.key != wsol_key {
+        return Err(SwapError::InvalidInput.into());
+    }
+    if *native_mint.key != native_mint::ID {
+        return Err(SwapError::IncorrectMint.into());
+    }
+
+    let seeds = &[WSOL_SEED, owner.key.as_ref(), &[bump_seed][..]];
+    let space = spl_token::state::Account::LEN;
+    let lamports = Rent::get()?
+        .minimum_balance(space)
+        .checked_add(amount)
+        .ok_or(SwapError::ConversionFailure)?;
+
+    // Lamports sent to the address beforehand would make create_account
+    // fail, so top up and allocate instead; the surplus is returned on close
+    let current_lamports = wsol_account.lamports();
+    if current_lamports == 0 {
+        system_program::create_account(
+            CpiContext::new_with_signer(
+                system_program.clone(),
+                system_program::CreateAccount {
+                    from: owner.clone(),
+                    to: wsol_account.clone(),
+                },
+                &[&seeds[..]],
+            ),
+            lamports,
+            space as u64,
+            token_program.key,
+        )?;
+    } else {
+        let top_up = lamports.saturating_sub(current_lamports);
+        if top_up > 0 {
+            system_program::transfer(
+                CpiContext::new(
+                    system_program.clone(),
+                    system_program::Transfer {
+                        from: owner.clone(),
+                        to: wsol_account.clone(),
+                    },
+                ),
+                top_up,
+            )?;
+        }
+        system_program::allocate(
+            CpiContext::new_with_signer(
+                system_program.clone(),
+                system_program::Allocate {
+                    account_to_allocate: wsol_account.clone(),
+                },
+                &[&seeds[..]],
+            ),
+            space as u64,
+        )?;
+        system_program::assign(
+            CpiContext::new_with_signer(
+                system_program.clone(),
+                system_program::Assign {
+                    account_to_assign: wsol_account.clone(),
+                },
+                &[&seeds[..]],
+            ),
+            token_program.key,
+        )?;
+    }
+
+    token::initialize_account3(CpiContext::new(
+        token_program.clone(),
+        token::InitializeAccount3 {
+            account: wsol_account.clone(),
+            mint: native_mint.clone(),
+            authority: owner.clone(),
+        },
+    ))?;
+    token::sync_native(CpiContext::new(
+        token_program.clone(),
+        token::SyncNative {
+            account: wsol_account.clone(),
+        },
+    ))
+}
+
+/// Closes the temporary wrapped SOL account, returning its remaining balance
+/// and rent to `owner` as SOL.
+pub fn unwrap_sol<'info>(
+    owner: &AccountInfo<'info>,
+    wsol_account: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+) -> Result<()> {
+    token::close_account(CpiContext::new(
+        token_program.clone(),
+        token::CloseAccount {
+            account: wsol_account.clone(),
+            destination: owner.clone(),
+            authority: owner.clone(),
+        },
+    ))
+}
+
 pub fn authority_key(program_id: &Pubkey, info: Pubkey, bump_seed: u8) -> Result<Pubkey> {
     Pubkey::create_program_address(&[&info.to_bytes()[..32], &[bump_seed]], program_id)
         .or(Err(SwapError::InvalidProgramAddress.into()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::constant_product;
+
+    #[test]
+    fn curve_input_round_trip() {
+        // Every byte of the parameters survives, for curves with several
+        let curve_input = CurveInput {
+            curve_type: CurveType::ConstantProductWithOffset as u8,
+            curve_params: std::array::from_fn(|i| i as u8 + 1),
+        };
+        let bytes = curve_input.try_to_vec().unwrap();
+        assert_eq!(bytes.len(), CurveInput::LEN);
+        let unpacked = CurveInput::try_from_slice(&bytes).unwrap();
+        assert_eq!(unpacked.curve_type, curve_input.curve_type);
+        assert_eq!(unpacked.curve_params, curve_input.curve_params);
+    }
+
+    #[test]
+    fn amm_keeps_curve_params() {
+        let mut amm = Amm::try_from_slice(&[0; Amm::LEN]).unwrap();
+        amm.curve = CurveInput {
+            curve_type: CurveType::ConstantPrice as u8,
+            curve_params: std::array::from_fn(|i| 0xff - i as u8),
+        };
+        let bytes = amm.try_to_vec().unwrap();
+        assert_eq!(bytes.len(), Amm::LEN);
+        let unpacked = Amm::try_from_slice(&bytes).unwrap();
+        assert_eq!(unpacked.curve.curve_type, amm.curve.curve_type);
+        assert_eq!(unpacked.curve.curve_params, amm.curve.curve_params);
+    }
+
+    #[test]
+    fn curve_params_round_trip_through_swap_curve() {
+        let mut curve_params = [0u8; 32];
+        curve_params[..8].copy_from_slice(&0x0102_0304_0506_0708u64.to_le_bytes());
+        for curve_type in [
+            CurveType::ConstantProduct,
+            CurveType::ConstantPrice,
+            CurveType::ConstantProductWithOffset,
+        ] {
+            let curve = build_curve(&CurveInput {
+                curve_type: curve_type as u8,
+                curve_params,
+            })
+            .unwrap();
+            let mut packed = [0u8; SwapCurve::LEN];
+            curve.pack_into_slice(&mut packed);
+            assert_eq!(packed[0], curve_type as u8);
+            // The constant product has no parameters to pack
+            if curve_type == CurveType::ConstantProduct {
+                assert_eq!(packed[1..], [0u8; 32]);
+            } else {
+                assert_eq!(packed[1..], curve_params);
+            }
+            let unpacked = SwapCurve::unpack_from_slice(&packed).unwrap();
+            assert_eq!(unpacked.curve_type, curve_type);
+            assert!(unpacked == curve);
+        }
+    }
+
+    fn pool_mint(mint_authority: COption<Pubkey>) -> Mint {
+        let mut data = [0u8; spl_token::state::Mint::LEN];
+        spl_token::state::Mint {
+            mint_authority,
+            supply: 1_000,
+            decimals: 6,
+            is_initialized: true,
+            freeze_authority: COption::None,
+        }
+        .pack_into_slice(&mut data);
+        Mint::try_deserialize(&mut &data[..]).unwrap()
+    }
+
+    #[test]
+    fn validate_mint_authority_after_reassignment() {
+        let authority = Pubkey::new_unique();
+        assert!(validate_mint_authority(&pool_mint(COption::Some(authority)), &authority).is_ok());
+
+        // Authority moved to another key, or removed altogether
+        for mint_authority in [COption::Some(Pubkey::new_unique()), COption::None] {
+            assert_eq!(
+                validate_mint_authority(&pool_mint(mint_authority), &authority),
+                Err(SwapError::InvalidOwner.into())
+            );
+        }
+    }
+
+    /// Pool with every field zeroed, for tests of `Amm` methods.
+    fn zeroed_amm() -> Amm {
+        Amm::try_from_slice(&[0; Amm::LEN]).unwrap()
+    }
+
+    #[test]
+    fn swap_fees_in_fee_free_window() {
+        let mut amm = zeroed_amm();
+        amm.fees = FeeInput::from_bps(25, 5, 0, 20);
+        amm.fee_free_until = 1_000;
+        let curve = SwapCurve::default();
+        let quote = |timestamp| {
+            compute_swap(
+                10_000,
+                1_000_000,
+                2_000_000,
+                &amm.swap_fees(timestamp).unwrap(),
+                &curve,
+                TradeDirection::AtoB,
+            )
+            .unwrap()
+        };
+        let swapped = constant_product::swap(10_000, 1_000_000, 2_000_000)
+            .unwrap()
+            .destination_amount_swapped;
+
+        // Before the cutoff the whole curve output goes to the user
+        for timestamp in [0, 999] {
+            let amounts = quote(timestamp);
+            assert_eq!(u128::from(amounts.output_amount), swapped);
+            assert_eq!(amounts.trade_fee + amounts.owner_fee + amounts.host_fee, 0);
+        }
+
+        // From the cutoff on the configured fees are taken again
+        for timestamp in [1_000, 1_001, i64::MAX] {
+            let amounts = quote(timestamp);
+            assert!(amounts.owner_fee > 0);
+            assert!(amounts.host_fee > 0);
+            assert_eq!(
+                u128::from(amounts.output_amount) + amounts.trade_fee + amounts.owner_fee,
+                swapped
+            );
+        }
+    }
+
+    #[test]
+    fn corrupted_curve_type() {
+        let mut amm = zeroed_amm();
+        assert!(amm.swap_curve().is_ok());
+
+        // Only 0, 1 and 2 are curve types
+        for curve_type in [3, 0x7f, u8::MAX] {
+            amm.curve.curve_type = curve_type;
+            assert_eq!(
+                amm.swap_curve().unwrap_err(),
+                SwapError::CorruptedPoolState.into()
+            );
+        }
+    }
+
+    #[test]
+    fn corrupted_fees() {
+        let mut amm = zeroed_amm();
+        amm.fees = FeeInput::from_bps(25, 5, 0, 20);
+        assert!(amm.curve_fees().is_ok());
+
+        // A fee numerator at or above its denominator was never accepted
+        amm.fees.trade_fee_numerator = amm.fees.trade_fee_denominator;
+        assert_eq!(
+            amm.curve_fees().unwrap_err(),
+            SwapError::CorruptedPoolState.into()
+        );
+        assert_eq!(
+            amm.swap_fees(0).unwrap_err(),
+            SwapError::CorruptedPoolState.into()
+        );
+    }
+
+    #[test]
+    fn config_hash_covers_every_config_field() {
+        let amm = zeroed_amm();
+        let hash = amm.config_hash().unwrap();
+        let changes: [fn(&mut Amm); 24] = [
+            |amm| amm.owner = Pubkey::new_unique(),
+            |amm| amm.fee_owner = Pubkey::new_unique(),
+            |amm| amm.token_a_account = Pubkey::new_unique(),
+            |amm| amm.token_b_account = Pubkey::new_unique(),
+            |amm| amm.pool_mint = Pubkey::new_unique(),
+            |amm| amm.pool_fee_account = Pubkey::new_unique(),
+            |amm| amm.fees.trade_fee_numerator = 1,
+            |amm| amm.fees.owner_withdraw_fee_denominator = 1,
+            |amm| amm.fees.dynamic_fee_numerator = 1,
+            |amm| amm.curve.curve_type = CurveType::ConstantPrice as u8,
+            |amm| amm.curve.curve_params[31] = 1,
+            |amm| amm.min_pool_value = 1,
+            |amm| amm.deposits_enabled = true,
+            |amm| amm.fee_free_until = 1,
+            |amm| amm.max_price_impact_bps = 1,
+            |amm| amm.exit_fee_bps = 1,
+            |amm| amm.exit_fee_decay_period = 1,
+            |amm| amm.max_swap_fraction_bps = 1,
+            |amm| amm.default_host_fee_account = Pubkey::new_unique(),
+            |amm| amm.reconcile_reserves = true,
+            |amm| amm.max_reserve = 1,
+            |amm| amm.fee_split[0].recipient = Pubkey::new_unique(),
+            |amm| amm.fee_split[MAX_FEE_RECIPIENTS - 1].share_bps = 1,
+            |amm| amm.pending_owner = Pubkey::new_unique(),
+        ];
+        for (i, change) in changes.iter().enumerate() {
+            let mut changed = zeroed_amm();
+            change(&mut changed);
+            assert_ne!(changed.config_hash().unwrap(), hash, "change {i}");
+        }
+
+        // State moved by trading isn't configuration
+        let mut traded = zeroed_amm();
+        traded.reserve_a = 1_000;
+        traded.cumulative_trade_fee = 1;
+        traded.last_update_slot = 1;
+        assert_eq!(traded.config_hash().unwrap(), hash);
+    }
+
+    #[test]
+    fn slippage_bounds() {
+        assert_eq!(slippage_minimum(10_000, 0), 10_000);
+        assert_eq!(slippage_minimum(10_000, 50), 9_950);
+        assert_eq!(slippage_minimum(10_000, BASIS_POINTS), 0);
+        // Rounds down, never above the amount
+        assert_eq!(slippage_minimum(999, 1), 998);
+        assert_eq!(slippage_minimum(u64::MAX, 0), u64::MAX);
+
+        assert_eq!(slippage_maximum(10_000, 0), 10_000);
+        assert_eq!(slippage_maximum(10_000, 50), 10_050);
+        assert_eq!(slippage_maximum(10_000, BASIS_POINTS), 20_000);
+        assert_eq!(slippage_maximum(u64::MAX, 1), u64::MAX);
+    }
+
+    #[test]
+    fn price_impact_against_curve_spot_price() {
+        let mut amm = zeroed_amm();
+        amm.max_price_impact_bps = 100;
+        // The offset prices token B from an empty reserve, where the reserve
+        // ratio alone would give a spot price of zero
+        let curve = SwapCurve {
+            curve_type: CurveType::ConstantProductWithOffset,
+            calculator: Box::new(OffsetCurve {
+                token_b_offset: 1_000_000,
+            }),
+        };
+        let validate = |output_amount| {
+            amm.validate_price_impact(
+                &curve,
+                1_000,
+                output_amount,
+                1_000_000,
+                0,
+                TradeDirection::AtoB,
+            )
+        };
+        assert!(validate(999).is_ok());
+        assert!(validate(990).is_ok());
+        assert!(matches!(validate(989), Err(SwapError::PriceImpactTooHigh)));
+
+        // A constant price ignores the reserves entirely
+        let curve = SwapCurve {
+            curve_type: CurveType::ConstantPrice,
+            calculator: Box::new(ConstantPriceCurve { token_b_price: 4 }),
+        };
+        let validate = |output_amount| {
+            amm.validate_price_impact(
+                &curve,
+                4_000,
+                output_amount,
+                1,
+                1_000_000,
+                TradeDirection::AtoB,
+            )
+        };
+        assert!(validate(1_000).is_ok());
+        assert!(validate(990).is_ok());
+        assert!(matches!(validate(989), Err(SwapError::PriceImpactTooHigh)));
+    }
+
+    #[test]
+    fn exit_fee_decays_only_on_deposited_pool_tokens() {
+        let mut amm = zeroed_amm();
+        amm.exit_fee_bps = 500;
+        amm.exit_fee_decay_period = 100;
+        let fees = CurveFees::default();
+        let withdraw_fee = |deposited_pool_tokens, timestamp| {
+            amm.withdraw_fee(&fees, 10_000, deposited_pool_tokens, 0, timestamp)
+                .unwrap()
+        };
+
+        assert_eq!(withdraw_fee(10_000, 0), 500);
+        assert_eq!(withdraw_fee(10_000, 50), 250);
+        assert_eq!(withdraw_fee(10_000, 100), 0);
+        assert_eq!(withdraw_fee(10_000, 1_000), 0);
+        // A position that never deposited doesn't shorten the decay
+        assert_eq!(withdraw_fee(0, 1_000), 500);
+        // Pool tokens beyond the recorded deposit pay the full exit fee
+        assert_eq!(withdraw_fee(4_000, 100), 300);
+        assert_eq!(withdraw_fee(20_000, 100), 0);
+    }
+
+    #[test]
+    fn position_averages_deposit_times() {
+        let mut position = Position {
+            amm: Pubkey::default(),
+            owner: Pubkey::default(),
+            pool_token_account: Pubkey::default(),
+            pool_token_amount: 0,
+            bump_seed: 0,
+            deposit_ts: 0,
+            deposited_pool_tokens: 0,
+        };
+        position.record_deposit(0, 500).unwrap();
+        assert_eq!(position.deposited_pool_tokens, 0);
+
+        position.record_deposit(100, 1_000).unwrap();
+        assert_eq!(position.deposit_ts, 1_000);
+        assert_eq!(position.deposited_pool_tokens, 100);
+
+        // A top-up moves the deposit time by its share of the pool tokens
+        position.record_deposit(300, 2_000).unwrap();
+        assert_eq!(position.deposit_ts, 1_750);
+        assert_eq!(position.deposited_pool_tokens, 400);
+
+        assert!(position.record_deposit(u64::MAX, 2_000).is_none());
+    }
+
+    #[test]
+    fn fee_mint_claims_no_more_than_the_fee() {
+        let curve = SwapCurve {
+            curve_type: CurveType::ConstantProduct,
+            calculator: Box::new(ConstantProductCurve),
+        };
+        // An A to B swap leaves these reserves, with the fee of 1_000 B
+        // tokens taken from the output
+        let (token_a_amount, token_b_amount) = (1_000_000, 2_000_000);
+        let pool_supply = 1_000_000;
+        let fee = 1_000;
+        let value =
+            pool_value(&curve, token_a_amount, token_b_amount, TradeDirection::AtoB).unwrap();
+        assert_eq!(value, 4_000_000);
+
+        let deposit = |trade_direction| {
+            curve
+                .calculator
+                .deposit_single_token_type(
+                    fee,
+                    token_a_amount,
+                    token_b_amount,
+                    u128::from(pool_supply),
+                    trade_direction,
+                    RoundDirection::Floor,
+                )
+                .unwrap()
+        };
+        let pool_mint_amount = deposit(TradeDirection::BtoA);
+        assert!(validate_fee_mint(fee, pool_mint_amount, value, pool_supply).is_ok());
+
+        // Twice the pool tokens claim twice the fee
+        assert!(validate_fee_mint(fee, pool_mint_amount * 2, value, pool_supply).is_err());
+        // So does depositing the fee on the source side, where each token is
+        // worth two of the fee's
+        let source_mint_amount = deposit(TradeDirection::AtoB);
+        assert!(source_mint_amount > pool_mint_amount);
+        assert!(validate_fee_mint(fee, source_mint_amount, value, pool_supply).is_err());
+    }
+
+    #[test]
+    fn rent_exempt_at_current_size() {
+        let key = Pubkey::new_unique();
+        let rent = Rent::default();
+        let validate = |data_len: usize| {
+            let mut lamports = rent.minimum_balance(Amm::LEN);
+            let mut data = vec![0; data_len];
+            let amm_info =
+                AccountInfo::new(&key, false, true, &mut lamports, &mut data, &key, false, 0);
+            Amm::validate_rent_exempt(&amm_info, &rent)
+        };
+        assert!(validate(Amm::LEN).is_ok());
+
+        // Growing the account without topping up its lamports loses the
+        // exemption
+        assert!(validate(Amm::LEN + 64).is_err());
+    }
+
+    #[test]
+    fn rebalances_to_target_ratio_at_spot_price() {
+        // Priced at two A per B, a pool holding four times as much B as A is
+        // worth 900_000 B
+        let curve = SwapCurve {
+            curve_type: CurveType::ConstantPrice,
+            calculator: Box::new(ConstantPriceCurve { token_b_price: 2 }),
+        };
+        let spot_price = curve
+            .calculator
+            .spot_price(200_000, 800_000, TradeDirection::AtoB)
+            .unwrap();
+        assert_eq!(
+            rebalanced_reserves(200_000, 800_000, &spot_price, 1, 1).unwrap(),
+            (600_000, 600_000)
+        );
+
+        // The reserve ratio alone prices A at four B and overvalues the pool
+        let reserve_price = PreciseNumber::new(4).unwrap();
+        assert_eq!(
+            rebalanced_reserves(200_000, 800_000, &reserve_price, 1, 1).unwrap(),
+            (320_000, 320_000)
+        );
+
+        // A constant product pool is priced by its reserves
+        let spot_price = SwapCurve::default()
+            .calculator
+            .spot_price(200_000, 800_000, TradeDirection::AtoB)
+            .unwrap();
+        assert_eq!(
+            rebalanced_reserves(200_000, 800_000, &spot_price, 1, 6).unwrap(),
+            (160_000, 960_000)
+        );
+    }
+}
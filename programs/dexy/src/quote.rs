@@ -0,0 +1,478 @@
+use anchor_lang::prelude::{borsh, AnchorDeserialize, AnchorSerialize};
+
+use crate::{
+    convert::try_to_u64,
+    curve::{
+        base::{CurveType, SwapCurve},
+        calculator::{RoundDirection, TradeDirection},
+        constant_product,
+        fees::CurveFees,
+    },
+    SwapError,
+};
+
+/// Token amounts resulting from a swap once fees are taken out.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, Default, PartialEq)]
+pub struct SwapAmounts {
+    /// Amount of destination token sent to the user
+    pub output_amount: u64,
+    /// Amount of destination token kept by the pool for liquidity providers
+    pub trade_fee: u128,
+    /// Amount of destination token converted to pool tokens for the owner
+    pub owner_fee: u128,
+    /// Amount of destination token converted to pool tokens for the host
+    pub host_fee: u128,
+}
+
+/// Quotes a swap of `amount_in` against reserves of `swap_source_amount` and
+/// `swap_destination_amount`, taking the trade, owner and host fees out of the
+/// destination amount.
+pub fn compute_swap(
+    amount_in: u64,
+    swap_source_amount: u64,
+    swap_destination_amount: u64,
+    fees: &CurveFees,
+    curve: &SwapCurve,
+    trade_direction: TradeDirection,
+) -> Result<SwapAmounts, SwapError> {
+    // Constant product, the common curve, is called directly rather than
+    // through the calculator trait object
+    let result = match curve.curve_type {
+        CurveType::ConstantProduct => constant_product::swap(
+            u128::from(amount_in),
+            u128::from(swap_source_amount),
+            u128::from(swap_destination_amount),
+        ),
+        _ => curve.calculator.swap_without_token_fees(
+            u128::from(amount_in),
+            u128::from(swap_source_amount),
+            u128::from(swap_destination_amount),
+            trade_direction,
+        ),
+    }
+    .ok_or(SwapError::ZeroTradingTokens)?;
+
+    // Constant product swaps round the destination left in the pool up and
+    // can't empty it, but curves with a fixed price or a virtual offset can
+    // price more than the reserve holds
+    if result.destination_amount_swapped >= u128::from(swap_destination_amount) {
+        return Err(SwapError::InsufficientLiquidity);
+    }
+
+    let (swap_token_a_amount, swap_token_b_amount) = match trade_direction {
+        TradeDirection::AtoB => (swap_source_amount, swap_destination_amount),
+        TradeDirection::BtoA => (swap_destination_amount, swap_source_amount),
+    };
+    let trade_fee = fees
+        .trading_fee(result.destination_amount_swapped)
+        .and_then(|trade_fee| {
+            fees.dynamic_trading_fee(
+                trade_fee,
+                u128::from(swap_source_amount).checked_add(result.source_amount_swapped)?,
+                u128::from(swap_destination_amount)
+                    .checked_sub(result.destination_amount_swapped)?,
+                &curve.calculator.spot_price(
+                    u128::from(swap_token_a_amount),
+                    u128::from(swap_token_b_amount),
+                    trade_direction,
+                )?,
+            )
+        })
+        .ok_or(SwapError::FeeCalculationFailure)?;
+
+    let mut owner_fee = fees
+        .owner_trading_fee(result.destination_amount_swapped)
+        .ok_or(SwapError::FeeCalculationFailure)?;
+
+    let host_fee = if owner_fee > 0 {
+        fees.host_fee(owner_fee)
+            .ok_or(SwapError::FeeCalculationFailure)?
+    } else {
+        0
+    };
+
+    if host_fee > 0 {
+        owner_fee = owner_fee
+            .checked_sub(host_fee)
+            .ok_or(SwapError::FeeCalculationFailure)?;
+    }
+
+    let total_fees = trade_fee
+        .checked_add(owner_fee)
+        .ok_or(SwapError::FeeCalculationFailure)?;
+
+    let destination_amount_swapped = result
+        .destination_amount_swapped
+        .checked_sub(total_fees)
+        .ok_or(SwapError::FeeCalculationFailure)?;
+
+    let output_amount = try_to_u64(destination_amount_swapped)?;
+
+    // Dust swaps whose output rounds to zero after fees would take the input
+    // for nothing
+    if output_amount == 0 {
+        return Err(SwapError::ZeroTradingTokens);
+    }
+
+    Ok(SwapAmounts {
+        output_amount,
+        trade_fee,
+        owner_fee,
+        host_fee,
+    })
+}
+
+/// Finds the largest part of `amount_in` that swaps at no worse than the
+/// limit price `minimum_amount_out / amount_in`, for partial fills.
+///
+/// The average price of a swap falls as it grows, so the input meeting the
+/// limit is found by bisection over `compute_swap` rather than by inverting
+/// each curve. Returns 0 if no part of the input meets the limit.
+pub fn compute_partial_fill(
+    amount_in: u64,
+    minimum_amount_out: u64,
+    swap_source_amount: u64,
+    swap_destination_amount: u64,
+    fees: &CurveFees,
+    curve: &SwapCurve,
+    trade_direction: TradeDirection,
+) -> u64 {
+    let meets_limit = |fill_amount: u64| {
+        compute_swap(
+            fill_amount,
+            swap_source_amount,
+            swap_destination_amount,
+            fees,
+            curve,
+            trade_direction,
+        )
+        .is_ok_and(|amounts| {
+            u128::from(amounts.output_amount) * u128::from(amount_in)
+                >= u128::from(minimum_amount_out) * u128::from(fill_amount)
+        })
+    };
+
+    if meets_limit(amount_in) {
+        return amount_in;
+    }
+    // meets_limit(high) is false, and meets_limit(low) is true or low is 0
+    let (mut low, mut high) = (0, amount_in);
+    while high - low > 1 {
+        let middle = low + (high - low) / 2;
+        if meets_limit(middle) {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+    low
+}
+
+/// Pool tokens minted by a single-token deposit, and the fee it pays.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, Default, PartialEq)]
+pub struct DepositAmounts {
+    /// Pool tokens minted to the depositor
+    pub pool_token_amount: u64,
+    /// Trade fee on the half of the deposit notionally swapped for the other
+    /// token, in source tokens
+    pub trade_fee: u128,
+}
+
+/// Quotes a deposit of `source_amount` of the token given by
+/// `trade_direction` alone, as `SwapCurve::deposit_single_token_type` mints
+/// it, rounding down.
+pub fn compute_deposit_single_token_type(
+    source_amount: u64,
+    swap_token_a_amount: u64,
+    swap_token_b_amount: u64,
+    pool_supply: u64,
+    fees: &CurveFees,
+    curve: &SwapCurve,
+    trade_direction: TradeDirection,
+) -> Result<DepositAmounts, SwapError> {
+    let source_amount = u128::from(source_amount);
+    let half_source_amount = std::cmp::max(1, source_amount / 2);
+    let trade_fee = fees
+        .trading_fee(half_source_amount)
+        .ok_or(SwapError::FeeCalculationFailure)?;
+
+    let pool_token_amount = curve
+        .deposit_single_token_type(
+            source_amount,
+            u128::from(swap_token_a_amount),
+            u128::from(swap_token_b_amount),
+            u128::from(pool_supply),
+            trade_direction,
+            RoundDirection::Floor,
+            fees,
+        )
+        .ok_or(SwapError::ZeroTradingTokens)?;
+    let pool_token_amount = try_to_u64(pool_token_amount)?;
+    if pool_token_amount == 0 {
+        return Err(SwapError::ZeroTradingTokens);
+    }
+
+    Ok(DepositAmounts {
+        pool_token_amount,
+        trade_fee,
+    })
+}
+
+/// Smallest deposits that mint at least one pool token.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, Default, PartialEq)]
+pub struct MinimumDeposit {
+    /// Smallest pool token amount a balanced deposit pays both reserves for
+    pub pool_token_amount: u64,
+    /// Token A paid for `pool_token_amount`
+    pub token_a_amount: u64,
+    /// Token B paid for `pool_token_amount`
+    pub token_b_amount: u64,
+    /// Smallest single-token deposit of token A, 0 if none mints
+    pub single_token_a_amount: u64,
+    /// Smallest single-token deposit of token B, 0 if none mints
+    pub single_token_b_amount: u64,
+}
+
+/// Finds the smallest deposits minting a pool token against the given
+/// reserves and supply.
+///
+/// A balanced deposit pays nothing into a reserve whose token amount rounds
+/// down to zero, which happens below `supply / reserve` pool tokens, so the
+/// balanced minimum is the smallest amount paying into both. Curves round
+/// differently, so the threshold is searched for through
+/// `pool_tokens_to_trading_tokens` rather than computed in closed form, and
+/// likewise for single-token deposits.
+pub fn compute_minimum_deposit(
+    swap_token_a_amount: u64,
+    swap_token_b_amount: u64,
+    pool_supply: u64,
+    fees: &CurveFees,
+    curve: &SwapCurve,
+) -> Result<MinimumDeposit, SwapError> {
+    let mints_balanced = |pool_tokens: u64| {
+        curve
+            .calculator
+            .pool_tokens_to_trading_tokens(
+                u128::from(pool_tokens),
+                u128::from(pool_supply),
+                u128::from(swap_token_a_amount),
+                u128::from(swap_token_b_amount),
+                RoundDirection::Ceil,
+            )
+            .filter(|tokens| tokens.token_a_amount > 0 && tokens.token_b_amount > 0)
+            .is_some()
+    };
+    let pool_token_amount =
+        minimum_satisfying(mints_balanced).ok_or(SwapError::ZeroTradingTokens)?;
+    let tokens = curve
+        .calculator
+        .pool_tokens_to_trading_tokens(
+            u128::from(pool_token_amount),
+            u128::from(pool_supply),
+            u128::from(swap_token_a_amount),
+            u128::from(swap_token_b_amount),
+            RoundDirection::Ceil,
+        )
+        .ok_or(SwapError::ZeroTradingTokens)?;
+
+    let mints_single = |trade_direction: TradeDirection| {
+        move |source_amount: u64| {
+            curve
+                .deposit_single_token_type(
+                    u128::from(source_amount),
+                    u128::from(swap_token_a_amount),
+                    u128::from(swap_token_b_amount),
+                    u128::from(pool_supply),
+                    trade_direction,
+                    RoundDirection::Floor,
+                    fees,
+                )
+                .is_some_and(|pool_tokens| pool_tokens > 0)
+        }
+    };
+
+    Ok(MinimumDeposit {
+        pool_token_amount,
+        token_a_amount: try_to_u64(tokens.token_a_amount)?,
+        token_b_amount: try_to_u64(tokens.token_b_amount)?,
+        single_token_a_amount: minimum_satisfying(mints_single(TradeDirection::AtoB)).unwrap_or(0),
+        single_token_b_amount: minimum_satisfying(mints_single(TradeDirection::BtoA)).unwrap_or(0),
+    })
+}
+
+/// Ratio of the live reserves as a fraction in lowest terms.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, Default, PartialEq)]
+pub struct ReserveRatio {
+    /// Token A side of the ratio
+    pub token_a_amount: u64,
+    /// Token B side of the ratio
+    pub token_b_amount: u64,
+}
+
+/// Reduces the reserves `swap_token_a_amount : swap_token_b_amount` by their
+/// greatest common divisor, so a balanced deposit can be sized as any
+/// multiple of the result.
+pub fn compute_reserve_ratio(
+    swap_token_a_amount: u64,
+    swap_token_b_amount: u64,
+) -> Result<ReserveRatio, SwapError> {
+    if swap_token_a_amount == 0 && swap_token_b_amount == 0 {
+        return Err(SwapError::EmptySupply);
+    }
+    let (mut x, mut y) = (swap_token_a_amount, swap_token_b_amount);
+    while y != 0 {
+        (x, y) = (y, x % y);
+    }
+    Ok(ReserveRatio {
+        token_a_amount: swap_token_a_amount / x,
+        token_b_amount: swap_token_b_amount / x,
+    })
+}
+
+/// Smallest positive amount for which `predicate` holds, assuming it keeps
+/// holding for larger amounts. Doubles up to a bound, then bisects, so the
+/// cost grows with the log of the answer.
+fn minimum_satisfying(predicate: impl Fn(u64) -> bool) -> Option<u64> {
+    let mut high: u64 = 1;
+    while !predicate(high) {
+        high = high.checked_mul(2)?;
+    }
+    let mut low = high / 2;
+    // predicate(low) is false, or low is 0
+    while high - low > 1 {
+        let middle = low + (high - low) / 2;
+        if predicate(middle) {
+            high = middle;
+        } else {
+            low = middle;
+        }
+    }
+    Some(high)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::{constant_price::ConstantPriceCurve, test_util::Rng};
+
+    fn fees() -> CurveFees {
+        CurveFees {
+            trade_fee_numerator: 25,
+            trade_fee_denominator: 10_000,
+            owner_trade_fee_numerator: 5,
+            owner_trade_fee_denominator: 10_000,
+            host_fee_numerator: 20,
+            host_fee_denominator: 100,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn compute_swap_larger_input_never_yields_less() {
+        let (fees, curve) = (fees(), SwapCurve::default());
+        let mut rng = Rng(0x5eed);
+        for _ in 0..5_000 {
+            let swap_source_amount = rng.amount(15) as u64;
+            let swap_destination_amount = rng.amount(15) as u64;
+            let amount_in = rng.amount(15) as u64;
+            let larger_amount_in = amount_in.saturating_add(rng.amount(15) as u64);
+            let quote = |amount_in| {
+                compute_swap(
+                    amount_in,
+                    swap_source_amount,
+                    swap_destination_amount,
+                    &fees,
+                    &curve,
+                    TradeDirection::AtoB,
+                )
+                .map(|amounts| amounts.output_amount)
+            };
+            // Inputs the curve can't price are rejected rather than quoted
+            // for less
+            if let (Ok(output), Ok(larger_output)) = (quote(amount_in), quote(larger_amount_in)) {
+                assert!(larger_output >= output);
+            }
+        }
+    }
+
+    #[test]
+    fn compute_swap_splits_curve_output_into_fees() {
+        let (fees, curve) = (fees(), SwapCurve::default());
+        let mut rng = Rng(0xcafe);
+        for _ in 0..5_000 {
+            let swap_source_amount = rng.amount(15) as u64;
+            let swap_destination_amount = rng.amount(15) as u64;
+            let amount_in = rng.amount(15) as u64;
+            let Ok(amounts) = compute_swap(
+                amount_in,
+                swap_source_amount,
+                swap_destination_amount,
+                &fees,
+                &curve,
+                TradeDirection::AtoB,
+            ) else {
+                continue;
+            };
+            let result = constant_product::swap(
+                u128::from(amount_in),
+                u128::from(swap_source_amount),
+                u128::from(swap_destination_amount),
+            )
+            .unwrap();
+            assert!(amounts.output_amount > 0);
+            assert!(amounts.output_amount < swap_destination_amount);
+            assert_eq!(
+                u128::from(amounts.output_amount) + amounts.trade_fee + amounts.owner_fee,
+                result.destination_amount_swapped
+            );
+        }
+    }
+
+    #[test]
+    fn compute_swap_rejects_zero_input() {
+        let result = compute_swap(
+            0,
+            1_000_000,
+            1_000_000,
+            &fees(),
+            &SwapCurve::default(),
+            TradeDirection::AtoB,
+        );
+        assert!(matches!(result, Err(SwapError::ZeroTradingTokens)));
+    }
+
+    #[test]
+    fn compute_swap_dynamic_fee_rises_as_destination_is_depleted() {
+        let fees = CurveFees {
+            owner_withdraw_fee_numerator: 25,
+            owner_withdraw_fee_denominator: 10_000,
+            dynamic_fee_numerator: 1,
+            dynamic_fee_denominator: 1,
+            ..Default::default()
+        };
+        // One B buys four A, so these reserves start out balanced in value
+        let curve = SwapCurve {
+            curve_type: CurveType::ConstantPrice,
+            calculator: Box::new(ConstantPriceCurve { token_b_price: 4 }),
+        };
+        let (mut swap_source_amount, mut swap_destination_amount) = (250_000, 1_000_000);
+        let mut last_trade_fee = 0;
+        for _ in 0..10 {
+            let amounts = compute_swap(
+                10_000,
+                swap_source_amount,
+                swap_destination_amount,
+                &fees,
+                &curve,
+                TradeDirection::BtoA,
+            )
+            .unwrap();
+            assert!(amounts.trade_fee > last_trade_fee);
+            last_trade_fee = amounts.trade_fee;
+            swap_source_amount += 10_000;
+            swap_destination_amount -= amounts.output_amount;
+        }
+        // Past the base fee of 25 bps on the 40_000 A each swap buys
+        assert!(last_trade_fee > 100);
+    }
+}
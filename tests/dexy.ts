@@ -1,16 +1,2074 @@
 import * as anchor from "@coral-xyz/anchor";
-import { Program } from "@coral-xyz/anchor";
+import { BN, Program } from "@coral-xyz/anchor";
+import {
+  Keypair,
+  PublicKey,
+  SystemProgram,
+  Transaction,
+  TransactionInstruction,
+} from "@solana/web3.js";
+import { assert } from "chai";
 import { Dexy } from "../target/types/dexy";
 
+const TOKEN_PROGRAM_ID = anchor.utils.token.TOKEN_PROGRAM_ID;
+const BPF_LOADER_UPGRADEABLE_ID = new PublicKey(
+  "BPFLoaderUpgradeab1e11111111111111111111111"
+);
+const MINT_SIZE = 82;
+const ACCOUNT_SIZE = 165;
+
+// SPL token instructions, encoded by hand to avoid an extra dependency
+const INITIALIZE_ACCOUNT_3 = 18;
+const INITIALIZE_MINT_2 = 20;
+const MINT_TO = 7;
+
+const ZERO_FEES = {
+  tradeFeeNumerator: new BN(0),
+  tradeFeeDenominator: new BN(0),
+  ownerTradeFeeNumerator: new BN(0),
+  ownerTradeFeeDenominator: new BN(0),
+  ownerWithdrawFeeNumerator: new BN(0),
+  ownerWithdrawFeeDenominator: new BN(0),
+  hostFeeNumerator: new BN(0),
+  hostFeeDenominator: new BN(0),
+  flashLoanFeeNumerator: new BN(0),
+  flashLoanFeeDenominator: new BN(0),
+  dynamicFeeNumerator: new BN(0),
+  dynamicFeeDenominator: new BN(0),
+};
+
+const CONSTANT_PRODUCT = { curveType: 0, curveParams: new Array(32).fill(0) };
+
 describe("dexy", () => {
   // Configure the client to use the local cluster.
-  anchor.setProvider(anchor.AnchorProvider.env());
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
 
   const program = anchor.workspace.Dexy as Program<Dexy>;
+  const payer = provider.wallet.publicKey;
+
+  const amm = Keypair.generate();
+  const [authority] = PublicKey.findProgramAddressSync(
+    [amm.publicKey.toBuffer()],
+    program.programId
+  );
+
+  const reserveA = new BN(1_000_000);
+  const reserveB = new BN(2_000_000);
+
+  let mintA: PublicKey;
+  let mintB: PublicKey;
+  let poolMint: PublicKey;
+  let tokenA: PublicKey;
+  let tokenB: PublicKey;
+  let feeAccount: PublicKey;
+  let userTokenA: PublicKey;
+  let userTokenB: PublicKey;
+  let userPoolToken: PublicKey;
+
+  async function send(
+    instructions: TransactionInstruction[],
+    signers: Keypair[] = []
+  ) {
+    await provider.sendAndConfirm(
+      new Transaction().add(...instructions),
+      signers
+    );
+  }
+
+  async function createMint(mintAuthority: PublicKey): Promise<PublicKey> {
+    const mint = Keypair.generate();
+    const lamports =
+      await provider.connection.getMinimumBalanceForRentExemption(MINT_SIZE);
+    const data = Buffer.concat([
+      Buffer.from([INITIALIZE_MINT_2, 0]),
+      mintAuthority.toBuffer(),
+      Buffer.from([0]),
+    ]);
+    await send(
+      [
+        SystemProgram.createAccount({
+          fromPubkey: payer,
+          newAccountPubkey: mint.publicKey,
+          lamports,
+          space: MINT_SIZE,
+          programId: TOKEN_PROGRAM_ID,
+        }),
+        new TransactionInstruction({
+          programId: TOKEN_PROGRAM_ID,
+          keys: [{ pubkey: mint.publicKey, isSigner: false, isWritable: true }],
+          data,
+        }),
+      ],
+      [mint]
+    );
+    return mint.publicKey;
+  }
+
+  async function createTokenAccount(
+    mint: PublicKey,
+    owner: PublicKey
+  ): Promise<PublicKey> {
+    const account = Keypair.generate();
+    const lamports =
+      await provider.connection.getMinimumBalanceForRentExemption(ACCOUNT_SIZE);
+    const data = Buffer.concat([
+      Buffer.from([INITIALIZE_ACCOUNT_3]),
+      owner.toBuffer(),
+    ]);
+    await send(
+      [
+        SystemProgram.createAccount({
+          fromPubkey: payer,
+          newAccountPubkey: account.publicKey,
+          lamports,
+          space: ACCOUNT_SIZE,
+          programId: TOKEN_PROGRAM_ID,
+        }),
+        new TransactionInstruction({
+          programId: TOKEN_PROGRAM_ID,
+          keys: [
+            { pubkey: account.publicKey, isSigner: false, isWritable: true },
+            { pubkey: mint, isSigner: false, isWritable: false },
+          ],
+          data,
+        }),
+      ],
+      [account]
+    );
+    return account.publicKey;
+  }
+
+  async function mintTo(mint: PublicKey, destination: PublicKey, amount: BN) {
+    const data = Buffer.concat([
+      Buffer.from([MINT_TO]),
+      amount.toArrayLike(Buffer, "le", 8),
+    ]);
+    await send([
+      new TransactionInstruction({
+        programId: TOKEN_PROGRAM_ID,
+        keys: [
+          { pubkey: mint, isSigner: false, isWritable: true },
+          { pubkey: destination, isSigner: false, isWritable: true },
+          { pubkey: payer, isSigner: true, isWritable: false },
+        ],
+        data,
+      }),
+    ]);
+  }
+
+  async function balance(account: PublicKey): Promise<BN> {
+    const { value } = await provider.connection.getTokenAccountBalance(account);
+    return new BN(value.amount);
+  }
+
+  async function supply(mint: PublicKey): Promise<BN> {
+    const { value } = await provider.connection.getTokenSupply(mint);
+    return new BN(value.amount);
+  }
+
+  // Integer square root, rounding down
+  function sqrt(value: BN): BN {
+    if (value.isZero()) {
+      return value;
+    }
+    let x = value;
+    let y = x.addn(1).shrn(1);
+    while (y.lt(x)) {
+      x = y;
+      y = x.add(value.div(x)).shrn(1);
+    }
+    return x;
+  }
+
+  // Constant product output, mirroring the ceiling division of the program
+  function swapOutput(amountIn: BN, source: BN, destination: BN): BN {
+    const invariant = source.mul(destination);
+    const newSource = source.add(amountIn);
+    let newDestination = invariant.div(newSource);
+    if (!invariant.mod(newSource).isZero()) {
+      newDestination = newDestination.addn(1);
+    }
+    return destination.sub(newDestination);
+  }
+
+  function poolTokensToTradingTokens(
+    poolTokens: BN,
+    poolSupply: BN,
+    reserve: BN,
+    roundUp: boolean
+  ): BN {
+    const amount = poolTokens.mul(reserve).div(poolSupply);
+    const remainder = poolTokens.mul(reserve).mod(poolSupply);
+    return roundUp && !remainder.isZero() && !amount.isZero()
+      ? amount.addn(1)
+      : amount;
+  }
+
+  async function swap(amountIn: BN, minimumAmountOut: BN, aToB: boolean) {
+    const [sourceInfo, destinationInfo] = aToB
+      ? [userTokenA, userTokenB]
+      : [userTokenB, userTokenA];
+    const [swapSource, swapDestination] = aToB
+      ? [tokenA, tokenB]
+      : [tokenB, tokenA];
+    await program.methods
+      .swap(amountIn, minimumAmountOut)
+      .accountsStrict({
+        authority,
+        amm: amm.publicKey,
+        userTransferAuthority: payer,
+        sourceInfo,
+        destinationInfo,
+        swapSource,
+        swapDestination,
+        poolMint,
+        poolAccount: feeAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        hostFeeAccount: null,
+        defaultHostFeeAccount: null,
+        hostPosition: null,
+        wsolAccount: null,
+        nativeMint: null,
+        systemProgram: null,
+      })
+      .rpc();
+  }
+
+  // Initializes a pool of its own over the test mints, for tests needing
+  // other fees, curve or reserves than the shared pool
+  async function createPool(
+    fees = ZERO_FEES,
+    curve = CONSTANT_PRODUCT,
+    minPoolValue = new BN(0),
+    seedA = reserveA,
+    seedB = reserveB
+  ) {
+    const pool = Keypair.generate();
+    const [poolAuthority] = PublicKey.findProgramAddressSync(
+      [pool.publicKey.toBuffer()],
+      program.programId
+    );
+    const mint = await createMint(poolAuthority);
+    const poolTokenA = await createTokenAccount(mintA, poolAuthority);
+    const poolTokenB = await createTokenAccount(mintB, poolAuthority);
+    const poolFeeAccount = await createTokenAccount(mint, payer);
+    const poolToken = await createTokenAccount(mint, payer);
+    await mintTo(mintA, poolTokenA, seedA);
+    await mintTo(mintB, poolTokenB, seedB);
+
+    await program.methods
+      .initialize(fees, curve, minPoolValue, 0)
+      .accountsStrict({
+        authority: poolAuthority,
+        initializer: payer,
+        amm: pool.publicKey,
+        poolMint: mint,
+        tokenA: poolTokenA,
+        tokenB: poolTokenB,
+        tokenAMint: mintA,
+        tokenBMint: mintB,
+        feeAccount: poolFeeAccount,
+        destination: poolToken,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        initializerTokenA: null,
+        initializerTokenB: null,
+        config: null,
+      })
+      .signers([pool])
+      .rpc();
+
+    return {
+      amm: pool.publicKey,
+      authority: poolAuthority,
+      poolMint: mint,
+      tokenA: poolTokenA,
+      tokenB: poolTokenB,
+      feeAccount: poolFeeAccount,
+      poolToken,
+    };
+  }
+
+  type Pool = Awaited<ReturnType<typeof createPool>>;
+
+  // Accounts of a swap of the user's tokens on a pool from createPool
+  function swapAccounts(pool: Pool, aToB = true) {
+    return {
+      authority: pool.authority,
+      amm: pool.amm,
+      userTransferAuthority: payer,
+      sourceInfo: aToB ? userTokenA : userTokenB,
+      destinationInfo: aToB ? userTokenB : userTokenA,
+      swapSource: aToB ? pool.tokenA : pool.tokenB,
+      swapDestination: aToB ? pool.tokenB : pool.tokenA,
+      poolMint: pool.poolMint,
+      poolAccount: pool.feeAccount,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      hostFeeAccount: null,
+      defaultHostFeeAccount: null,
+      hostPosition: null,
+      wsolAccount: null,
+      nativeMint: null,
+      systemProgram: null,
+    };
+  }
+
+  // Asserts that `request` fails with the program error `code`
+  async function assertError(request: Promise<unknown>, code: string) {
+    let error: unknown;
+    try {
+      await request;
+    } catch (err) {
+      error = err;
+    }
+    assert.instanceOf(error, anchor.AnchorError);
+    assert.equal((error as anchor.AnchorError).error.errorCode.code, code);
+  }
+
+  before(async () => {
+    // The program orders token A and B by mint key
+    const mints = [await createMint(payer), await createMint(payer)];
+    mints.sort((a, b) => Buffer.compare(a.toBuffer(), b.toBuffer()));
+    [mintA, mintB] = mints;
+    poolMint = await createMint(authority);
+
+    tokenA = await createTokenAccount(mintA, authority);
+    tokenB = await createTokenAccount(mintB, authority);
+    feeAccount = await createTokenAccount(poolMint, payer);
+    userTokenA = await createTokenAccount(mintA, payer);
+    userTokenB = await createTokenAccount(mintB, payer);
+    userPoolToken = await createTokenAccount(poolMint, payer);
+
+    await mintTo(mintA, tokenA, reserveA);
+    await mintTo(mintB, tokenB, reserveB);
+    await mintTo(mintA, userTokenA, new BN(1_000_000));
+    await mintTo(mintB, userTokenB, new BN(1_000_000));
+  });
+
+  it("initializes a constant product pool", async () => {
+    await program.methods
+      .initialize(ZERO_FEES, CONSTANT_PRODUCT, new BN(0), 0)
+      .accountsStrict({
+        authority,
+        initializer: payer,
+        amm: amm.publicKey,
+        poolMint,
+        tokenA,
+        tokenB,
+        tokenAMint: mintA,
+        tokenBMint: mintB,
+        feeAccount,
+        destination: userPoolToken,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        initializerTokenA: null,
+        initializerTokenB: null,
+        config: null,
+      })
+      .signers([amm])
+      .rpc();
+
+    const state = await program.account.amm.fetch(amm.publicKey);
+    assert.isTrue(state.isInitialized);
+    assert.isTrue(state.tokenAAccount.equals(tokenA));
+    assert.isTrue(state.tokenBAccount.equals(tokenB));
+    // The test mints have no decimals
+    assert.equal(state.tokenADecimals, 0);
+    assert.equal(state.tokenBDecimals, 0);
+    assert.equal(state.poolMintDecimals, 0);
+
+    // The initial supply is the geometric mean of the seeded reserves
+    const initialSupply = sqrt(reserveA.mul(reserveB));
+    assert.isTrue((await balance(userPoolToken)).eq(initialSupply));
+    assert.isTrue((await supply(poolMint)).eq(initialSupply));
+  });
+
+  it("derives the same authority at initialization and in swaps", async () => {
+    // initialize uses find_program_address, while swaps rebuild the key from
+    // the stored bump with create_program_address in authority_key
+    const [expected, bump] = PublicKey.findProgramAddressSync(
+      [amm.publicKey.toBuffer()],
+      program.programId
+    );
+    const info = await program.methods
+      .getAuthority()
+      .accountsStrict({ amm: amm.publicKey })
+      .view();
+    const state = await program.account.amm.fetch(amm.publicKey);
+    const rebuilt = PublicKey.createProgramAddressSync(
+      [amm.publicKey.toBuffer(), Buffer.from([state.bumpSeed])],
+      program.programId
+    );
+
+    assert.isTrue(info.authority.equals(expected));
+    assert.equal(info.bumpSeed, bump);
+    assert.equal(state.bumpSeed, bump);
+    assert.isTrue(rebuilt.equals(expected));
+  });
+
+  it("reports the reserve ratio in lowest terms", async () => {
+    const ratio = await program.methods
+      .getReserveRatio()
+      .accountsStrict({ amm: amm.publicKey, tokenA, tokenB })
+      .view();
+    const poolA = await balance(tokenA);
+    const poolB = await balance(tokenB);
+
+    // Scales back up to the reserves, and can't be reduced any further
+    const scale = poolA.div(ratio.tokenAAmount);
+    assert.isTrue(ratio.tokenAAmount.mul(scale).eq(poolA));
+    assert.isTrue(ratio.tokenBAmount.mul(scale).eq(poolB));
+    assert.isTrue(ratio.tokenAAmount.gcd(ratio.tokenBAmount).eqn(1));
+  });
+
+  it("swaps token A for token B along the curve", async () => {
+    const amountIn = new BN(10_000);
+    const poolA = await balance(tokenA);
+    const poolB = await balance(tokenB);
+    const userA = await balance(userTokenA);
+    const userB = await balance(userTokenB);
+    const expectedOut = swapOutput(amountIn, poolA, poolB);
+
+    await program.methods
+      .swap(amountIn, expectedOut)
+      .accountsStrict({
+        authority,
+        amm: amm.publicKey,
+        userTransferAuthority: payer,
+        sourceInfo: userTokenA,
+        destinationInfo: userTokenB,
+        swapSource: tokenA,
+        swapDestination: tokenB,
+        poolMint,
+        poolAccount: feeAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        hostFeeAccount: null,
+        defaultHostFeeAccount: null,
+        hostPosition: null,
+        wsolAccount: null,
+        nativeMint: null,
+        systemProgram: null,
+      })
+      .rpc();
+
+    assert.isTrue((await balance(userTokenA)).eq(userA.sub(amountIn)));
+    assert.isTrue((await balance(userTokenB)).eq(userB.add(expectedOut)));
+    assert.isTrue((await balance(tokenA)).eq(poolA.add(amountIn)));
+    assert.isTrue((await balance(tokenB)).eq(poolB.sub(expectedOut)));
+
+    // The invariant never decreases
+    const invariant = poolA.mul(poolB);
+    const newInvariant = (await balance(tokenA)).mul(await balance(tokenB));
+    assert.isTrue(newInvariant.gte(invariant));
+  });
+
+  it("never decreases the invariant over random swaps", async () => {
+    // Park-Miller generator with a fixed seed, so failures are reproducible
+    let seed = 42;
+    const random = (max: number) => {
+      seed = (seed * 16807) % 2147483647;
+      return seed % max;
+    };
+
+    for (let i = 0; i < 16; i++) {
+      const aToB = random(2) === 0;
+      const amountIn = new BN(1_000 + random(5_000));
+      const poolA = await balance(tokenA);
+      const poolB = await balance(tokenB);
+      const [source, destination] = aToB ? [poolA, poolB] : [poolB, poolA];
+      const expectedOut = swapOutput(amountIn, source, destination);
+
+      assert.isTrue(expectedOut.gtn(0));
+      assert.isTrue(expectedOut.lt(destination));
+
+      await swap(amountIn, expectedOut, aToB);
+
+      const newInvariant = (await balance(tokenA)).mul(await balance(tokenB));
+      assert.isTrue(newInvariant.gte(poolA.mul(poolB)));
+    }
+  });
+
+  it("quotes the spot price as the reserve ratio", async () => {
+    const spot = await program.methods
+      .getSpotPrice({ atoB: {} })
+      .accountsStrict({ amm: amm.publicKey, tokenA, tokenB })
+      .view();
+
+    // Token B per token A as Q64.64, up to the 12 decimals of the curve math
+    const expected = (await balance(tokenB))
+      .shln(64)
+      .div(await balance(tokenA));
+    assert.isTrue(spot.sub(expected).abs().muln(1_000_000_000).lte(expected));
+  });
+
+  it("rejects a swap of zero tokens", async () => {
+    let error: unknown;
+    try {
+      await swap(new BN(0), new BN(0), true);
+    } catch (err) {
+      error = err;
+    }
+    assert.instanceOf(error, anchor.AnchorError);
+    assert.equal(
+      (error as anchor.AnchorError).error.errorCode.code,
+      "InvalidInput"
+    );
+  });
+
+  it("rejects a swap through another token program", async () => {
+    // The pool was initialized with the legacy token program
+    const token2022 = new PublicKey(
+      "TokenzQdBNbLqP5VEhdkAS6EPFLC1PeRTYgAXnBhjh6"
+    );
+    let error: unknown;
+    try {
+      await program.methods
+        .swap(new BN(1_000), new BN(0))
+        .accountsStrict({
+          authority,
+          amm: amm.publicKey,
+          userTransferAuthority: payer,
+          sourceInfo: userTokenA,
+          destinationInfo: userTokenB,
+          swapSource: tokenA,
+          swapDestination: tokenB,
+          poolMint,
+          poolAccount: feeAccount,
+          tokenProgram: token2022,
+          hostFeeAccount: null,
+          defaultHostFeeAccount: null,
+          hostPosition: null,
+          wsolAccount: null,
+          nativeMint: null,
+          systemProgram: null,
+        })
+        .rpc();
+    } catch (err) {
+      error = err;
+    }
+    assert.instanceOf(error, anchor.AnchorError);
+    assert.equal(
+      (error as anchor.AnchorError).error.errorCode.code,
+      "IncorrectTokenProgramId"
+    );
+  });
+
+  it("only lets the owner swap without fees", async () => {
+    const stranger = Keypair.generate();
+    let error: unknown;
+    try {
+      await program.methods
+        .swapNoFee(new BN(1_000), new BN(0))
+        .accountsStrict({
+          authority,
+          amm: amm.publicKey,
+          userTransferAuthority: stranger.publicKey,
+          sourceInfo: userTokenA,
+          destinationInfo: userTokenB,
+          swapSource: tokenA,
+          swapDestination: tokenB,
+          poolMint,
+          poolAccount: feeAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          hostFeeAccount: null,
+          defaultHostFeeAccount: null,
+          hostPosition: null,
+          wsolAccount: null,
+          nativeMint: null,
+          systemProgram: null,
+        })
+        .signers([stranger])
+        .rpc();
+    } catch (err) {
+      error = err;
+    }
+    assert.instanceOf(error, anchor.AnchorError);
+    assert.equal(
+      (error as anchor.AnchorError).error.errorCode.code,
+      "Unauthorized"
+    );
+  });
+
+  it("partially fills a swap at the limit price", async () => {
+    // Asks for the average price of half the amount, which the full amount
+    // can't meet as the price moves against it
+    const amountIn = new BN(100_000);
+    const poolA = await balance(tokenA);
+    const poolB = await balance(tokenB);
+    const minimumAmountOut = swapOutput(amountIn.divn(2), poolA, poolB).muln(2);
+    const userA = await balance(userTokenA);
+    const userB = await balance(userTokenB);
+
+    await program.methods
+      .swapWithPartialFill(amountIn, minimumAmountOut)
+      .accountsStrict({
+        authority,
+        amm: amm.publicKey,
+        userTransferAuthority: payer,
+        sourceInfo: userTokenA,
+        destinationInfo: userTokenB,
+        swapSource: tokenA,
+        swapDestination: tokenB,
+        poolMint,
+        poolAccount: feeAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        hostFeeAccount: null,
+        defaultHostFeeAccount: null,
+        hostPosition: null,
+        wsolAccount: null,
+        nativeMint: null,
+        systemProgram: null,
+      })
+      .rpc();
+
+    const filled = userA.sub(await balance(userTokenA));
+    const received = (await balance(userTokenB)).sub(userB);
+    assert.isTrue(filled.gtn(0) && filled.lt(amountIn));
+    assert.isTrue(received.eq(swapOutput(filled, poolA, poolB)));
+    // At the limit price, and no better than it one token later
+    assert.isTrue(received.mul(amountIn).gte(minimumAmountOut.mul(filled)));
+    const next = swapOutput(filled.addn(1), poolA, poolB);
+    assert.isTrue(next.mul(amountIn).lt(minimumAmountOut.mul(filled.addn(1))));
+  });
+
+  it("returns the deposit on a deposit and withdraw round trip", async () => {
+    const poolTokens = new BN(10_000);
+    const poolA = await balance(tokenA);
+    const poolB = await balance(tokenB);
+    const poolSupply = await supply(poolMint);
+    const userA = await balance(userTokenA);
+    const userB = await balance(userTokenB);
+    const userPool = await balance(userPoolToken);
+
+    const depositA = poolTokensToTradingTokens(
+      poolTokens,
+      poolSupply,
+      poolA,
+      true
+    );
+    const depositB = poolTokensToTradingTokens(
+      poolTokens,
+      poolSupply,
+      poolB,
+      true
+    );
+
+    await program.methods
+      .depositLiquidity(poolTokens, depositA, depositB)
+      .accountsStrict({
+        authority,
+        amm: amm.publicKey,
+        userTransferAuthority: payer,
+        userTokenA,
+        userTokenB,
+        tokenA,
+        tokenB,
+        poolMint,
+        userPoolToken,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        position: null,
+        wsolAccount: null,
+        nativeMint: null,
+        systemProgram: null,
+      })
+      .rpc();
+
+    assert.isTrue((await balance(userTokenA)).eq(userA.sub(depositA)));
+    assert.isTrue((await balance(userTokenB)).eq(userB.sub(depositB)));
+    assert.isTrue((await balance(userPoolToken)).eq(userPool.add(poolTokens)));
+
+    const withdrawA = poolTokensToTradingTokens(
+      poolTokens,
+      poolSupply.add(poolTokens),
+      poolA.add(depositA),
+      false
+    );
+    const withdrawB = poolTokensToTradingTokens(
+      poolTokens,
+      poolSupply.add(poolTokens),
+      poolB.add(depositB),
+      false
+    );
+
+    await program.methods
+      .withdrawLiquidity(poolTokens, withdrawA, withdrawB)
+      .accountsStrict({
+        authority,
+        amm: amm.publicKey,
+        userTransferAuthority: payer,
+        sourcePoolAccount: userPoolToken,
+        tokenA,
+        tokenB,
+        userTokenA,
+        userTokenB,
+        poolMint,
+        feeAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        position: null,
+      })
+      .rpc();
+
+    const finalA = await balance(userTokenA);
+    const finalB = await balance(userTokenB);
+    assert.isTrue(finalA.eq(userA.sub(depositA).add(withdrawA)));
+    assert.isTrue(finalB.eq(userB.sub(depositB).add(withdrawB)));
+    assert.isTrue((await balance(userPoolToken)).eq(userPool));
+    assert.isTrue((await supply(poolMint)).eq(poolSupply));
+
+    // Rounding favors the pool, by at most one token of each side
+    assert.isTrue(finalA.lte(userA) && finalA.gte(userA.subn(1)));
+    assert.isTrue(finalB.lte(userB) && finalB.gte(userB.subn(1)));
+  });
+  it("skims tokens sent to the reserves directly", async () => {
+    const donation = new BN(5_000);
+    await mintTo(mintA, tokenA, donation);
+    const poolA = await balance(tokenA);
+    const poolB = await balance(tokenB);
+    const userA = await balance(userTokenA);
+    const userB = await balance(userTokenB);
+
+    await program.methods
+      .skim()
+      .accountsStrict({
+        authority,
+        amm: amm.publicKey,
+        owner: payer,
+        tokenA,
+        tokenB,
+        destinationA: userTokenA,
+        destinationB: userTokenB,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    assert.isTrue((await balance(tokenA)).eq(poolA.sub(donation)));
+    assert.isTrue((await balance(tokenB)).eq(poolB));
+    assert.isTrue((await balance(userTokenA)).eq(userA.add(donation)));
+    assert.isTrue((await balance(userTokenB)).eq(userB));
+  });
+
+  it("transfers ownership once the proposed owner accepts", async () => {
+    const nextOwner = Keypair.generate();
+
+    await program.methods
+      .proposeOwner(nextOwner.publicKey)
+      .accountsStrict({ amm: amm.publicKey, owner: payer })
+      .rpc();
+    let state = await program.account.amm.fetch(amm.publicKey);
+    assert.isTrue(state.owner.equals(payer));
+    assert.isTrue(state.pendingOwner.equals(nextOwner.publicKey));
+
+    await program.methods
+      .acceptOwner()
+      .accountsStrict({
+        amm: amm.publicKey,
+        pendingOwner: nextOwner.publicKey,
+      })
+      .signers([nextOwner])
+      .rpc();
+    state = await program.account.amm.fetch(amm.publicKey);
+    assert.isTrue(state.owner.equals(nextOwner.publicKey));
+    assert.isTrue(state.pendingOwner.equals(PublicKey.default));
+
+    // Hand the pool back for the following tests
+    await program.methods
+      .proposeOwner(payer)
+      .accountsStrict({ amm: amm.publicKey, owner: nextOwner.publicKey })
+      .signers([nextOwner])
+      .rpc();
+    await program.methods
+      .acceptOwner()
+      .accountsStrict({ amm: amm.publicKey, pendingOwner: payer })
+      .rpc();
+    state = await program.account.amm.fetch(amm.publicKey);
+    assert.isTrue(state.owner.equals(payer));
+  });
+
+  it("only stores fee splits that add up to the whole fee", async () => {
+    const first = Keypair.generate().publicKey;
+    const second = Keypair.generate().publicKey;
+
+    let error: unknown;
+    try {
+      await program.methods
+        .setFeeSplit([
+          { recipient: first, shareBps: 6_000 },
+          { recipient: second, shareBps: 3_000 },
+        ])
+        .accountsStrict({ amm: amm.publicKey, owner: payer })
+        .rpc();
+    } catch (err) {
+      error = err;
+    }
+    assert.instanceOf(error, anchor.AnchorError);
+    assert.equal(
+      (error as anchor.AnchorError).error.errorCode.code,
+      "InvalidFeeSplit"
+    );
+
+    await program.methods
+      .setFeeSplit([
+        { recipient: first, shareBps: 6_000 },
+        { recipient: second, shareBps: 4_000 },
+      ])
+      .accountsStrict({ amm: amm.publicKey, owner: payer })
+      .rpc();
+    let state = await program.account.amm.fetch(amm.publicKey);
+    assert.isTrue(state.feeSplit[0].recipient.equals(first));
+    assert.equal(state.feeSplit[1].shareBps, 4_000);
+    assert.equal(state.feeSplit[2].shareBps, 0);
+
+    // Clears the split, so the following swaps mint to the fee account
+    await program.methods
+      .setFeeSplit([])
+      .accountsStrict({ amm: amm.publicKey, owner: payer })
+      .rpc();
+    state = await program.account.amm.fetch(amm.publicKey);
+    assert.isTrue(state.feeSplit.every((split) => split.shareBps === 0));
+  });
+
+  it("validates the initial reserves by curve", async () => {
+    // Seeds a fresh pool with no token A and some token B
+    async function initializeWithoutTokenA(curve: {
+      curveType: number;
+      curveParams: number[];
+    }) {
+      const pool = Keypair.generate();
+      const [poolAuthority] = PublicKey.findProgramAddressSync(
+        [pool.publicKey.toBuffer()],
+        program.programId
+      );
+      const mint = await createMint(poolAuthority);
+      const poolTokenB = await createTokenAccount(mintB, poolAuthority);
+      await mintTo(mintB, poolTokenB, reserveB);
+      await program.methods
+        .initialize(ZERO_FEES, curve, new BN(0), 0)
+        .accountsStrict({
+          authority: poolAuthority,
+          initializer: payer,
+          amm: pool.publicKey,
+          poolMint: mint,
+          tokenA: await createTokenAccount(mintA, poolAuthority),
+          tokenB: poolTokenB,
+          tokenAMint: mintA,
+          tokenBMint: mintB,
+          feeAccount: await createTokenAccount(mint, payer),
+          destination: await createTokenAccount(mint, payer),
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+          initializerTokenA: null,
+          initializerTokenB: null,
+          config: null,
+        })
+        .signers([pool])
+        .rpc();
+      return pool.publicKey;
+    }
+
+    // The constant product needs both reserves
+    let error: unknown;
+    try {
+      await initializeWithoutTokenA(CONSTANT_PRODUCT);
+    } catch (err) {
+      error = err;
+    }
+    assert.instanceOf(error, anchor.AnchorError);
+    assert.equal(
+      (error as anchor.AnchorError).error.errorCode.code,
+      "EmptySupply"
+    );
+
+    // The constant price only needs token B, which token A is priced against
+    const price = new BN(2).toArrayLike(Buffer, "le", 8);
+    const constantPrice = {
+      curveType: 1,
+      curveParams: [...price, ...new Array(24).fill(0)],
+    };
+    const pool = await initializeWithoutTokenA(constantPrice);
+    const state = await program.account.amm.fetch(pool);
+    assert.isTrue(state.isInitialized);
+  });
+
+  it("seeds an empty reserve with a single-token deposit", async () => {
+    // A constant price pool may start without token A
+    const pool = Keypair.generate();
+    const [poolAuthority] = PublicKey.findProgramAddressSync(
+      [pool.publicKey.toBuffer()],
+      program.programId
+    );
+    const mint = await createMint(poolAuthority);
+    const poolTokenA = await createTokenAccount(mintA, poolAuthority);
+    const poolTokenB = await createTokenAccount(mintB, poolAuthority);
+    const userPool = await createTokenAccount(mint, payer);
+    await mintTo(mintB, poolTokenB, reserveB);
+
+    const price = new BN(2);
+    await program.methods
+      .initialize(
+        ZERO_FEES,
+        {
+          curveType: 1,
+          curveParams: [
+            ...price.toArrayLike(Buffer, "le", 8),
+            ...new Array(24).fill(0),
+          ],
+        },
+        new BN(0),
+        0
+      )
+      .accountsStrict({
+        authority: poolAuthority,
+        initializer: payer,
+        amm: pool.publicKey,
+        poolMint: mint,
+        tokenA: poolTokenA,
+        tokenB: poolTokenB,
+        tokenAMint: mintA,
+        tokenBMint: mintB,
+        feeAccount: await createTokenAccount(mint, payer),
+        destination: userPool,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        initializerTokenA: null,
+        initializerTokenB: null,
+        config: null,
+      })
+      .signers([pool])
+      .rpc();
+
+    // Token A is worth its share of the pool value, measured in token A
+    const depositA = new BN(10_000);
+    const poolSupply = await supply(mint);
+    const expected = poolSupply.mul(depositA).div(reserveB.mul(price));
+    const userPoolBefore = await balance(userPool);
+
+    await program.methods
+      .smartDeposit(depositA, new BN(0), expected)
+      .accountsStrict({
+        authority: poolAuthority,
+        amm: pool.publicKey,
+        userTransferAuthority: payer,
+        userTokenA,
+        userTokenB,
+        tokenA: poolTokenA,
+        tokenB: poolTokenB,
+        poolMint: mint,
+        userPoolToken: userPool,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        position: null,
+        wsolAccount: null,
+        nativeMint: null,
+        systemProgram: null,
+      })
+      .rpc();
+
+    assert.isTrue((await balance(poolTokenA)).eq(depositA));
+    assert.isTrue((await balance(poolTokenB)).eq(reserveB));
+    assert.isTrue((await balance(userPool)).eq(userPoolBefore.add(expected)));
+  });
+
+  it("leaves every fee to liquidity providers", async () => {
+    const pool = Keypair.generate();
+    const [poolAuthority] = PublicKey.findProgramAddressSync(
+      [pool.publicKey.toBuffer()],
+      program.programId
+    );
+    const mint = await createMint(poolAuthority);
+    const poolTokenA = await createTokenAccount(mintA, poolAuthority);
+    const poolTokenB = await createTokenAccount(mintB, poolAuthority);
+    const poolFeeAccount = await createTokenAccount(mint, payer);
+    await mintTo(mintA, poolTokenA, reserveA);
+    await mintTo(mintB, poolTokenB, reserveB);
+
+    // A 0.3% trade fee for liquidity providers, and no owner or host fee
+    const fees = {
+      ...ZERO_FEES,
+      tradeFeeNumerator: new BN(30),
+      tradeFeeDenominator: new BN(10_000),
+    };
+    await program.methods
+      .initialize(fees, CONSTANT_PRODUCT, new BN(0), 0)
+      .accountsStrict({
+        authority: poolAuthority,
+        initializer: payer,
+        amm: pool.publicKey,
+        poolMint: mint,
+        tokenA: poolTokenA,
+        tokenB: poolTokenB,
+        tokenAMint: mintA,
+        tokenBMint: mintB,
+        feeAccount: poolFeeAccount,
+        destination: await createTokenAccount(mint, payer),
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        initializerTokenA: null,
+        initializerTokenB: null,
+        config: null,
+      })
+      .signers([pool])
+      .rpc();
+
+    const amountIn = new BN(10_000);
+    const swapped = swapOutput(amountIn, reserveA, reserveB);
+    const expectedOut = swapped.sub(swapped.muln(30).divn(10_000));
+    const poolSupply = await supply(mint);
+    const userB = await balance(userTokenB);
+
+    await program.methods
+      .swap(amountIn, expectedOut)
+      .accountsStrict({
+        authority: poolAuthority,
+        amm: pool.publicKey,
+        userTransferAuthority: payer,
+        sourceInfo: userTokenA,
+        destinationInfo: userTokenB,
+        swapSource: poolTokenA,
+        swapDestination: poolTokenB,
+        poolMint: mint,
+        poolAccount: poolFeeAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        hostFeeAccount: null,
+        defaultHostFeeAccount: null,
+        hostPosition: null,
+        wsolAccount: null,
+        nativeMint: null,
+        systemProgram: null,
+      })
+      .rpc();
+
+    // Only the trade fee is taken, and it stays in the reserve
+    assert.isTrue((await balance(userTokenB)).eq(userB.add(expectedOut)));
+    assert.isTrue((await balance(poolTokenB)).eq(reserveB.sub(expectedOut)));
+    assert.isTrue((await supply(mint)).eq(poolSupply));
+    assert.isTrue((await balance(poolFeeAccount)).isZero());
+  });
+
+  it("rejects an initial destination of the wrong mint", async () => {
+    const pool = Keypair.generate();
+    const [poolAuthority] = PublicKey.findProgramAddressSync(
+      [pool.publicKey.toBuffer()],
+      program.programId
+    );
+    const mint = await createMint(poolAuthority);
+    const poolTokenA = await createTokenAccount(mintA, poolAuthority);
+    const poolTokenB = await createTokenAccount(mintB, poolAuthority);
+    await mintTo(mintA, poolTokenA, reserveA);
+    await mintTo(mintB, poolTokenB, reserveB);
+
+    let error: unknown;
+    try {
+      await program.methods
+        .initialize(ZERO_FEES, CONSTANT_PRODUCT, new BN(0), 0)
+        .accountsStrict({
+          authority: poolAuthority,
+          initializer: payer,
+          amm: pool.publicKey,
+          poolMint: mint,
+          tokenA: poolTokenA,
+          tokenB: poolTokenB,
+          tokenAMint: mintA,
+          tokenBMint: mintB,
+          feeAccount: await createTokenAccount(mint, payer),
+          // Holds token A rather than the pool token
+          destination: await createTokenAccount(mintA, payer),
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+          initializerTokenA: null,
+          initializerTokenB: null,
+          config: null,
+        })
+        .signers([pool])
+        .rpc();
+    } catch (err) {
+      error = err;
+    }
+    assert.instanceOf(error, anchor.AnchorError);
+    assert.equal(
+      (error as anchor.AnchorError).error.errorCode.code,
+      "IncorrectPoolMint"
+    );
+  });
+
+  it("rejects a swap that would empty the destination reserve", async () => {
+    // A constant price pool prices token B at 2 token A whatever its
+    // reserves, so enough token A asks for all of token B
+    const pool = Keypair.generate();
+    const [poolAuthority] = PublicKey.findProgramAddressSync(
+      [pool.publicKey.toBuffer()],
+      program.programId
+    );
+    const pricedMint = await createMint(poolAuthority);
+    const poolTokenA = await createTokenAccount(mintA, poolAuthority);
+    const poolTokenB = await createTokenAccount(mintB, poolAuthority);
+    const pricedFeeAccount = await createTokenAccount(pricedMint, payer);
+    await mintTo(mintA, poolTokenA, new BN(10_000));
+    await mintTo(mintB, poolTokenB, new BN(10_000));
+
+    const price = new BN(2).toArrayLike(Buffer, "le", 8);
+    await program.methods
+      .initialize(
+        ZERO_FEES,
+        { curveType: 1, curveParams: [...price, ...new Array(24).fill(0)] },
+        new BN(0),
+        0
+      )
+      .accountsStrict({
+        authority: poolAuthority,
+        initializer: payer,
+        amm: pool.publicKey,
+        poolMint: pricedMint,
+        tokenA: poolTokenA,
+        tokenB: poolTokenB,
+        tokenAMint: mintA,
+        tokenBMint: mintB,
+        feeAccount: pricedFeeAccount,
+        destination: await createTokenAccount(pricedMint, payer),
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        initializerTokenA: null,
+        initializerTokenB: null,
+        config: null,
+      })
+      .signers([pool])
+      .rpc();
+
+    let error: unknown;
+    try {
+      await program.methods
+        .swap(new BN(20_000), new BN(0))
+        .accountsStrict({
+          authority: poolAuthority,
+          amm: pool.publicKey,
+          userTransferAuthority: payer,
+          sourceInfo: userTokenA,
+          destinationInfo: userTokenB,
+          swapSource: poolTokenA,
+          swapDestination: poolTokenB,
+          poolMint: pricedMint,
+          poolAccount: pricedFeeAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          hostFeeAccount: null,
+          defaultHostFeeAccount: null,
+          hostPosition: null,
+          wsolAccount: null,
+          nativeMint: null,
+          systemProgram: null,
+        })
+        .rpc();
+    } catch (err) {
+      error = err;
+    }
+    assert.instanceOf(error, anchor.AnchorError);
+    assert.equal(
+      (error as anchor.AnchorError).error.errorCode.code,
+      "InsufficientLiquidity"
+    );
+  });
+
+  it("swaps on a pool without pool tokens outstanding", async () => {
+    const pool = Keypair.generate();
+    const [poolAuthority] = PublicKey.findProgramAddressSync(
+      [pool.publicKey.toBuffer()],
+      program.programId
+    );
+    const emptyPoolMint = await createMint(poolAuthority);
+    const poolTokenA = await createTokenAccount(mintA, poolAuthority);
+    const poolTokenB = await createTokenAccount(mintB, poolAuthority);
+    const emptyFeeAccount = await createTokenAccount(emptyPoolMint, payer);
+    const poolTokens = await createTokenAccount(emptyPoolMint, payer);
+    await mintTo(mintA, poolTokenA, new BN(10_000));
+    await mintTo(mintB, poolTokenB, new BN(10_000));
+
+    const ownerFees = {
+      ...ZERO_FEES,
+      ownerTradeFeeNumerator: new BN(30),
+      ownerTradeFeeDenominator: new BN(10_000),
+    };
+    await program.methods
+      .initialize(ownerFees, CONSTANT_PRODUCT, new BN(0), 0)
+      .accountsStrict({
+        authority: poolAuthority,
+        initializer: payer,
+        amm: pool.publicKey,
+        poolMint: emptyPoolMint,
+        tokenA: poolTokenA,
+        tokenB: poolTokenB,
+        tokenAMint: mintA,
+        tokenBMint: mintB,
+        feeAccount: emptyFeeAccount,
+        destination: poolTokens,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        initializerTokenA: null,
+        initializerTokenB: null,
+        config: null,
+      })
+      .signers([pool])
+      .rpc();
+
+    // Withdraw the whole supply, then refill the reserves directly
+    await program.methods
+      .withdrawLiquidity(await balance(poolTokens), new BN(0), new BN(0))
+      .accountsStrict({
+        authority: poolAuthority,
+        amm: pool.publicKey,
+        userTransferAuthority: payer,
+        sourcePoolAccount: poolTokens,
+        tokenA: poolTokenA,
+        tokenB: poolTokenB,
+        userTokenA,
+        userTokenB,
+        poolMint: emptyPoolMint,
+        feeAccount: emptyFeeAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        position: null,
+      })
+      .rpc();
+    assert.isTrue((await supply(emptyPoolMint)).isZero());
+    await mintTo(mintA, poolTokenA, new BN(10_000));
+    await mintTo(mintB, poolTokenB, new BN(10_000));
+
+    // The owner fee has no pool tokens to be minted as, and stays in the
+    // reserves instead of failing the swap
+    const userB = await balance(userTokenB);
+    await program.methods
+      .swap(new BN(1_000), new BN(1))
+      .accountsStrict({
+        authority: poolAuthority,
+        amm: pool.publicKey,
+        userTransferAuthority: payer,
+        sourceInfo: userTokenA,
+        destinationInfo: userTokenB,
+        swapSource: poolTokenA,
+        swapDestination: poolTokenB,
+        poolMint: emptyPoolMint,
+        poolAccount: emptyFeeAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        hostFeeAccount: null,
+        defaultHostFeeAccount: null,
+        hostPosition: null,
+        wsolAccount: null,
+        nativeMint: null,
+        systemProgram: null,
+      })
+      .rpc();
+    assert.isTrue((await balance(userTokenB)).gt(userB));
+    assert.isTrue((await supply(emptyPoolMint)).isZero());
+    assert.isTrue((await balance(emptyFeeAccount)).isZero());
+  });
+
+  it("quotes small deposits into a deep pool exactly", async () => {
+    const pool = Keypair.generate();
+    const [poolAuthority] = PublicKey.findProgramAddressSync(
+      [pool.publicKey.toBuffer()],
+      program.programId
+    );
+    const deepPoolMint = await createMint(poolAuthority);
+    const poolTokenA = await createTokenAccount(mintA, poolAuthority);
+    const poolTokenB = await createTokenAccount(mintB, poolAuthority);
+    const deepReserve = new BN(10).pow(new BN(18));
+    await mintTo(mintA, poolTokenA, deepReserve);
+    await mintTo(mintB, poolTokenB, deepReserve);
+
+    await program.methods
+      .initialize(ZERO_FEES, CONSTANT_PRODUCT, new BN(0), 0)
+      .accountsStrict({
+        authority: poolAuthority,
+        initializer: payer,
+        amm: pool.publicKey,
+        poolMint: deepPoolMint,
+        tokenA: poolTokenA,
+        tokenB: poolTokenB,
+        tokenAMint: mintA,
+        tokenBMint: mintB,
+        feeAccount: await createTokenAccount(deepPoolMint, payer),
+        destination: await createTokenAccount(deepPoolMint, payer),
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        initializerTokenA: null,
+        initializerTokenB: null,
+        config: null,
+      })
+      .signers([pool])
+      .rpc();
+
+    // Deposits down to a trillionth of the reserve, where a ratio with 12
+    // decimals loses everything, mint sqrt(S^2 (R + x) / R) - S
+    const poolSupply = await supply(deepPoolMint);
+    for (const exponent of [3, 6, 9, 12, 15]) {
+      const amount = new BN(10).pow(new BN(exponent));
+      const quote = await program.methods
+        .quoteDepositSingleTokenType(amount, { atoB: {} })
+        .accountsStrict({
+          amm: pool.publicKey,
+          tokenA: poolTokenA,
+          tokenB: poolTokenB,
+          poolMint: deepPoolMint,
+        })
+        .view();
+      const expected = sqrt(
+        poolSupply.sqr().mul(deepReserve.add(amount)).div(deepReserve)
+      ).sub(poolSupply);
+      assert.isTrue(quote.poolTokenAmount.eq(expected));
+    }
+  });
+
+  it("edits the initializer allowlist of the global config", async () => {
+    const [config] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config")],
+      program.programId
+    );
+    const [programData] = PublicKey.findProgramAddressSync(
+      [program.programId.toBuffer()],
+      BPF_LOADER_UPGRADEABLE_ID
+    );
+
+    // The local validator deploys the program with the wallet as its
+    // upgrade authority
+    await program.methods
+      .initializeConfig()
+      .accountsStrict({
+        admin: payer,
+        config,
+        program: program.programId,
+        programData,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    await program.methods
+      .addToAllowlist(payer)
+      .accountsStrict({ config, admin: payer })
+      .rpc();
+    let state = await program.account.globalConfig.fetch(config);
+    assert.isTrue(state.admin.equals(payer));
+    assert.equal(state.allowlist.length, 1);
+    assert.isTrue(state.allowlist[0].equals(payer));
+
+    await program.methods
+      .removeFromAllowlist(payer)
+      .accountsStrict({ config, admin: payer })
+      .rpc();
+    state = await program.account.globalConfig.fetch(config);
+    assert.equal(state.allowlist.length, 0);
+  });
+
+  it("swaps between equally weighted reserves of a weighted pool", async () => {
+    const pool = Keypair.generate();
+    const [poolAuthority] = PublicKey.findProgramAddressSync(
+      [pool.publicKey.toBuffer()],
+      program.programId
+    );
+    const mintC = await createMint(payer);
+    const weightedPoolMint = await createMint(poolAuthority);
+    const reserves = [
+      await createTokenAccount(mintA, poolAuthority),
+      await createTokenAccount(mintB, poolAuthority),
+      await createTokenAccount(mintC, poolAuthority),
+    ];
+    for (const [mint, reserve] of [
+      [mintA, reserves[0]],
+      [mintB, reserves[1]],
+      [mintC, reserves[2]],
+    ]) {
+      await mintTo(mint, reserve, new BN(1_000_000));
+    }
+    const weightedFeeAccount = await createTokenAccount(
+      weightedPoolMint,
+      payer
+    );
+    const weightedPoolToken = await createTokenAccount(
+      weightedPoolMint,
+      payer
+    );
+
+    await program.methods
+      .initializeWeightedPool(ZERO_FEES, [new BN(1), new BN(1), new BN(2)])
+      .accountsStrict({
+        authority: poolAuthority,
+        initializer: payer,
+        pool: pool.publicKey,
+        poolMint: weightedPoolMint,
+        feeAccount: weightedFeeAccount,
+        destination: weightedPoolToken,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .remainingAccounts(
+        reserves.map((pubkey) => ({
+          pubkey,
+          isSigner: false,
+          isWritable: true,
+        }))
+      )
+      .signers([pool])
+      .rpc();
+
+    const state = await program.account.weightedPool.fetch(pool.publicKey);
+    assert.equal(state.reserves.length, 3);
+
+    // With equal weights the curve is the constant product, up to the
+    // approximation error which is rounded in the pool's favor
+    const amountIn = new BN(10_000);
+    const poolA = await balance(reserves[0]);
+    const poolB = await balance(reserves[1]);
+    const userB = await balance(userTokenB);
+    const constantProductOut = amountIn.mul(poolB).div(poolA.add(amountIn));
+
+    await program.methods
+      .swapWeighted(amountIn, constantProductOut.subn(1))
+      .accountsStrict({
+        authority: poolAuthority,
+        pool: pool.publicKey,
+        userTransferAuthority: payer,
+        sourceInfo: userTokenA,
+        destinationInfo: userTokenB,
+        swapSource: reserves[0],
+        swapDestination: reserves[1],
+        poolMint: weightedPoolMint,
+        poolAccount: weightedFeeAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    const received = (await balance(userTokenB)).sub(userB);
+    assert.isTrue(received.lte(constantProductOut));
+    assert.isTrue(received.gte(constantProductOut.subn(1)));
+    assert.isTrue((await balance(reserves[0])).eq(poolA.add(amountIn)));
+  });
+
+  it("only swaps once the pool is worth the minimum pool value", async () => {
+    // The seeded pool is worth sqrt(1_000_000 * 2_000_000) = 1_414_213.56
+    const atThreshold = await createPool(
+      ZERO_FEES,
+      CONSTANT_PRODUCT,
+      new BN(1_414_213)
+    );
+    const userB = await balance(userTokenB);
+    await program.methods
+      .swap(new BN(1_000), new BN(1))
+      .accountsStrict(swapAccounts(atThreshold))
+      .rpc();
+    assert.isTrue((await balance(userTokenB)).gt(userB));
+
+    const aboveThreshold = await createPool(
+      ZERO_FEES,
+      CONSTANT_PRODUCT,
+      new BN(1_414_214)
+    );
+    await assertError(
+      program.methods
+        .swap(new BN(1_000), new BN(1))
+        .accountsStrict(swapAccounts(aboveThreshold))
+        .rpc(),
+      "PoolTooThin"
+    );
+  });
+
+  it("accumulates the fees of every swap", async () => {
+    const pool = await createPool({
+      ...ZERO_FEES,
+      tradeFeeNumerator: new BN(25),
+      tradeFeeDenominator: new BN(10_000),
+      ownerTradeFeeNumerator: new BN(5),
+      ownerTradeFeeDenominator: new BN(10_000),
+    });
+
+    let tradeFees = new BN(0);
+    let ownerFees = new BN(0);
+    for (const [amountIn, aToB] of [
+      [new BN(10_000), true],
+      [new BN(25_000), false],
+      [new BN(3_333), true],
+    ] as [BN, boolean][]) {
+      const quote = await program.methods
+        .quoteSwap(amountIn, aToB ? { atoB: {} } : { btoA: {} })
+        .accountsStrict({
+          amm: pool.amm,
+          tokenA: pool.tokenA,
+          tokenB: pool.tokenB,
+        })
+        .view();
+      // Without a host account the host's share goes to the owner
+      tradeFees = tradeFees.add(quote.tradeFee);
+      ownerFees = ownerFees.add(quote.ownerFee).add(quote.hostFee);
+
+      await program.methods
+        .swap(amountIn, quote.outputAmount)
+        .accountsStrict(swapAccounts(pool, aToB))
+        .rpc();
+    }
+
+    const info = await program.methods
+      .getPoolInfo()
+      .accountsStrict({ amm: pool.amm })
+      .view();
+    assert.isTrue(ownerFees.gtn(0));
+    assert.isTrue(info.cumulativeTradeFee.eq(tradeFees));
+    assert.isTrue(info.cumulativeOwnerFee.eq(ownerFees));
+  });
+
+  it("fails a zero-input swap before moving any tokens", async () => {
+    const accounts = [userTokenA, userTokenB, tokenA, tokenB, feeAccount];
+    const before = await Promise.all(accounts.map(balance));
+    const poolSupply = await supply(poolMint);
+
+    await assertError(swap(new BN(0), new BN(0), true), "InvalidInput");
+    await assertError(swap(new BN(0), new BN(1), false), "InvalidInput");
+
+    const after = await Promise.all(accounts.map(balance));
+    after.forEach((amount, i) => assert.isTrue(amount.eq(before[i])));
+    assert.isTrue((await supply(poolMint)).eq(poolSupply));
+  });
+
+  it("reports whether a swap would succeed", async () => {
+    const amountIn = new BN(10_000);
+    const poolA = await balance(tokenA);
+    const poolB = await balance(tokenB);
+    const expectedOut = swapOutput(amountIn, poolA, poolB);
+    const canSwap = (minimumAmountOut: BN) =>
+      program.methods
+        .canSwap(amountIn, minimumAmountOut, { atoB: {} })
+        .accountsStrict({ amm: amm.publicKey, tokenA, tokenB })
+        .view();
+
+    const success = await canSwap(expectedOut);
+    assert.isTrue(success.success);
+    assert.equal(success.errorCode, 0);
+
+    // One token more than the swap gives fails on slippage
+    const failure = await canSwap(expectedOut.addn(1));
+    const exceededSlippage = program.idl.errors.find(
+      (error) => error.name.toLowerCase() === "exceededslippage"
+    );
+    assert.isFalse(failure.success);
+    assert.equal(failure.errorCode, exceededSlippage.code);
+
+    // Neither moves any tokens
+    assert.isTrue((await balance(tokenA)).eq(poolA));
+    assert.isTrue((await balance(tokenB)).eq(poolB));
+  });
+
+  it("bounds the number of remaining accounts", async () => {
+    const recipients = (count: number) =>
+      Array.from({ length: count }, () => ({
+        pubkey: Keypair.generate().publicKey,
+        isSigner: false,
+        isWritable: false,
+      }));
+    const swapWith = (count: number) =>
+      program.methods
+        .swap(new BN(1_000), new BN(1))
+        .accountsStrict({
+          authority,
+          amm: amm.publicKey,
+          userTransferAuthority: payer,
+          sourceInfo: userTokenA,
+          destinationInfo: userTokenB,
+          swapSource: tokenA,
+          swapDestination: tokenB,
+          poolMint,
+          poolAccount: feeAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          hostFeeAccount: null,
+          defaultHostFeeAccount: null,
+          hostPosition: null,
+          wsolAccount: null,
+          nativeMint: null,
+          systemProgram: null,
+        })
+        .remainingAccounts(recipients(count))
+        .rpc();
+
+    // MAX_REMAINING_ACCOUNTS is 8
+    await swapWith(8);
+    await assertError(swapWith(9), "TooManyAccounts");
+  });
+
+  it("reports effective prices falling with the swap size", async () => {
+    const amountsIn = [1_000, 10_000, 50_000, 100_000, 500_000].map(
+      (amount) => new BN(amount)
+    );
+    const poolA = await balance(tokenA);
+    const poolB = await balance(tokenB);
+    const prices = await program.methods
+      .getPriceCurve(amountsIn, { atoB: {} })
+      .accountsStrict({ amm: amm.publicKey, tokenA, tokenB })
+      .view();
+
+    assert.equal(prices.length, amountsIn.length);
+    // Q64.64 prices of the constant product output, each below the last and
+    // all below the spot price
+    let previous = poolB.shln(64).div(poolA);
+    prices.forEach((price: BN, i: number) => {
+      const output = swapOutput(amountsIn[i], poolA, poolB);
+      assert.isTrue(price.eq(output.shln(64).div(amountsIn[i])));
+      assert.isTrue(price.lt(previous));
+      previous = price;
+    });
+  });
+
+  it("initializes a pool funded from the initializer's accounts", async () => {
+    const pool = Keypair.generate();
+    const [poolAuthority] = PublicKey.findProgramAddressSync(
+      [pool.publicKey.toBuffer()],
+      program.programId
+    );
+    const mint = await createMint(poolAuthority);
+    const poolTokenA = await createTokenAccount(mintA, poolAuthority);
+    const poolTokenB = await createTokenAccount(mintB, poolAuthority);
+    const poolToken = await createTokenAccount(mint, payer);
+    const fundingA = new BN(10_000);
+    const fundingB = new BN(40_000);
+    const userA = await balance(userTokenA);
+    const userB = await balance(userTokenB);
+
+    // The reserves start empty and are funded by the instruction itself
+    await program.methods
+      .initializeWithFunding(
+        ZERO_FEES,
+        CONSTANT_PRODUCT,
+        new BN(0),
+        0,
+        fundingA,
+        fundingB
+      )
+      .accountsStrict({
+        authority: poolAuthority,
+        initializer: payer,
+        amm: pool.publicKey,
+        poolMint: mint,
+        tokenA: poolTokenA,
+        tokenB: poolTokenB,
+        tokenAMint: mintA,
+        tokenBMint: mintB,
+        feeAccount: await createTokenAccount(mint, payer),
+        destination: poolToken,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        initializerTokenA: userTokenA,
+        initializerTokenB: userTokenB,
+        config: null,
+      })
+      .signers([pool])
+      .rpc();
+
+    assert.isTrue((await balance(userTokenA)).eq(userA.sub(fundingA)));
+    assert.isTrue((await balance(userTokenB)).eq(userB.sub(fundingB)));
+    assert.isTrue((await balance(poolTokenA)).eq(fundingA));
+    assert.isTrue((await balance(poolTokenB)).eq(fundingB));
+    // Minted against the funded reserves, sqrt(10_000 * 40_000)
+    assert.isTrue((await balance(poolToken)).eqn(20_000));
+    const state = await program.account.amm.fetch(pool.publicKey);
+    assert.isTrue(state.isInitialized);
+  });
+
+  it("returns the pool tokens of a percentage of the pool", async () => {
+    const poolSupply = await supply(poolMint);
+    const poolA = await balance(tokenA);
+    const poolB = await balance(tokenB);
+    for (const shareBps of [1, 100, 2_500, 3_333, 10_000]) {
+      const share = await program.methods
+        .getPoolShare(shareBps)
+        .accountsStrict({ amm: amm.publicKey, tokenA, tokenB, poolMint })
+        .view();
+      const poolTokens = poolSupply.muln(shareBps).divn(10_000);
+      assert.isTrue(share.poolTokenAmount.eq(poolTokens));
+      assert.isTrue(
+        share.tokenAAmount.eq(
+          poolTokensToTradingTokens(poolTokens, poolSupply, poolA, false)
+        )
+      );
+      assert.isTrue(
+        share.tokenBAmount.eq(
+          poolTokensToTradingTokens(poolTokens, poolSupply, poolB, false)
+        )
+      );
+    }
+  });
+
+  it("rejects a withdrawal paying its fee to another account", async () => {
+    // Holds the pool token, but isn't the pool fee account
+    const otherFeeAccount = await createTokenAccount(poolMint, payer);
+    const userPool = await balance(userPoolToken);
+
+    await assertError(
+      program.methods
+        .withdrawLiquidity(new BN(1_000), new BN(0), new BN(0))
+        .accountsStrict({
+          authority,
+          amm: amm.publicKey,
+          userTransferAuthority: payer,
+          sourcePoolAccount: userPoolToken,
+          tokenA,
+          tokenB,
+          userTokenA,
+          userTokenB,
+          poolMint,
+          feeAccount: otherFeeAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          position: null,
+        })
+        .rpc(),
+      "IncorrectFeeAccount"
+    );
+    assert.isTrue((await balance(userPoolToken)).eq(userPool));
+  });
+
+  it("values pool tokens fairly under manipulated reserves", async () => {
+    const pool = await createPool(
+      ZERO_FEES,
+      CONSTANT_PRODUCT,
+      new BN(0),
+      new BN(100_000),
+      new BN(200_000)
+    );
+    // External prices, as Q64.64, matching the pool's price of 2 B per A
+    const priceA = new BN(2).shln(64);
+    const priceB = new BN(1).shln(64);
+    const values = async () => {
+      const fair = await program.methods
+        .fairLpValue(priceA, priceB)
+        .accountsStrict({
+          amm: pool.amm,
+          tokenA: pool.tokenA,
+          tokenB: pool.tokenB,
+          poolMint: pool.poolMint,
+        })
+        .view();
+      // Naive valuation summing the reserve values
+      const naive = (await balance(pool.tokenA))
+        .mul(priceA)
+        .add((await balance(pool.tokenB)).mul(priceB))
+        .div(await supply(pool.poolMint));
+      return { fair, naive };
+    };
+
+    const before = await values();
+    // Pushes the pool price away from the external price
+    await program.methods
+      .swap(new BN(50_000), new BN(1))
+      .accountsStrict(swapAccounts(pool))
+      .rpc();
+    const after = await values();
+
+    // The naive value is inflated by 8%, the fair value barely moves
+    assert.isTrue(after.naive.muln(100).gt(before.naive.muln(105)));
+    assert.isTrue(
+      after.fair.sub(before.fair).abs().muln(1_000).lt(before.fair)
+    );
+  });
+
+  it("charges an early withdraw more than one after the decay", async () => {
+    const pool = await createPool();
+    const decayPeriod = 2;
+    await program.methods
+      .setExitFee(500, new BN(decayPeriod))
+      .accountsStrict({ amm: pool.amm, owner: payer })
+      .rpc();
+
+    const [position] = PublicKey.findProgramAddressSync(
+      [Buffer.from("position"), pool.amm.toBuffer(), payer.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .openPosition()
+      .accountsStrict({
+        amm: pool.amm,
+        owner: payer,
+        position,
+        poolTokenAccount: await createTokenAccount(pool.poolMint, position),
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const deposit = (withPosition: boolean) =>
+      program.methods
+        .depositLiquidity(new BN(100_000), new BN(200_000), new BN(300_000))
+        .accountsStrict({
+          authority: pool.authority,
+          amm: pool.amm,
+          userTransferAuthority: payer,
+          userTokenA,
+          userTokenB,
+          tokenA: pool.tokenA,
+          tokenB: pool.tokenB,
+          poolMint: pool.poolMint,
+          userPoolToken: pool.poolToken,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          position: withPosition ? position : null,
+          wsolAccount: null,
+          nativeMint: null,
+          systemProgram: null,
+        })
+        .rpc();
+    // Returns the fee paid on withdrawing `poolTokens`
+    const withdraw = async (poolTokens: BN) => {
+      const fees = await balance(pool.feeAccount);
+      await program.methods
+        .withdrawLiquidity(poolTokens, new BN(0), new BN(0))
+        .accountsStrict({
+          authority: pool.authority,
+          amm: pool.amm,
+          userTransferAuthority: payer,
+          sourcePoolAccount: pool.poolToken,
+          tokenA: pool.tokenA,
+          tokenB: pool.tokenB,
+          userTokenA,
+          userTokenB,
+          poolMint: pool.poolMint,
+          feeAccount: pool.feeAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          position,
+        })
+        .rpc();
+      return (await balance(pool.feeAccount)).sub(fees);
+    };
+
+    // Depositing outside the position would leave its deposit time stale
+    await assertError(deposit(false), "PositionRequired");
+
+    await deposit(true);
+    const earlyFee = await withdraw(new BN(10_000));
+    await new Promise((resolve) =>
+      setTimeout(resolve, (decayPeriod + 2) * 1_000)
+    );
+    const lateFee = await withdraw(new BN(10_000));
+
+    assert.isTrue(earlyFee.gt(lateFee));
+    assert.isTrue(earlyFee.lten(500));
+    assert.isTrue(lateFee.isZero());
+
+    // Only the 80_000 left of the deposit decayed, the pool tokens minted at
+    // initialization pay the full 5% exit fee
+    const mixedFee = await withdraw(new BN(100_000));
+    assert.isTrue(mixedFee.eqn(1_000));
+  });
+
+  it("derives slippage bounds from the pool's quote", async () => {
+    const pool = await createPool({
+      ...ZERO_FEES,
+      ownerTradeFeeNumerator: new BN(30),
+      ownerTradeFeeDenominator: new BN(10_000),
+      ownerWithdrawFeeNumerator: new BN(10),
+      ownerWithdrawFeeDenominator: new BN(10_000),
+    });
+    const amountIn = new BN(20_000);
+    const quote = await program.methods
+      .quoteSwap(amountIn, { atoB: {} })
+      .accountsStrict({
+        amm: pool.amm,
+        tokenA: pool.tokenA,
+        tokenB: pool.tokenB,
+      })
+      .view();
+
+    // Without slippage the swap must pay exactly the quote, fees included
+    const userB = await balance(userTokenB);
+    await program.methods
+      .swapWithSlippageBps(amountIn, 0)
+      .accountsStrict(swapAccounts(pool))
+      .rpc();
+    assert.isTrue(
+      (await balance(userTokenB)).sub(userB).eq(quote.outputAmount)
+    );
+
+    const poolTokens = new BN(10_000);
+    const poolToken = await balance(pool.poolToken);
+    await program.methods
+      .depositLiquidityWithSlippageBps(poolTokens, 0)
+      .accountsStrict({
+        authority: pool.authority,
+        amm: pool.amm,
+        userTransferAuthority: payer,
+        userTokenA,
+        userTokenB,
+        tokenA: pool.tokenA,
+        tokenB: pool.tokenB,
+        poolMint: pool.poolMint,
+        userPoolToken: pool.poolToken,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        position: null,
+        wsolAccount: null,
+        nativeMint: null,
+        systemProgram: null,
+      })
+      .rpc();
+    assert.isTrue(
+      (await balance(pool.poolToken)).eq(poolToken.add(poolTokens))
+    );
+
+    await program.methods
+      .withdrawLiquidityWithSlippageBps(poolTokens, 0)
+      .accountsStrict({
+        authority: pool.authority,
+        amm: pool.amm,
+        userTransferAuthority: payer,
+        sourcePoolAccount: pool.poolToken,
+        tokenA: pool.tokenA,
+        tokenB: pool.tokenB,
+        userTokenA,
+        userTokenB,
+        poolMint: pool.poolMint,
+        feeAccount: pool.feeAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        position: null,
+      })
+      .rpc();
+    assert.isTrue((await balance(pool.poolToken)).eq(poolToken));
+  });
+
+  it("mints more for a lopsided deposit than a balanced one", async () => {
+    const depositA = new BN(10_000);
+    const depositB = new BN(10_000);
+    const depositAccounts = (pool: Pool) => ({
+      authority: pool.authority,
+      amm: pool.amm,
+      userTransferAuthority: payer,
+      userTokenA,
+      userTokenB,
+      tokenA: pool.tokenA,
+      tokenB: pool.tokenB,
+      poolMint: pool.poolMint,
+      userPoolToken: pool.poolToken,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      position: null,
+      wsolAccount: null,
+      nativeMint: null,
+      systemProgram: null,
+    });
+
+    // At 2 B per A, the balanced deposit only takes 5_000 of the token A
+    const balanced = await createPool();
+    const balancedBefore = await balance(balanced.poolToken);
+    await program.methods
+      .depositLiquidityByTokens(depositA, depositB)
+      .accountsStrict(depositAccounts(balanced))
+      .rpc();
+    const balancedMinted = (await balance(balanced.poolToken)).sub(
+      balancedBefore
+    );
+
+    // The smart deposit adds the other 5_000 as a single-token deposit
+    const smart = await createPool();
+    const smartBefore = await balance(smart.poolToken);
+    await program.methods
+      .smartDeposit(depositA, depositB, balancedMinted.addn(1))
+      .accountsStrict(depositAccounts(smart))
+      .rpc();
+    const smartMinted = (await balance(smart.poolToken)).sub(smartBefore);
+
+    assert.isTrue(smartMinted.gt(balancedMinted));
+    assert.isTrue((await balance(smart.tokenA)).eq(reserveA.add(depositA)));
+  });
+
+  it("sweeps the reserves only when opted in and no fee is due", async () => {
+    const withdrawAccounts = (pool: Pool) => ({
+      authority: pool.authority,
+      amm: pool.amm,
+      userTransferAuthority: payer,
+      sourcePoolAccount: pool.poolToken,
+      tokenA: pool.tokenA,
+      tokenB: pool.tokenB,
+      userTokenA,
+      userTokenB,
+      poolMint: pool.poolMint,
+      feeAccount: pool.feeAccount,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      position: null,
+    });
+
+    const feePool = await createPool({
+      ...ZERO_FEES,
+      ownerWithdrawFeeNumerator: new BN(10),
+      ownerWithdrawFeeDenominator: new BN(10_000),
+    });
+    await assertError(
+      program.methods
+        .sweepLiquidity(new BN(0), new BN(0))
+        .accountsStrict(withdrawAccounts(feePool))
+        .rpc(),
+      "WithdrawFeeDue"
+    );
+
+    const pool = await createPool();
+    await program.methods
+      .sweepLiquidity(reserveA, reserveB)
+      .accountsStrict(withdrawAccounts(pool))
+      .rpc();
+    assert.isTrue((await balance(pool.tokenA)).isZero());
+    assert.isTrue((await balance(pool.tokenB)).isZero());
+    assert.isTrue((await supply(pool.poolMint)).isZero());
+  });
+
+  it("compounds host fees into the host's position", async () => {
+    const pool = await createPool({
+      ...ZERO_FEES,
+      ownerTradeFeeNumerator: new BN(30),
+      ownerTradeFeeDenominator: new BN(10_000),
+      hostFeeNumerator: new BN(20),
+      hostFeeDenominator: new BN(100),
+    });
+
+    const [hostPosition] = PublicKey.findProgramAddressSync(
+      [Buffer.from("position"), pool.amm.toBuffer(), payer.toBuffer()],
+      program.programId
+    );
+    const hostPoolToken = await createTokenAccount(pool.poolMint, hostPosition);
+    await program.methods
+      .openPosition()
+      .accountsStrict({
+        amm: pool.amm,
+        owner: payer,
+        position: hostPosition,
+        poolTokenAccount: hostPoolToken,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    // Every swap through the partner adds its host fee to the position
+    let tracked = new BN(0);
+    for (const aToB of [true, false, true]) {
+      await program.methods
+        .swap(new BN(10_000), new BN(1))
+        .accountsStrict({
+          ...swapAccounts(pool, aToB),
+          hostFeeAccount: hostPoolToken,
+          hostPosition,
+        })
+        .rpc();
+      const position = await program.account.position.fetch(hostPosition);
+      assert.isTrue(position.poolTokenAmount.gt(tracked));
+      tracked = position.poolTokenAmount;
+    }
+    assert.isTrue((await balance(hostPoolToken)).eq(tracked));
+  });
+
+  it("rejects a swap quoted before the pool last changed", async () => {
+    const pool = await createPool();
+    const quoteSlot = async () =>
+      (await program.account.amm.fetch(pool.amm)).lastUpdateSlot;
+    const swapAt = (expectedSlot: BN) =>
+      program.methods
+        .swapWithExpectedSlot(new BN(1_000), new BN(1), expectedSlot)
+        .accountsStrict(swapAccounts(pool))
+        .rpc();
+
+    // Nothing touched the pool since the quote
+    const slot = await quoteSlot();
+    await swapAt(slot);
 
-  it("Is initialized!", async () => {
-    // Add your test here.
-    const tx = await program.methods.initialize().rpc();
-    console.log("Your transaction signature", tx);
+    // Another swap lands in a later slot, after the quote was made
+    const staleSlot = await quoteSlot();
+    while ((await provider.connection.getSlot()) <= staleSlot.toNumber()) {
+      await new Promise((resolve) => setTimeout(resolve, 100));
+    }
+    await program.methods
+      .swap(new BN(1_000), new BN(1))
+      .accountsStrict(swapAccounts(pool, false))
+      .rpc();
+    await assertError(swapAt(staleSlot), "StaleQuote");
+    await swapAt(await quoteSlot());
   });
 });